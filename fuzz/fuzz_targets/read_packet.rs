@@ -0,0 +1,19 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use patchwork::models::packet::{read, Direction, Status};
+use patchwork::models::protocol_version::ProtocolVersion;
+use std::io::Cursor;
+
+// Whatever a client or peer sends, in whichever state and on whichever version, reading it has to
+// end in a packet or an error, never a panic
+fuzz_target!(|data: &[u8]| {
+    if let Some((state, frame)) = data.split_first() {
+        let versions = ProtocolVersion::SUPPORTED;
+        let version = versions[usize::from(*state / 7) % versions.len()];
+        if let Ok(status) = Status::from_i32(i32::from(*state % 7)) {
+            for direction in [Direction::Serverbound, Direction::Clientbound].iter().copied() {
+                let _ = read(&mut Cursor::new(frame.to_vec()), status, direction, version);
+            }
+        }
+    }
+});
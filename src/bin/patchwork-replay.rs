@@ -0,0 +1,175 @@
+use patchwork::bot::Bot;
+use patchwork::models::capture::{self, Direction, Record};
+use patchwork::models::packet::Packet;
+
+use std::env;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+const USAGE: &str = "Usage: patchwork-replay <capture> [options]
+
+Prints the packets in a capture written by a node started with --capture, or replays what a player
+sent against a running node.
+
+Options:
+        --conn <conn_id>        Only look at this connection
+        --replay <address:port> Log in to the node as the player did and send everything they
+                                sent in play, keeping the original timing. Needs --conn
+        --full                  Print packets in full instead of cutting them short
+    -h, --help                  Print this message";
+
+// Chunks alone would fill the screen
+const SHORT_PACKET_LENGTH: usize = 160;
+
+// How long a replayed player sticks around after its last packet, so the node can react to it
+const LINGER: Duration = Duration::from_secs(1);
+
+struct Options {
+    capture: String,
+    conn_id: Option<Uuid>,
+    replay: Option<(String, u16)>,
+    full: bool,
+}
+
+fn main() {
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(e) => fail(&format!("{}\n\n{}", e, USAGE)),
+    };
+    let records: Vec<Record> = match capture::load(&options.capture) {
+        Ok(records) => records
+            .into_iter()
+            .filter(|record| options.conn_id.is_none_or(|id| id == record.conn_id))
+            .collect(),
+        Err(e) => fail(&e),
+    };
+    match (&options.replay, options.conn_id) {
+        (Some((address, port)), Some(_)) => replay(&records, address, *port),
+        (Some(_), None) => fail("--replay needs --conn to pick whose packets to send"),
+        (None, _) => print(&records, options.full),
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("patchwork-replay: {}", message);
+    process::exit(1);
+}
+
+fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Option<Options>, String> {
+    let mut args = args.into_iter();
+    let mut capture = None;
+    let mut conn_id = None;
+    let mut replay = None;
+    let mut full = false;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} expects a value", arg));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "--conn" => {
+                let value = value()?;
+                conn_id = Some(
+                    Uuid::parse_str(&value)
+                        .map_err(|_| format!("invalid connection id {:?}", value))?,
+                );
+            }
+            "--replay" => {
+                let value = value()?;
+                replay = Some(parse_address(&value)?);
+            }
+            "--full" => full = true,
+            _ if capture.is_none() && !arg.starts_with('-') => capture = Some(arg),
+            _ => return Err(format!("unknown argument {:?}", arg)),
+        }
+    }
+    Ok(Some(Options {
+        capture: capture.ok_or("no capture given")?,
+        conn_id,
+        replay,
+        full,
+    }))
+}
+
+fn parse_address(value: &str) -> Result<(String, u16), String> {
+    let invalid = || format!("invalid address {:?}, expected address:port", value);
+    let separator = value.rfind(':').ok_or_else(invalid)?;
+    let port = value[separator + 1..].parse().map_err(|_| invalid())?;
+    Ok((String::from(&value[..separator]), port))
+}
+
+fn print(records: &[Record], full: bool) {
+    let start = records.first().map_or(0, |record| record.time);
+    for record in records {
+        let arrow = match record.direction {
+            Direction::Inbound => "<-",
+            Direction::Outbound => "->",
+        };
+        let packet = match record.decode() {
+            Ok(Packet::Unknown) => format!("Unknown ({} bytes)", record.frame.len()),
+            Ok(packet) => format!("{:?}", packet),
+            Err(e) => format!("Undecodable ({})", e),
+        };
+        let packet = match full || packet.chars().count() <= SHORT_PACKET_LENGTH {
+            true => packet,
+            false => format!(
+                "{}...",
+                packet.chars().take(SHORT_PACKET_LENGTH).collect::<String>()
+            ),
+        };
+        println!(
+            "{:>10.3}s {} {} state {} {}",
+            // Frames in and out are recorded from different threads, so times can be a hair out of order
+            record.time.saturating_sub(start) as f64 / 1_000_000.0,
+            record.conn_id,
+            arrow,
+            record.state,
+            packet
+        );
+    }
+}
+
+// The bot logs itself in, so only what the player sent once in play is replayed. Keep alives are
+// left out too: the bot answers the node's own, and the captured ones would be for other ids
+fn replay(records: &[Record], address: &str, port: u16) {
+    let inbound: Vec<(&Record, Packet)> = records
+        .iter()
+        .filter(|record| record.direction == Direction::Inbound)
+        .filter_map(|record| record.decode().ok().map(|packet| (record, packet)))
+        .collect();
+    let username = inbound
+        .iter()
+        .find_map(|(_, packet)| match packet {
+            Packet::LoginStart(login_start) => Some(login_start.username.clone()),
+            _ => None,
+        })
+        .unwrap_or_else(|| fail("the connection never logged in"));
+
+    let bot = match Bot::connect(address, port, &username) {
+        Ok(bot) => bot,
+        Err(e) => fail(&format!("could not log in as {}: {}", username, e)),
+    };
+    println!("Logged in as {}", username);
+
+    let played: Vec<&(&Record, Packet)> = inbound
+        .iter()
+        .filter(|(record, packet)| {
+            record.state == 3 && !matches!(packet, Packet::KeepAlive(_) | Packet::Unknown)
+        })
+        .collect();
+    let captured_start = played.first().map_or(0, |(record, _)| record.time);
+    let replay_start = Instant::now();
+    for (record, packet) in played {
+        let due = Duration::from_micros(record.time.saturating_sub(captured_start));
+        if let Some(wait) = due.checked_sub(replay_start.elapsed()) {
+            thread::sleep(wait);
+        }
+        println!("Sending {:?}", packet);
+        bot.send(packet.clone());
+    }
+    thread::sleep(LINGER);
+}
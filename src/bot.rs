@@ -0,0 +1,325 @@
+// A headless client that plays on a node the way a person would, for the integration tests. It only
+// speaks as much of the protocol as it takes to log in offline, walk around and build, and hands
+// everything the node sends back to the test to make assertions on
+
+use super::models::compression::decompress;
+use super::models::minecraft_protocol::{frame_length, MinecraftProtocolReader};
+use super::models::minecraft_types::BlockPosition;
+use super::models::packet;
+use super::models::packet::{
+    read, ChatMessage, ChunkData, ClientboundKeepAlive, Direction, Handshake, KeepAlive,
+    LoginStart, Packet, PlayerBlockPlacement, PlayerDigging, PlayerPosition, Status,
+};
+use super::models::protocol_error::ProtocolError;
+use super::models::protocol_version::ProtocolVersion;
+
+use std::fmt;
+use std::io::{Cursor, Read};
+use std::net::{Shutdown, TcpStream};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Nodes in the tests run on the same machine, so anything slower than this is a hang
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How far a bot moves with each position packet while walking
+const STEP: f64 = 0.5;
+
+#[derive(Debug)]
+pub enum BotError {
+    Protocol(ProtocolError),
+    Disconnected(String),
+    TimedOut(String),
+}
+
+impl fmt::Display for BotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BotError::Protocol(e) => write!(f, "{}", e),
+            BotError::Disconnected(reason) => write!(f, "disconnected: {}", reason),
+            BotError::TimedOut(what) => write!(f, "timed out waiting for {}", what),
+        }
+    }
+}
+
+impl From<ProtocolError> for BotError {
+    fn from(e: ProtocolError) -> BotError {
+        BotError::Protocol(e)
+    }
+}
+
+impl From<std::io::Error> for BotError {
+    fn from(e: std::io::Error) -> BotError {
+        BotError::Protocol(ProtocolError::Io(e))
+    }
+}
+
+pub struct Bot {
+    pub username: String,
+    pub entity_id: i32,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+    stream: Arc<Mutex<TcpStream>>,
+    compression_threshold: Option<i32>,
+    version: ProtocolVersion,
+    received: Receiver<Result<Packet, ProtocolError>>,
+}
+
+impl Bot {
+    // Logs in and waits until the node has placed the bot in the world
+    pub fn connect(address: &str, port: u16, username: &str) -> Result<Bot, BotError> {
+        Bot::connect_as(address, port, username, ProtocolVersion::NATIVE)
+    }
+
+    // Logs in the way a client on another version would
+    pub fn connect_as(
+        address: &str,
+        port: u16,
+        username: &str,
+        version: ProtocolVersion,
+    ) -> Result<Bot, BotError> {
+        let mut stream = TcpStream::connect((address, port))?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(LOGIN_TIMEOUT))?;
+        packet::write(
+            &mut stream,
+            Packet::Handshake(Handshake {
+                protocol_version: version.number(),
+                server_address: String::from(address),
+                server_port: port,
+                next_state: 2,
+            }),
+            None,
+            version,
+        );
+        packet::write(
+            &mut stream,
+            Packet::LoginStart(LoginStart {
+                username: String::from(username),
+            }),
+            None,
+            version,
+        );
+        let compression_threshold = log_in(&mut stream, version)?;
+        stream.set_read_timeout(None)?;
+
+        let (sender, received) = channel();
+        let stream = Arc::new(Mutex::new(stream));
+        let reader = stream.lock().unwrap().try_clone()?;
+        let keep_alive = stream.clone();
+        thread::spawn(move || {
+            read_packets(reader, keep_alive, compression_threshold, version, sender)
+        });
+
+        let mut bot = Bot {
+            username: String::from(username),
+            entity_id: 0,
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+            stream,
+            compression_threshold,
+            version,
+            received,
+        };
+        if let Packet::JoinGame(join_game) =
+            bot.expect(LOGIN_TIMEOUT, |p| matches!(p, Packet::JoinGame(_)))?
+        {
+            bot.entity_id = join_game.entity_id;
+        }
+        if let Packet::ClientboundPlayerPositionAndLook(position) = bot
+            .expect(LOGIN_TIMEOUT, |p| {
+                matches!(p, Packet::ClientboundPlayerPositionAndLook(_))
+            })?
+        {
+            bot.x = position.x;
+            bot.y = position.y;
+            bot.z = position.z;
+        }
+        Ok(bot)
+    }
+
+    pub fn send(&self, packet: Packet) {
+        let mut stream = self.stream.lock().unwrap();
+        packet::write(
+            &mut *stream,
+            packet,
+            self.compression_threshold,
+            self.version,
+        );
+    }
+
+    // The next packet the node sent. Keep alives are answered before they get here
+    pub fn receive(&self, timeout: Duration) -> Result<Packet, BotError> {
+        match self.received.recv_timeout(timeout) {
+            Ok(Ok(Packet::PlayDisconnect(disconnect))) => {
+                Err(BotError::Disconnected(disconnect.reason))
+            }
+            Ok(Ok(packet)) => Ok(packet),
+            Ok(Err(e)) => Err(BotError::Protocol(e)),
+            Err(RecvTimeoutError::Timeout) => Err(BotError::TimedOut(String::from("a packet"))),
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(BotError::Disconnected(String::from("connection closed")))
+            }
+        }
+    }
+
+    // Skips over everything until a packet matches, so tests only have to describe the packets
+    // they care about
+    pub fn expect<F: Fn(&Packet) -> bool>(
+        &self,
+        timeout: Duration,
+        matches: F,
+    ) -> Result<Packet, BotError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.receive(remaining) {
+                Ok(packet) if matches(&packet) => return Ok(packet),
+                Ok(_) => {}
+                Err(BotError::TimedOut(_)) => {
+                    return Err(BotError::TimedOut(String::from("a matching packet")))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Walks in a straight line, reporting the position after every step like the client does
+    pub fn walk_to(&mut self, x: f64, z: f64) {
+        loop {
+            let (dx, dz) = (x - self.x, z - self.z);
+            let distance = (dx * dx + dz * dz).sqrt();
+            if distance <= STEP {
+                self.x = x;
+                self.z = z;
+            } else {
+                self.x += dx / distance * STEP;
+                self.z += dz / distance * STEP;
+            }
+            self.send(Packet::PlayerPosition(PlayerPosition {
+                x: self.x,
+                feet_y: self.y,
+                z: self.z,
+                on_ground: true,
+            }));
+            if distance <= STEP {
+                return;
+            }
+        }
+    }
+
+    // Places a block on top of the one at location
+    pub fn place_block(&self, location: BlockPosition) {
+        self.send(Packet::PlayerBlockPlacement(PlayerBlockPlacement {
+            location,
+            face: 1,
+            hand: 0,
+            cursor_x: 0.5,
+            cursor_y: 1.0,
+            cursor_z: 0.5,
+        }));
+    }
+
+    pub fn break_block(&self, location: BlockPosition) {
+        self.send(Packet::PlayerDigging(PlayerDigging {
+            status: 2,
+            location,
+            face: 1,
+        }));
+    }
+
+    pub fn chat(&self, message: &str) {
+        self.send(Packet::ChatMessage(ChatMessage {
+            message: String::from(message),
+        }));
+    }
+}
+
+impl Drop for Bot {
+    // Stops the thread reading packets, and lets the node know the player is gone
+    fn drop(&mut self) {
+        if let Ok(stream) = self.stream.lock() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+// Waits for login to succeed, and returns the compression threshold the node asked for on the way
+fn log_in(stream: &mut TcpStream, version: ProtocolVersion) -> Result<Option<i32>, BotError> {
+    let mut compression_threshold = None;
+    loop {
+        let mut frame = read_frame(stream, compression_threshold)?;
+        match read(&mut frame, Status::Login, Direction::Clientbound, version)? {
+            Packet::SetCompression(set_compression) => {
+                compression_threshold = Some(set_compression.threshold);
+            }
+            Packet::LoginSuccess(_) => return Ok(compression_threshold),
+            Packet::LoginDisconnect(disconnect) => {
+                return Err(BotError::Disconnected(disconnect.reason))
+            }
+            packet => {
+                return Err(BotError::Protocol(ProtocolError::UnexpectedPacket {
+                    state: 2,
+                    packet: String::from(packet.debug_print_type()),
+                }))
+            }
+        }
+    }
+}
+
+fn read_packets(
+    mut stream: TcpStream,
+    keep_alive: Arc<Mutex<TcpStream>>,
+    compression_threshold: Option<i32>,
+    version: ProtocolVersion,
+    packets: Sender<Result<Packet, ProtocolError>>,
+) {
+    loop {
+        let packet =
+            read_frame(&mut stream, compression_threshold).and_then(|frame| decode(frame, version));
+        if let Ok(Packet::ClientboundKeepAlive(ClientboundKeepAlive { id })) = packet {
+            let mut stream = keep_alive.lock().unwrap();
+            packet::write(
+                &mut *stream,
+                Packet::KeepAlive(KeepAlive { id }),
+                compression_threshold,
+                version,
+            );
+            continue;
+        }
+        let done = match &packet {
+            Ok(Packet::PlayDisconnect(_)) => true,
+            Err(e) if e.is_closed() => return,
+            Err(_) => true,
+            Ok(_) => false,
+        };
+        if packets.send(packet).is_err() || done {
+            return;
+        }
+    }
+}
+
+fn read_frame<R: Read>(
+    stream: &mut R,
+    compression_threshold: Option<i32>,
+) -> Result<Cursor<Vec<u8>>, ProtocolError> {
+    let length = frame_length(stream.read_var_int()?)?;
+    let mut frame = vec![0; length];
+    stream.read_exact(&mut frame)?;
+    if compression_threshold.is_some() {
+        frame = decompress(frame)?;
+    }
+    Ok(Cursor::new(frame))
+}
+
+// A login brings in a whole world of chunks, and no test looks inside them
+fn decode(mut frame: Cursor<Vec<u8>>, version: ProtocolVersion) -> Result<Packet, ProtocolError> {
+    if frame.clone().read_var_int()? == version.play_id(Direction::Clientbound, ChunkData::ID) {
+        return Ok(Packet::Unknown);
+    }
+    read(&mut frame, Status::Play, Direction::Clientbound, version)
+}
@@ -0,0 +1,313 @@
+// Several nodes in one process, wired together the way a test asks for. Every peer link runs
+// through a loopback proxy the test holds on to, so partitioning, delaying or killing a link
+// happens to real sockets and the nodes see exactly what they would on a bad network
+
+use super::config::Config;
+use super::interfaces::patchwork::PatchworkState;
+use super::models::map::Peer;
+use super::node::Node;
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const LOOPBACK: &str = "127.0.0.1";
+
+// How often wait_for_links checks in on the nodes
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+pub struct Cluster {
+    nodes: Vec<Option<Node>>,
+    links: Vec<(usize, usize, Link)>,
+}
+
+impl Cluster {
+    // Every node gets the same config, apart from listening on a port of its own and having no
+    // peers other than the ones it is linked to
+    pub fn start(
+        config: &Config,
+        size: usize,
+        links: &[(usize, usize)],
+    ) -> Result<Cluster, String> {
+        let mut cluster = Cluster {
+            nodes: Vec::new(),
+            links: Vec::new(),
+        };
+        for _ in 0..size {
+            let mut config = config.clone();
+            config.bind_address = String::from(LOOPBACK);
+            config.port = 0;
+            config.peers = Vec::new();
+            cluster.nodes.push(Some(Node::start(config)?));
+        }
+        for &(from, to) in links {
+            cluster.link(from, to)?;
+        }
+        Ok(cluster)
+    }
+
+    pub fn node(&self, index: usize) -> &Node {
+        match &self.nodes[index] {
+            Some(node) => node,
+            None => panic!("node {} has been stopped", index),
+        }
+    }
+
+    pub fn port(&self, index: usize) -> u16 {
+        self.node(index).port()
+    }
+
+    // Gives `from` a map for `to`. Links only go one way, like peers in a node's config
+    pub fn link(&mut self, from: usize, to: usize) -> Result<Link, String> {
+        let link = Link::open(self.port(to))
+            .map_err(|e| format!("Could not link node {} to node {}: {}", from, to, e))?;
+        self.node(from).add_peer(Peer {
+            address: String::from(LOOPBACK),
+            port: link.port(),
+        });
+        self.links.push((from, to, link.clone()));
+        Ok(link)
+    }
+
+    // The link a node's map for another node goes through, if there is one
+    pub fn link_between(&self, from: usize, to: usize) -> Option<Link> {
+        self.links
+            .iter()
+            .find(|(link_from, link_to, _)| *link_from == from && *link_to == to)
+            .map(|(_, _, link)| link.clone())
+    }
+
+    // Peers are connected in the background, so anything that has to go over a link should wait on
+    // this first
+    pub fn wait_for_links(&self, timeout: Duration) -> Result<(), String> {
+        let deadline = Instant::now() + timeout;
+        for (index, node) in self.nodes.iter().enumerate() {
+            let node = match node {
+                Some(node) => node,
+                None => continue,
+            };
+            let expected = self
+                .links
+                .iter()
+                .filter(|(from, _, _)| *from == index)
+                .count();
+            loop {
+                let connected = node
+                    .patchwork_state()
+                    .maps()
+                    .wait()
+                    .map_err(|e| format!("Could not get the maps of node {}: {}", index, e))?
+                    .iter()
+                    .filter(|map| map.peer_connection.is_some())
+                    .count();
+                if connected >= expected {
+                    break;
+                }
+                if Instant::now() >= deadline {
+                    return Err(format!(
+                        "Node {} connected {} of its {} links",
+                        index, connected, expected
+                    ));
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+        Ok(())
+    }
+
+    // Shuts a single node down the same way a signal would. Links to it stay up, but have nothing
+    // on the other end
+    pub fn stop_node(&mut self, index: usize) {
+        if let Some(node) = self.nodes[index].take() {
+            node.stop();
+        }
+    }
+
+    pub fn stop(mut self) {
+        for index in (0..self.nodes.len()).rev() {
+            self.stop_node(index);
+        }
+        for (_, _, link) in self.links.iter() {
+            link.close();
+        }
+    }
+}
+
+// A proxy between two nodes. Everything one node sends to the other over a peer connection, or on
+// behalf of a player anchored to the other's map, passes through here
+#[derive(Clone)]
+pub struct Link {
+    port: u16,
+    state: Arc<LinkState>,
+}
+
+struct LinkState {
+    partitioned: Mutex<bool>,
+    healed: Condvar,
+    delay: Mutex<Duration>,
+    connections: Mutex<Vec<TcpStream>>,
+    closed: AtomicBool,
+}
+
+impl Link {
+    fn open(target: u16) -> io::Result<Link> {
+        let listener = TcpListener::bind((LOOPBACK, 0))?;
+        let link = Link {
+            port: listener.local_addr()?.port(),
+            state: Arc::new(LinkState {
+                partitioned: Mutex::new(false),
+                healed: Condvar::new(),
+                delay: Mutex::new(Duration::from_secs(0)),
+                connections: Mutex::new(Vec::new()),
+                closed: AtomicBool::new(false),
+            }),
+        };
+        let state = link.state.clone();
+        thread::spawn(move || {
+            for inbound in listener.incoming() {
+                if state.closed.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(inbound) = inbound {
+                    if let Err(e) = forward(inbound, target, &state) {
+                        warn!("Link to port {} could not connect: {}", target, e);
+                    }
+                }
+            }
+        });
+        Ok(link)
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    // Holds on to everything sent over the link, in both directions, until it is healed. The
+    // sockets stay open so neither side can tell, just like a real partition
+    pub fn partition(&self) {
+        *self.state.partitioned.lock().unwrap() = true;
+    }
+
+    // Lets everything held back by a partition through, in the order it was sent
+    pub fn heal(&self) {
+        *self.state.partitioned.lock().unwrap() = false;
+        self.state.healed.notify_all();
+    }
+
+    // Adds latency to everything sent over the link from now on
+    pub fn delay(&self, delay: Duration) {
+        *self.state.delay.lock().unwrap() = delay;
+    }
+
+    // Closes every connection currently open over the link, as if the network dropped them. The
+    // link still accepts new connections, so nodes are free to reconnect
+    pub fn kill(&self) {
+        for connection in self.state.connections.lock().unwrap().drain(..) {
+            let _ = connection.shutdown(Shutdown::Both);
+        }
+    }
+
+    fn close(&self) {
+        self.state.closed.store(true, Ordering::SeqCst);
+        self.heal();
+        self.kill();
+        // Wakes up the thread accepting connections so it notices
+        let _ = TcpStream::connect((LOOPBACK, self.port));
+    }
+}
+
+fn forward(inbound: TcpStream, target: u16, state: &Arc<LinkState>) -> io::Result<()> {
+    let outbound = TcpStream::connect((LOOPBACK, target))?;
+    inbound.set_nodelay(true)?;
+    outbound.set_nodelay(true)?;
+    {
+        let mut connections = state.connections.lock().unwrap();
+        connections.push(inbound.try_clone()?);
+        connections.push(outbound.try_clone()?);
+    }
+    pump(inbound.try_clone()?, outbound.try_clone()?, state.clone())?;
+    pump(outbound, inbound, state.clone())
+}
+
+// Copies one direction of a connection, honouring whatever the test has done to the link. Chunks
+// are read as soon as they arrive and held back until their delay is up, so that a delayed link
+// adds the same latency to everything rather than each chunk waiting on the ones before it. When
+// either side goes away the other is closed too
+fn pump(from: TcpStream, mut to: TcpStream, state: Arc<LinkState>) -> io::Result<()> {
+    let (chunks, read_chunks) = mpsc::channel::<(Instant, Vec<u8>)>();
+    let mut reading = from.try_clone()?;
+    thread::spawn(move || {
+        let mut buffer = [0; 16 * 1024];
+        loop {
+            let read = match reading.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(read) => read,
+            };
+            if chunks
+                .send((Instant::now(), buffer[..read].to_vec()))
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+    thread::spawn(move || {
+        for (read_at, chunk) in read_chunks {
+            {
+                let mut partitioned = state.partitioned.lock().unwrap();
+                while *partitioned {
+                    partitioned = state.healed.wait(partitioned).unwrap();
+                }
+            }
+            let due = read_at + *state.delay.lock().unwrap();
+            let now = Instant::now();
+            if due > now {
+                thread::sleep(due - now);
+            }
+            if to.write_all(&chunk).is_err() {
+                break;
+            }
+        }
+        let _ = from.shutdown(Shutdown::Both);
+        let _ = to.shutdown(Shutdown::Both);
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_hold_chunks_back_from_when_they_were_sent() {
+        let target = TcpListener::bind((LOOPBACK, 0)).unwrap();
+        let link = Link::open(target.local_addr().unwrap().port()).unwrap();
+        let delay = Duration::from_millis(300);
+        link.delay(delay);
+        let mut client = TcpStream::connect((LOOPBACK, link.port())).unwrap();
+        client.set_nodelay(true).unwrap();
+        let (mut server, _) = target.accept().unwrap();
+
+        // Far enough apart that the link reads them separately
+        let sent_at = Instant::now();
+        client.write_all(b"a").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        client.write_all(b"b").unwrap();
+
+        let mut byte = [0];
+        server.read_exact(&mut byte).unwrap();
+        let first = sent_at.elapsed();
+        server.read_exact(&mut byte).unwrap();
+        let second = sent_at.elapsed();
+        assert!(first >= delay, "first chunk arrived after {:?}", first);
+        assert!(
+            second - first < delay / 2,
+            "second chunk arrived {:?} after the first",
+            second - first
+        );
+        link.close();
+    }
+}
@@ -0,0 +1,540 @@
+// Node configuration. Values are layered in the following order, with later layers taking
+// precedence:
+//    - the defaults in the constants module
+//    - the config file passed with --config (TOML or JSON, picked by file extension)
+//    - the PORT, PEER_PORT and LOG environment variables we used before config files existed
+//    - command line flags
+//
+// Everything is validated once at startup so a bad value is reported before any service starts
+
+use super::constants::{
+    DEFAULT_BIND_ADDRESS, DEFAULT_COMPRESSION_THRESHOLD, DEFAULT_LOG_LEVEL, DEFAULT_MAX_PLAYERS,
+    DEFAULT_MOTD, DEFAULT_OUTBOUND_DISCONNECT_THRESHOLD, DEFAULT_OUTBOUND_DROP_THRESHOLD,
+    DEFAULT_PORT,
+};
+use super::models::map::Peer;
+
+use serde::Deserialize;
+use simplelog::LevelFilter;
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+pub const USAGE: &str = "Usage: patchwork [options]
+
+Options:
+    -c, --config <path>         Read settings from a TOML or JSON config file
+    -b, --bind <address>        Address to listen on
+    -p, --port <port>           Port to listen on
+        --peer <address:port>   Connect to a peer on startup (repeatable)
+        --motd <text>           Message shown in the server list
+        --max-players <count>   Maximum number of players allowed on this node
+        --log-level <level>     One of off, error, warn, info, debug, trace
+        --compression-threshold <bytes>
+                                Compress packets of at least this size, -1 disables compression
+        --online-mode <true|false>
+                                Authenticate players with the session server and encrypt
+                                their connections
+        --session-file <path>   Authenticate online mode players against a JSON list of
+                                profiles instead of the session server
+        --capture <path>        Write every packet sent and received to a file, which
+                                patchwork-replay can print or replay
+        --outbound-drop-threshold <bytes>
+                                Skip movement packets for connections with this much
+                                waiting to be written
+        --outbound-disconnect-threshold <bytes>
+                                Queue no more than this for a connection, and disconnect
+                                connections that stay that far behind
+    -h, --help                  Print this message";
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub bind_address: String,
+    pub port: u16,
+    pub peers: Vec<Peer>,
+    pub motd: String,
+    pub max_players: u16,
+    pub log_level: LevelFilter,
+    pub compression_threshold: Option<i32>,
+    pub online_mode: bool,
+    pub session_file: Option<String>,
+    pub capture_file: Option<String>,
+    pub outbound_drop_threshold: usize,
+    pub outbound_disconnect_threshold: usize,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    HelpRequested,
+    UnknownArgument(String),
+    MissingValue(String),
+    UnsupportedFormat(String),
+    Io {
+        path: String,
+        error: String,
+    },
+    Parse {
+        path: String,
+        error: String,
+    },
+    Invalid {
+        setting: String,
+        value: String,
+        reason: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::HelpRequested => write!(f, "{}", USAGE),
+            ConfigError::UnknownArgument(arg) => {
+                write!(f, "unknown argument {:?}\n\n{}", arg, USAGE)
+            }
+            ConfigError::MissingValue(flag) => write!(f, "{} expects a value", flag),
+            ConfigError::UnsupportedFormat(path) => {
+                write!(f, "config file {:?} must end in .toml or .json", path)
+            }
+            ConfigError::Io { path, error } => {
+                write!(f, "could not read config file {:?}: {}", path, error)
+            }
+            ConfigError::Parse { path, error } => {
+                write!(f, "could not parse config file {:?}: {}", path, error)
+            }
+            ConfigError::Invalid {
+                setting,
+                value,
+                reason,
+            } => write!(f, "invalid {} {:?}: {}", setting, value, reason),
+        }
+    }
+}
+
+// Every layer is expressed as a set of optional settings which get merged on top of each other
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Settings {
+    bind_address: Option<String>,
+    port: Option<u16>,
+    peers: Option<Vec<Peer>>,
+    motd: Option<String>,
+    max_players: Option<u16>,
+    log_level: Option<String>,
+    compression_threshold: Option<i32>,
+    online_mode: Option<bool>,
+    session_file: Option<String>,
+    capture_file: Option<String>,
+    outbound_drop_threshold: Option<usize>,
+    outbound_disconnect_threshold: Option<usize>,
+}
+
+impl Settings {
+    fn merge(&mut self, other: Settings) {
+        if other.bind_address.is_some() {
+            self.bind_address = other.bind_address;
+        }
+        if other.port.is_some() {
+            self.port = other.port;
+        }
+        if other.peers.is_some() {
+            self.peers = other.peers;
+        }
+        if other.motd.is_some() {
+            self.motd = other.motd;
+        }
+        if other.max_players.is_some() {
+            self.max_players = other.max_players;
+        }
+        if other.log_level.is_some() {
+            self.log_level = other.log_level;
+        }
+        if other.compression_threshold.is_some() {
+            self.compression_threshold = other.compression_threshold;
+        }
+        if other.online_mode.is_some() {
+            self.online_mode = other.online_mode;
+        }
+        if other.session_file.is_some() {
+            self.session_file = other.session_file;
+        }
+        if other.capture_file.is_some() {
+            self.capture_file = other.capture_file;
+        }
+        if other.outbound_drop_threshold.is_some() {
+            self.outbound_drop_threshold = other.outbound_drop_threshold;
+        }
+        if other.outbound_disconnect_threshold.is_some() {
+            self.outbound_disconnect_threshold = other.outbound_disconnect_threshold;
+        }
+    }
+}
+
+pub fn load<I: IntoIterator<Item = String>, E: Fn(&str) -> Option<String>>(
+    args: I,
+    env: E,
+) -> Result<Config, ConfigError> {
+    let (config_path, cli_settings) = parse_args(args)?;
+
+    let mut settings = Settings::default();
+    if let Some(path) = config_path {
+        settings.merge(read_file(&path)?);
+    }
+    settings.merge(read_env(env)?);
+    settings.merge(cli_settings);
+
+    validate(settings)
+}
+
+fn parse_args<I: IntoIterator<Item = String>>(
+    args: I,
+) -> Result<(Option<String>, Settings), ConfigError> {
+    let mut config_path = None;
+    let mut settings = Settings::default();
+    let mut peers = Vec::new();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| ConfigError::MissingValue(arg.clone()))
+        };
+        match arg.as_str() {
+            "-h" | "--help" => return Err(ConfigError::HelpRequested),
+            "-c" | "--config" => config_path = Some(value()?),
+            "-b" | "--bind" => settings.bind_address = Some(value()?),
+            "-p" | "--port" => settings.port = Some(parse_port("port", &value()?)?),
+            "--peer" => peers.push(parse_peer(&value()?)?),
+            "--motd" => settings.motd = Some(value()?),
+            "--max-players" => {
+                let max_players = value()?;
+                settings.max_players = Some(max_players.parse().map_err(|_| {
+                    invalid("max players", &max_players, "expected a number up to 65535")
+                })?)
+            }
+            "--log-level" => settings.log_level = Some(value()?),
+            "--compression-threshold" => {
+                let threshold = value()?;
+                settings.compression_threshold = Some(threshold.parse().map_err(|_| {
+                    invalid("compression threshold", &threshold, "expected a number")
+                })?)
+            }
+            "--online-mode" => {
+                let online_mode = value()?;
+                settings.online_mode =
+                    Some(online_mode.parse().map_err(|_| {
+                        invalid("online mode", &online_mode, "expected true or false")
+                    })?)
+            }
+            "--session-file" => settings.session_file = Some(value()?),
+            "--capture" => settings.capture_file = Some(value()?),
+            "--outbound-drop-threshold" => {
+                settings.outbound_drop_threshold =
+                    Some(parse_bytes("outbound drop threshold", &value()?)?)
+            }
+            "--outbound-disconnect-threshold" => {
+                settings.outbound_disconnect_threshold =
+                    Some(parse_bytes("outbound disconnect threshold", &value()?)?)
+            }
+            _ => return Err(ConfigError::UnknownArgument(arg)),
+        }
+    }
+    if !peers.is_empty() {
+        settings.peers = Some(peers);
+    }
+
+    Ok((config_path, settings))
+}
+
+fn read_file(path: &str) -> Result<Settings, ConfigError> {
+    let contents = fs::read_to_string(path).map_err(|e| ConfigError::Io {
+        path: path.to_string(),
+        error: e.to_string(),
+    })?;
+    let parse_error = |error: String| ConfigError::Parse {
+        path: path.to_string(),
+        error,
+    };
+    match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => toml::from_str(&contents).map_err(|e| parse_error(e.to_string())),
+        Some("json") => serde_json::from_str(&contents).map_err(|e| parse_error(e.to_string())),
+        _ => Err(ConfigError::UnsupportedFormat(path.to_string())),
+    }
+}
+
+fn read_env<E: Fn(&str) -> Option<String>>(env: E) -> Result<Settings, ConfigError> {
+    let mut settings = Settings::default();
+    if let Some(port) = env("PORT") {
+        settings.port = Some(parse_port("PORT", &port)?);
+    }
+    if let Some(peer_port) = env("PEER_PORT") {
+        settings.peers = Some(vec![Peer {
+            address: String::from(DEFAULT_BIND_ADDRESS),
+            port: parse_port("PEER_PORT", &peer_port)?,
+        }]);
+    }
+    settings.log_level = env("LOG");
+    Ok(settings)
+}
+
+fn validate(settings: Settings) -> Result<Config, ConfigError> {
+    let bind_address = settings
+        .bind_address
+        .unwrap_or_else(|| String::from(DEFAULT_BIND_ADDRESS));
+    if IpAddr::from_str(&bind_address).is_err() {
+        return Err(invalid(
+            "bind address",
+            &bind_address,
+            "expected an IPv4 or IPv6 address",
+        ));
+    }
+
+    let peers = settings.peers.unwrap_or_default();
+    for (index, peer) in peers.iter().enumerate() {
+        if peer.address.is_empty() {
+            return Err(invalid("peer address", &peer.address, "cannot be empty"));
+        }
+        if peer.port == 0 {
+            return Err(invalid(
+                "peer port",
+                "0",
+                "expected a port between 1 and 65535",
+            ));
+        }
+        if peers[..index]
+            .iter()
+            .any(|other| other.address == peer.address && other.port == peer.port)
+        {
+            return Err(invalid(
+                "peer",
+                &format!("{}:{}", peer.address, peer.port),
+                "listed more than once",
+            ));
+        }
+    }
+
+    let max_players = settings.max_players.unwrap_or(DEFAULT_MAX_PLAYERS);
+    if max_players == 0 {
+        return Err(invalid(
+            "max players",
+            "0",
+            "must allow at least one player",
+        ));
+    }
+
+    let log_level = settings
+        .log_level
+        .unwrap_or_else(|| String::from(DEFAULT_LOG_LEVEL));
+    let log_level = LevelFilter::from_str(&log_level).map_err(|_| {
+        invalid(
+            "log level",
+            &log_level,
+            "expected one of off, error, warn, info, debug, trace",
+        )
+    })?;
+
+    let online_mode = settings.online_mode.unwrap_or(false);
+    if let (false, Some(session_file)) = (online_mode, &settings.session_file) {
+        return Err(invalid(
+            "session file",
+            session_file,
+            "only used in online mode",
+        ));
+    }
+
+    let outbound_drop_threshold = settings
+        .outbound_drop_threshold
+        .unwrap_or(DEFAULT_OUTBOUND_DROP_THRESHOLD);
+    let outbound_disconnect_threshold = settings
+        .outbound_disconnect_threshold
+        .unwrap_or(DEFAULT_OUTBOUND_DISCONNECT_THRESHOLD);
+    if outbound_drop_threshold == 0 {
+        return Err(invalid(
+            "outbound drop threshold",
+            "0",
+            "must allow at least one byte",
+        ));
+    }
+    if outbound_disconnect_threshold <= outbound_drop_threshold {
+        return Err(invalid(
+            "outbound disconnect threshold",
+            &outbound_disconnect_threshold.to_string(),
+            "must be above the outbound drop threshold",
+        ));
+    }
+
+    Ok(Config {
+        bind_address,
+        port: settings.port.unwrap_or(DEFAULT_PORT),
+        peers,
+        motd: settings.motd.unwrap_or_else(|| String::from(DEFAULT_MOTD)),
+        max_players,
+        log_level,
+        // Like vanilla, any negative threshold turns compression off
+        compression_threshold: Some(
+            settings
+                .compression_threshold
+                .unwrap_or(DEFAULT_COMPRESSION_THRESHOLD),
+        )
+        .filter(|threshold| *threshold >= 0),
+        online_mode,
+        session_file: settings.session_file,
+        capture_file: settings.capture_file,
+        outbound_drop_threshold,
+        outbound_disconnect_threshold,
+    })
+}
+
+fn parse_port(setting: &str, value: &str) -> Result<u16, ConfigError> {
+    value
+        .parse::<u16>()
+        .map_err(|_| invalid(setting, value, "expected a port between 0 and 65535"))
+}
+
+fn parse_bytes(setting: &str, value: &str) -> Result<usize, ConfigError> {
+    value
+        .parse::<usize>()
+        .map_err(|_| invalid(setting, value, "expected a number of bytes"))
+}
+
+fn parse_peer(value: &str) -> Result<Peer, ConfigError> {
+    match value.rsplit_once(':') {
+        Some((address, port)) => Ok(Peer {
+            address: String::from(address),
+            port: parse_port("peer port", port)?,
+        }),
+        None => Err(invalid("peer", value, "expected <address>:<port>")),
+    }
+}
+
+fn invalid(setting: &str, value: &str, reason: &str) -> ConfigError {
+    ConfigError::Invalid {
+        setting: setting.to_string(),
+        value: value.to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn no_env(_: &str) -> Option<String> {
+        None
+    }
+
+    #[test]
+    fn defaults() {
+        let config = load(args(&[]), no_env).unwrap();
+        assert_eq!(DEFAULT_BIND_ADDRESS, config.bind_address);
+        assert_eq!(DEFAULT_PORT, config.port);
+        assert!(config.peers.is_empty());
+        assert_eq!(DEFAULT_MAX_PLAYERS, config.max_players);
+        assert_eq!(LevelFilter::Info, config.log_level);
+        assert_eq!(
+            Some(DEFAULT_COMPRESSION_THRESHOLD),
+            config.compression_threshold
+        );
+        assert!(!config.online_mode);
+    }
+
+    #[test]
+    fn negative_compression_threshold_disables_compression() {
+        let config = load(args(&["--compression-threshold", "-1"]), no_env).unwrap();
+        assert_eq!(None, config.compression_threshold);
+    }
+
+    #[test]
+    fn command_line_overrides_environment() {
+        let env = |key: &str| match key {
+            "PORT" => Some(String::from("8000")),
+            "PEER_PORT" => Some(String::from("8001")),
+            _ => None,
+        };
+        let config = load(
+            args(&[
+                "--port",
+                "9000",
+                "--peer",
+                "10.0.0.2:9001",
+                "--peer",
+                "10.0.0.3:9002",
+                "--log-level",
+                "trace",
+            ]),
+            env,
+        )
+        .unwrap();
+        assert_eq!(9000, config.port);
+        assert_eq!(2, config.peers.len());
+        assert_eq!("10.0.0.3", config.peers[1].address);
+        assert_eq!(9002, config.peers[1].port);
+        assert_eq!(LevelFilter::Trace, config.log_level);
+    }
+
+    #[test]
+    fn legacy_environment_variables() {
+        let env = |key: &str| match key {
+            "PORT" => Some(String::from("8000")),
+            "PEER_PORT" => Some(String::from("8001")),
+            _ => None,
+        };
+        let config = load(args(&[]), env).unwrap();
+        assert_eq!(8000, config.port);
+        assert_eq!(1, config.peers.len());
+        assert_eq!(8001, config.peers[0].port);
+    }
+
+    #[test]
+    fn parses_toml_settings() {
+        let settings: Settings = toml::from_str(
+            r#"
+            bind_address = "0.0.0.0"
+            motd = "hello"
+            peers = [{ address = "10.0.0.2", port = 25566 }]
+            capture_file = "capture.jsonl"
+            "#,
+        )
+        .unwrap();
+        let config = validate(settings).unwrap();
+        assert_eq!("0.0.0.0", config.bind_address);
+        assert_eq!("hello", config.motd);
+        assert_eq!(25566, config.peers[0].port);
+        assert_eq!(Some(String::from("capture.jsonl")), config.capture_file);
+    }
+
+    #[test]
+    fn rejects_bad_values() {
+        assert!(load(args(&["--port", "70000"]), no_env).is_err());
+        assert!(load(args(&["--bind", "localhost"]), no_env).is_err());
+        assert!(load(args(&["--peer", "10.0.0.2"]), no_env).is_err());
+        assert!(load(
+            args(&["--peer", "10.0.0.2:1", "--peer", "10.0.0.2:1"]),
+            no_env
+        )
+        .is_err());
+        assert!(load(args(&["--max-players", "0"]), no_env).is_err());
+        assert!(load(args(&["--log-level", "loud"]), no_env).is_err());
+        assert!(load(args(&["--port"]), no_env).is_err());
+        assert!(load(args(&["--verbose"]), no_env).is_err());
+        assert!(load(args(&["--online-mode", "yes"]), no_env).is_err());
+        assert!(load(args(&["--session-file", "players.json"]), no_env).is_err());
+        assert!(load(args(&["--outbound-drop-threshold", "0"]), no_env).is_err());
+        assert!(load(
+            args(&[
+                "--outbound-drop-threshold",
+                "1024",
+                "--outbound-disconnect-threshold",
+                "1024"
+            ]),
+            no_env
+        )
+        .is_err());
+        assert!(toml::from_str::<Settings>("colour = \"red\"").is_err());
+    }
+}
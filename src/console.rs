@@ -0,0 +1,27 @@
+// Commands typed into the node's console. For now there's only `stop`, which shuts the node down
+
+use std::io::{self, BufRead};
+use std::sync::mpsc::Sender;
+use std::thread;
+
+const STOP_COMMAND: &str = "stop";
+
+pub fn start(stop: Sender<()>) {
+    // Nodes started without a console just see stdin close, which isn't a reason to stop
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            match line.as_ref().map(|line| line.trim()) {
+                Ok(STOP_COMMAND) => {
+                    let _ = stop.send(());
+                    break;
+                }
+                Ok("") => {}
+                Ok(command) => warn!("Unknown command {:?}, try {:?}", command, STOP_COMMAND),
+                Err(e) => {
+                    warn!("Could not read from the console: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+}
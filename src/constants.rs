@@ -1,7 +1,12 @@
 pub const ENTITY_ID_BLOCK_SIZE: i32 = 1000;
 pub const CHUNK_SIZE: i32 = 16;
 
-pub const SERVER_MAX_CAPACITY: u16 = 50;
-pub const SERVER_VERSION: &str = "1.13.2";
-pub const SERVER_PROTOCOL: u16 = 404;
-pub const SERVER_DESCRIPTION: &str = "Welcome to the jungle.";
+// Defaults for anything that can be overridden through the config file or command line
+pub const DEFAULT_BIND_ADDRESS: &str = "127.0.0.1";
+pub const DEFAULT_PORT: u16 = 25565;
+pub const DEFAULT_MAX_PLAYERS: u16 = 50;
+pub const DEFAULT_MOTD: &str = "Welcome to the jungle.";
+pub const DEFAULT_LOG_LEVEL: &str = "info";
+pub const DEFAULT_COMPRESSION_THRESHOLD: i32 = 256;
+pub const DEFAULT_OUTBOUND_DROP_THRESHOLD: usize = 8 * 1024 * 1024;
+pub const DEFAULT_OUTBOUND_DISCONNECT_THRESHOLD: usize = 32 * 1024 * 1024;
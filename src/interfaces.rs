@@ -2,13 +2,30 @@
 mod interface_macro;
 pub mod block;
 pub mod connection;
+pub mod keep_alive;
+pub mod login;
 pub mod messenger;
 pub mod packet_processor;
 pub mod patchwork;
 pub mod player;
+pub mod query;
 pub mod report;
 
+use super::models::encryption;
+use super::models::lighting;
 use super::models::map;
 use super::models::minecraft_types;
+use super::models::outbound;
 use super::models::packet;
+use super::models::protocol_version;
+use super::models::session;
 use super::models::translation;
+
+// Every interface also has a Stop operation, which ends the service's event loop once everything
+// sent to it before has been handled
+pub trait Operation {
+    fn stop() -> Self;
+
+    // For telling which operation a service was handling when it panicked
+    fn name(&self) -> &'static str;
+}
@@ -1,3 +1,4 @@
+use super::lighting::ColumnLight;
 use super::packet::{PlayerBlockPlacement, PlayerDigging};
 
 use std::sync::mpsc::Sender;
@@ -20,6 +21,7 @@ define_interface!(
 
 #[derive(Debug, Clone)]
 pub struct Block {
-    pub conn_id: Uuid,
     pub block_ids: Vec<Vec<Vec<Vec<i32>>>>,
+    // Indexed by pillar the same as block ids, and kept up to date as blocks change
+    pub light: Vec<Vec<ColumnLight>>,
 }
@@ -1,4 +1,14 @@
+use super::minecraft_types::ChatComponent;
+
 use std::sync::mpsc::Sender;
 use uuid::Uuid;
 
-define_interface!(ConnectionService, (Close, close, [conn_id: Uuid]));
+define_interface!(
+    ConnectionService,
+    (
+        Disconnect,
+        disconnect,
+        [conn_id: Uuid, reason: ChatComponent]
+    ),
+    (Close, close, [conn_id: Uuid])
+);
@@ -1,28 +1,122 @@
+// Operations are fire and forget by default. Queries are declared by giving the operation a return
+// type, as in `(GetPlayer, get_player, [conn_id: Uuid] -> Option<Player>)`: the operation carries a
+// `reply` for the service to answer through and the caller gets back a Query to wait on
 macro_rules! define_interface {
+    (@method $op:ident, $op_method:ident, [ $( $field_name:ident: $field_type:ty ),* ]) => {
+        fn $op_method(&self, $( $field_name: $field_type ),*) {
+            if self.send(Operations::$op($op { $( $field_name ),* })).is_err() {
+                trace!("Dropping {} for a stopped service", stringify!($op));
+            }
+        }
+    };
+    (@method $op:ident, $op_method:ident, [ $( $field_name:ident: $field_type:ty ),* ], $ret:ty) => {
+        // The query goes unanswered if the service has stopped
+        fn $op_method(&self, $( $field_name: $field_type ),*) -> super::query::Query<$ret> {
+            let (reply, query) = super::query::Query::channel();
+            if self.send(Operations::$op($op { $( $field_name, )* reply })).is_err() {
+                trace!("Dropping {} for a stopped service", stringify!($op));
+            }
+            query
+        }
+    };
+    (@record $op:ident, $op_method:ident, [ $( $field_name:ident: $field_type:ty ),* ]) => {
+        fn $op_method(&self, $( $field_name: $field_type ),*) {
+            self.operations.lock().unwrap().push(Operations::$op($op { $( $field_name ),* }));
+        }
+    };
+    (@record $op:ident, $op_method:ident, [ $( $field_name:ident: $field_type:ty ),* ], $ret:ty) => {
+        fn $op_method(&self, $( $field_name: $field_type ),*) -> super::query::Query<$ret> {
+            let (reply, query) = super::query::Query::channel();
+            let operation = Operations::$op($op { $( $field_name, )* reply });
+            if let Some(answer) = &self.answer {
+                answer(&operation);
+            }
+            if let Operations::$op(msg) = &operation {
+                msg.reply.close();
+            }
+            self.operations.lock().unwrap().push(operation);
+            query
+        }
+    };
     ($name:ident,
-            $( ( $op:ident, $op_method:ident, [ $( $field_name:ident: $field_type:ty ),* ] ) ),*
+            $( ( $op:ident, $op_method:ident, [ $( $field_name:ident: $field_type:ty ),* ] $( -> $ret:ty )? ) ),*
     ) => {
         pub trait $name {
-            $( fn $op_method(&self, $( $field_name: $field_type ),*); )*
+            $( fn $op_method(&self, $( $field_name: $field_type ),*) $( -> super::query::Query<$ret> )?; )*
         }
 
+        // Once a service has stopped during shutdown, anything still sent its way is dropped
         impl $name for Sender<Operations> {
             $(
-                fn $op_method(&self, $( $field_name: $field_type ),*) {
-                    self.send(Operations::$op($op { $( $field_name ),* }))
-                        .unwrap();
-                }
+                define_interface!(@method $op, $op_method, [ $( $field_name: $field_type ),* ] $(, $ret)?);
             )*
         }
 
+        #[derive(Debug)]
         pub enum Operations {
             $( $op($op), )*
+            Stop(Stop),
+        }
+
+        impl super::Operation for Operations {
+            fn stop() -> Operations {
+                Operations::Stop(Stop {})
+            }
+
+            fn name(&self) -> &'static str {
+                match self {
+                    $( Operations::$op(_) => stringify!($op), )*
+                    Operations::Stop(_) => "Stop",
+                }
+            }
+        }
+
+        #[derive(Debug)]
+        pub struct Stop {}
+
+        // Stands in for the service in tests, keeping every operation sent its way so they can be
+        // checked afterwards. Queries are answered by the closure given to answering, if any. Not
+        // every interface's recorder is used
+        #[cfg(test)]
+        #[allow(dead_code)]
+        #[derive(Clone, Default)]
+        pub struct Recorder {
+            operations: std::sync::Arc<std::sync::Mutex<Vec<Operations>>>,
+            answer: Option<std::sync::Arc<dyn Fn(&Operations) + std::marker::Send + Sync>>,
+        }
+
+        #[cfg(test)]
+        #[allow(dead_code)]
+        impl Recorder {
+            pub fn new() -> Recorder {
+                Recorder::default()
+            }
+
+            pub fn answering<F: 'static + Fn(&Operations) + std::marker::Send + Sync>(answer: F) -> Recorder {
+                Recorder {
+                    operations: Default::default(),
+                    answer: Some(std::sync::Arc::new(answer)),
+                }
+            }
+
+            // Everything recorded so far, leaving the recorder empty
+            pub fn take(&self) -> Vec<Operations> {
+                std::mem::take(&mut *self.operations.lock().unwrap())
+            }
+        }
+
+        #[cfg(test)]
+        impl $name for Recorder {
+            $(
+                define_interface!(@record $op, $op_method, [ $( $field_name: $field_type ),* ] $(, $ret)?);
+            )*
         }
 
         $(
             #[derive(Debug)]
             pub struct $op {
                 $( pub $field_name: $field_type, )*
+                $( pub reply: super::query::Reply<$ret>, )?
             }
         )*
     }
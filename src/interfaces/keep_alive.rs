@@ -0,0 +1,9 @@
+use std::sync::mpsc::Sender;
+use uuid::Uuid;
+
+define_interface!(
+    KeepAliveService,
+    (Track, track, [conn_id: Uuid]),
+    (Response, response, [conn_id: Uuid, id: i64]),
+    (Untrack, untrack, [conn_id: Uuid])
+);
@@ -0,0 +1,21 @@
+use super::player::Player;
+use super::session::GameProfile;
+use std::sync::mpsc::Sender;
+use uuid::Uuid;
+
+define_interface!(
+    LoginService,
+    (Start, start, [conn_id: Uuid, username: String]),
+    (
+        EncryptionResponse,
+        encryption_response,
+        [conn_id: Uuid, shared_secret: Vec<u8>, verify_token: Vec<u8>]
+    ),
+    (
+        Verified,
+        verified,
+        [conn_id: Uuid, profile: Result<GameProfile, String>]
+    ),
+    (Admitted, admitted, [conn_id: Uuid, player: Player]),
+    (Abandon, abandon, [conn_id: Uuid])
+);
@@ -1,6 +1,9 @@
+use super::encryption::DecryptionSlot;
 use super::map::Map;
+use super::minecraft_types::ChatComponent;
+use super::outbound::Outbound;
 use super::packet::Packet;
-use std::net::TcpStream;
+use super::protocol_version::ProtocolVersion;
 use std::sync::mpsc::Sender;
 use uuid::Uuid;
 
@@ -17,13 +20,39 @@ define_interface!(
         ]
     ),
     (Subscribe, subscribe, [conn_id: Uuid, typ: SubscriberType]),
-    (New, new_connection, [conn_id: Uuid, socket: TcpStream]),
+    (
+        New,
+        new_connection,
+        [conn_id: Uuid, outbound: Outbound, decryption: DecryptionSlot]
+    ),
+    (
+        EnableCompression,
+        enable_compression,
+        [conn_id: Uuid, threshold: i32]
+    ),
+    (
+        EnableEncryption,
+        enable_encryption,
+        [conn_id: Uuid, shared_secret: Vec<u8>]
+    ),
     (
         UpdateTranslation,
         update_translation,
         [conn_id: Uuid, map: Map]
     ),
-    (Close, close, [conn_id: Uuid])
+    (UpdateState, update_state, [conn_id: Uuid, state: i32]),
+    (
+        UpdateProtocolVersion,
+        update_protocol_version,
+        [conn_id: Uuid, version: ProtocolVersion]
+    ),
+    (
+        Disconnect,
+        disconnect,
+        [conn_id: Uuid, reason: ChatComponent]
+    ),
+    (Close, close, [conn_id: Uuid]),
+    (DisconnectAll, disconnect_all, [reason: ChatComponent])
 );
 
 #[derive(Debug)]
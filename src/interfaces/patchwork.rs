@@ -1,6 +1,7 @@
-use super::map::{Peer, PeerConnection};
+use super::map::{Map, Peer, PeerConnection};
 use super::packet::Packet;
 use std::sync::mpsc::Sender;
+use tokio::net::TcpStream;
 use uuid::Uuid;
 
 define_interface!(
@@ -16,5 +17,13 @@ define_interface!(
         ConnectMap,
         connect_map,
         [map_index: usize, peer_connection: PeerConnection]
-    )
+    ),
+    (DropMap, drop_map, [map_index: usize]),
+    (
+        AnchorConnected,
+        anchor_connected,
+        [conn_id: Uuid, anchor_conn_id: Uuid, stream: TcpStream]
+    ),
+    (DropPlayer, drop_player, [conn_id: Uuid]),
+    (GetMaps, maps, [] -> Vec<Map>)
 );
@@ -1,4 +1,4 @@
-use super::minecraft_types::{Description, Version};
+use super::minecraft_types::ProfileProperty;
 use super::packet::Packet;
 use std::sync::mpsc::Sender;
 use uuid::Uuid;
@@ -7,8 +7,10 @@ define_interface!(
     PlayerState,
     (Report, report, [conn_id: Uuid]),
     (New, new_player, [conn_id: Uuid, player: Player]),
+    (Admit, admit, [conn_id: Uuid, player: Player]),
     (Login, login, [conn_id: Uuid]),
     (Delete, delete_player, [conn_id: Uuid]),
+    (UpdateLatency, update_latency, [conn_id: Uuid, latency: i32]),
     (
         MoveAndLook,
         move_and_look,
@@ -38,11 +40,8 @@ define_interface!(
         [entity_id: i32, packet: Packet]
     ),
     (Reintroduce, reintroduce, [conn_id: Uuid]),
-    (
-        StatusResponse,
-        status_response,
-        [conn_id: Uuid, version: Version, description: Description]
-    )
+    (GetPlayer, get_player, [conn_id: Uuid] -> Option<Player>),
+    (ReportStatus, report_status, [conn_id: Uuid, protocol_version: i32])
 );
 
 #[derive(Debug, Clone)]
@@ -50,9 +49,11 @@ pub struct Player {
     pub conn_id: Uuid,
     pub uuid: Uuid,
     pub name: String,
+    pub properties: Vec<ProfileProperty>,
     pub position: Position,
     pub angle: Angle,
     pub entity_id: i32,
+    pub ping: i32,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -0,0 +1,99 @@
+// Replies to query operations. A query is sent like any other operation, along with a Reply the
+// service answers through once it gets to it, and the caller waits on the matching Query.
+//
+// Services handle their operations one at a time, so a service must never wait on a query to a
+// service that could be waiting on it (or on itself): neither would ever get to answering
+
+use std::cell::Cell;
+use std::fmt;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+pub struct Query<T>(Receiver<T>);
+
+// Replies are one-shot: the query is answered by the first value sent
+pub struct Reply<T>(Cell<Option<Sender<T>>>);
+
+#[derive(Debug, PartialEq)]
+pub enum QueryError {
+    // The service stopped, or dropped the reply without answering
+    Unanswered,
+    TimedOut,
+}
+
+impl<T> Query<T> {
+    pub fn channel() -> (Reply<T>, Query<T>) {
+        let (sender, receiver) = channel();
+        (Reply(Cell::new(Some(sender))), Query(receiver))
+    }
+
+    pub fn wait(self) -> Result<T, QueryError> {
+        self.0.recv().map_err(|_| QueryError::Unanswered)
+    }
+
+    pub fn wait_timeout(self, timeout: Duration) -> Result<T, QueryError> {
+        self.0.recv_timeout(timeout).map_err(|e| match e {
+            RecvTimeoutError::Timeout => QueryError::TimedOut,
+            RecvTimeoutError::Disconnected => QueryError::Unanswered,
+        })
+    }
+}
+
+impl<T> Reply<T> {
+    // Whoever asked may have given up waiting already, which is fine
+    pub fn send(&self, value: T) {
+        if let Some(sender) = self.0.take() {
+            let _ = sender.send(value);
+        }
+    }
+
+    // Lets recorders leave queries they weren't told how to answer unanswered, rather than have
+    // whoever asked wait on the recorded reply forever
+    #[cfg(test)]
+    pub fn close(&self) {
+        self.0.take();
+    }
+}
+
+impl<T> fmt::Debug for Reply<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Reply")
+    }
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryError::Unanswered => write!(f, "the service did not answer"),
+            QueryError::TimedOut => write!(f, "timed out waiting for the service to answer"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answered_queries_return_the_reply() {
+        let (reply, query) = Query::channel();
+        reply.send(5);
+        assert_eq!(Ok(5), query.wait());
+    }
+
+    #[test]
+    fn dropped_replies_are_unanswered() {
+        let (reply, query) = Query::<i32>::channel();
+        drop(reply);
+        assert_eq!(Err(QueryError::Unanswered), query.wait());
+    }
+
+    #[test]
+    fn waiting_can_time_out() {
+        let (_reply, query) = Query::<i32>::channel();
+        assert_eq!(
+            Err(QueryError::TimedOut),
+            query.wait_timeout(Duration::from_millis(10))
+        );
+    }
+}
@@ -0,0 +1,23 @@
+// Everything that makes up a node lives in this library, so nodes can be run in-process by the
+// integration tests and driven with the bot client. The patchwork binary just runs a single node
+
+#[macro_use]
+mod services;
+pub mod bot;
+pub mod cluster;
+pub mod config;
+pub mod console;
+pub mod constants;
+pub mod interfaces;
+pub mod models;
+pub mod node;
+mod packet_handlers;
+mod server;
+pub mod shutdown;
+#[cfg(test)]
+mod test_support;
+
+#[macro_use]
+extern crate log;
+extern crate serde;
+extern crate serde_json;
@@ -1,36 +1,27 @@
-#[macro_use]
-mod services;
-mod constants;
-mod interfaces;
-mod models;
-mod packet_handlers;
-mod server;
-
-use interfaces::patchwork::PatchworkState;
-
-use services::instance::ServiceInstance;
+use patchwork::config;
+use patchwork::console;
+use patchwork::node::Node;
+use patchwork::shutdown;
 
 use std::env;
-use std::thread;
+use std::process;
 
 #[macro_use]
 extern crate log;
 extern crate simplelog;
 use simplelog::{ConfigBuilder, LevelFilter, SimpleLogger};
-extern crate serde;
-extern crate serde_json;
-
-const DEFAULT_LOGGING_LEVEL: LevelFilter = LevelFilter::Info;
 
 fn main() {
-    let level = match env::var("LOG") {
-        Ok(level) => match level.as_str() {
-            "info" => LevelFilter::Info,
-            "trace" => LevelFilter::Trace,
-            "error" => LevelFilter::Error,
-            _ => DEFAULT_LOGGING_LEVEL,
-        },
-        Err(_) => DEFAULT_LOGGING_LEVEL,
+    let config = match config::load(env::args().skip(1), |key| env::var(key).ok()) {
+        Ok(config) => config,
+        Err(config::ConfigError::HelpRequested) => {
+            println!("{}", config::USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("patchwork: {}", e);
+            process::exit(1);
+        }
     };
 
     let logger_config = ConfigBuilder::new()
@@ -39,60 +30,18 @@ fn main() {
         .set_target_level(LevelFilter::Off)
         .build();
 
-    SimpleLogger::init(level, logger_config).unwrap();
+    SimpleLogger::init(config.log_level, logger_config).unwrap();
 
-    define_services!(
-    (
-        module: services::player::start,
-        name: player_state,
-        dependencies: [messenger, block_state, patchwork_state]
-    ),
-    (
-        module: services::block::start,
-        name: block_state,
-        dependencies: [messenger]
-    ),
-    (
-        module: services::patchwork::start,
-        name: patchwork_state,
-        dependencies: [messenger, inbound_packet_processor, player_state, block_state]
-    ),
-    (
-        module: services::messenger::start,
-        name: messenger,
-        dependencies: []
-    ),
-    (
-        module: services::packet_processor::start_inbound,
-        name: inbound_packet_processor,
-        dependencies: [messenger, player_state, block_state, patchwork_state]
-    ),
-    (
-        module: services::connection::start,
-        name: connection_service,
-        dependencies: [messenger, player_state, patchwork_state, inbound_packet_processor]
-    ),
-    (
-        module: services::keep_alive::start,
-        name: keep_alive,
-        dependencies: [messenger]
-    )
-        );
-
-    trace!("Services Started");
-
-    // the stuff below this should also probably be moved to a service model
-    let peer_address = String::from("127.0.0.1");
-    let peer_port = env::var("PEER_PORT").unwrap().parse::<u16>().unwrap();
-
-    patchwork_state.sender().new_map(models::map::Peer {
-        port: peer_port,
-        address: peer_address,
-    });
+    let node = match Node::start(config) {
+        Ok(node) => node,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
+    shutdown::on_signal(node.shutdown_sender());
+    console::start(node.shutdown_sender());
 
-    server::listen(
-        inbound_packet_processor.sender(),
-        connection_service.sender(),
-        messenger.sender(),
-    );
+    node.wait_for_shutdown_request();
+    node.stop();
 }
@@ -1,9 +1,18 @@
 #[macro_use]
 mod packet_macros;
+pub mod capture;
+pub mod compression;
+pub mod encryption;
+pub mod lighting;
 pub mod map;
 pub mod minecraft_protocol;
 pub mod minecraft_types;
+pub mod nbt;
+pub mod outbound;
 pub mod packet;
+pub mod protocol_error;
+pub mod protocol_version;
+pub mod session;
 pub mod translation;
 
 use super::constants;
@@ -0,0 +1,267 @@
+// A capture is every frame a node sent and received, one JSON record per line, for debugging
+// translation bugs after the fact with patchwork-replay. Frames are kept as they were on the wire
+// apart from encryption, along with the state and protocol version of the connection at the time,
+// which is everything needed to decode them again
+
+use super::compression::decompress;
+use super::minecraft_protocol::MinecraftProtocolReader;
+use super::packet::{read, Direction as PacketDirection, Packet, Status};
+use super::protocol_error::ProtocolError;
+use super::protocol_version::ProtocolVersion;
+
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Cursor, LineWriter, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record {
+    // Microseconds since the unix epoch
+    pub time: u64,
+    pub conn_id: Uuid,
+    pub direction: Direction,
+    pub state: i32,
+    // Captures from before clients could be on other versions are all on the native one
+    #[serde(default)]
+    pub protocol_version: i32,
+    pub compressed: bool,
+    #[serde(with = "hex")]
+    pub frame: Vec<u8>,
+}
+
+impl Record {
+    pub fn decode(&self) -> Result<Packet, ProtocolError> {
+        let frame = match self.compressed {
+            true => decompress(self.frame.clone())?,
+            false => self.frame.clone(),
+        };
+        let status = Status::from_i32(self.state)?;
+        // What we send is read by the other side of the connection, which sees the peer
+        // subscription states the other way around
+        let (status, direction) = match (self.direction, status) {
+            (Direction::Inbound, status) => (status, status.inbound()),
+            (Direction::Outbound, Status::InPeerSub) => {
+                (Status::OutPeerSub, PacketDirection::Serverbound)
+            }
+            (Direction::Outbound, Status::OutPeerSub) => {
+                (Status::InPeerSub, PacketDirection::Clientbound)
+            }
+            (Direction::Outbound, status) => (status, PacketDirection::Clientbound),
+        };
+        let version = ProtocolVersion::negotiate(self.protocol_version);
+        read(&mut Cursor::new(frame), status, direction, version)
+    }
+}
+
+// Shared by everything on the node that sees frames. Lines are flushed as they're written, so a
+// capture is still readable after a crash
+#[derive(Clone)]
+pub struct Capture {
+    file: Arc<Mutex<LineWriter<File>>>,
+}
+
+impl Capture {
+    pub fn create(path: &str) -> io::Result<Capture> {
+        Ok(Capture {
+            file: Arc::new(Mutex::new(LineWriter::new(File::create(path)?))),
+        })
+    }
+
+    // A frame as it was read off the connection
+    pub fn inbound(
+        &self,
+        conn_id: Uuid,
+        state: i32,
+        version: ProtocolVersion,
+        compressed: bool,
+        frame: &[u8],
+    ) {
+        self.record(
+            conn_id,
+            Direction::Inbound,
+            state,
+            version,
+            compressed,
+            frame.to_vec(),
+        );
+    }
+
+    // Packets as they are about to be written to the connection, length prefixes and all. Some go
+    // out with others ahead of them, and each of those is recorded as a frame of its own
+    pub fn outbound(
+        &self,
+        conn_id: Uuid,
+        state: i32,
+        version: ProtocolVersion,
+        compressed: bool,
+        bytes: &[u8],
+    ) {
+        let mut cursor = Cursor::new(bytes);
+        while (cursor.position() as usize) < bytes.len() {
+            let start = cursor.position() as usize;
+            let frame = match cursor.read_var_int() {
+                Ok(length) if length >= 0 => {
+                    let start = cursor.position() as usize;
+                    bytes.get(start..start + length as usize)
+                }
+                _ => None,
+            };
+            let frame = match frame {
+                Some(frame) => frame,
+                None => {
+                    warn!(
+                        "Not capturing a packet without a valid length for {:?} at byte {}",
+                        conn_id, start
+                    );
+                    return;
+                }
+            };
+            cursor.set_position(cursor.position() + frame.len() as u64);
+            self.record(
+                conn_id,
+                Direction::Outbound,
+                state,
+                version,
+                compressed,
+                frame.to_vec(),
+            );
+        }
+    }
+
+    fn record(
+        &self,
+        conn_id: Uuid,
+        direction: Direction,
+        state: i32,
+        version: ProtocolVersion,
+        compressed: bool,
+        frame: Vec<u8>,
+    ) {
+        let record = Record {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_micros() as u64)
+                .unwrap_or(0),
+            conn_id,
+            direction,
+            state,
+            protocol_version: version.number(),
+            compressed,
+            frame,
+        };
+        let line = serde_json::to_string(&record).expect("records are always serializable");
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = writeln!(file, "{}", line) {
+            warn!("Failed to write to the capture file: {}", e);
+        }
+    }
+}
+
+pub fn load(path: &str) -> Result<Vec<Record>, String> {
+    let file = File::open(path).map_err(|e| format!("could not open {:?}: {}", path, e))?;
+    BufReader::new(file)
+        .lines()
+        .enumerate()
+        .map(|(index, line)| {
+            let line = line.map_err(|e| format!("could not read {:?}: {}", path, e))?;
+            serde_json::from_str(&line)
+                .map_err(|e| format!("{:?} line {} is not a record: {}", path, index + 1, e))
+        })
+        .collect()
+}
+
+mod hex {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        serializer.serialize_str(&hex)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        (0..hex.len())
+            .step_by(2)
+            .map(|index| {
+                hex.get(index..index + 2)
+                    .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                    .ok_or_else(|| D::Error::custom("frames must be written in hex"))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::packet::{write, ClientboundKeepAlive, Handshake, LoginSuccess};
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn captured_frames_decode_to_what_was_sent() {
+        let path = env::temp_dir().join(format!("capture-{}.jsonl", Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+        let capture = Capture::create(path).unwrap();
+        let conn_id = Uuid::new_v4();
+
+        let mut handshake = Vec::new();
+        write(
+            &mut handshake,
+            Packet::Handshake(Handshake {
+                protocol_version: 404,
+                server_address: String::from("localhost"),
+                server_port: 25565,
+                next_state: 2,
+            }),
+            None,
+            ProtocolVersion::NATIVE,
+        );
+        capture.inbound(conn_id, 0, ProtocolVersion::NATIVE, false, &handshake[1..]);
+        let mut login_success = Vec::new();
+        write(
+            &mut login_success,
+            Packet::LoginSuccess(LoginSuccess {
+                uuid: Uuid::new_v4().to_string(),
+                username: String::from("Steve"),
+            }),
+            Some(0),
+            ProtocolVersion::NATIVE,
+        );
+        capture.outbound(conn_id, 2, ProtocolVersion::NATIVE, true, &login_success);
+        let mut keep_alive = Vec::new();
+        write(
+            &mut keep_alive,
+            Packet::ClientboundKeepAlive(ClientboundKeepAlive { id: 7 }),
+            Some(256),
+            ProtocolVersion::V1_14_4,
+        );
+        capture.outbound(conn_id, 3, ProtocolVersion::V1_14_4, true, &keep_alive);
+
+        let records = load(path).unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(3, records.len());
+        assert!(matches!(
+            records[0].decode(),
+            Ok(Packet::Handshake(Handshake { next_state: 2, .. }))
+        ));
+        match records[1].decode() {
+            Ok(Packet::LoginSuccess(login_success)) => assert_eq!("Steve", login_success.username),
+            other => panic!("expected LoginSuccess, got {:?}", other),
+        }
+        assert!(matches!(
+            records[2].decode(),
+            Ok(Packet::ClientboundKeepAlive(ClientboundKeepAlive { id: 7 }))
+        ));
+    }
+}
@@ -0,0 +1,87 @@
+// Once compression is enabled on a connection every frame carries the uncompressed length of the
+// packet ahead of the packet body:
+//    - packets smaller than the threshold are sent as-is with an uncompressed length of 0
+//    - everything else is zlib compressed
+//
+// The outer length prefix is unchanged, so frames can still be split off the socket without
+// knowing whether compression is on
+
+use super::minecraft_protocol::{MinecraftProtocolReader, MinecraftProtocolWriter};
+use super::protocol_error::ProtocolError;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::io::{Cursor, Read, Write};
+
+// The largest uncompressed packet the vanilla client will accept
+const MAX_UNCOMPRESSED_LENGTH: usize = 2_097_152;
+
+pub fn compress(payload: Vec<u8>, threshold: i32) -> Vec<u8> {
+    let mut frame = Vec::new();
+    if payload.len() < threshold as usize {
+        frame.write_var_int(0);
+        frame.extend(payload);
+    } else {
+        frame.write_var_int(payload.len() as i32);
+        let mut encoder = ZlibEncoder::new(frame, Compression::default());
+        encoder
+            .write_all(&payload)
+            .expect("writing to a vector cannot fail");
+        frame = encoder.finish().expect("writing to a vector cannot fail");
+    }
+    frame
+}
+
+pub fn decompress(frame: Vec<u8>) -> Result<Vec<u8>, ProtocolError> {
+    let mut cursor = Cursor::new(frame);
+    let uncompressed_length = cursor.read_var_int()? as usize;
+    let offset = cursor.position() as usize;
+    let mut frame = cursor.into_inner();
+    if uncompressed_length == 0 {
+        return Ok(frame.split_off(offset));
+    }
+    if uncompressed_length > MAX_UNCOMPRESSED_LENGTH {
+        return Err(ProtocolError::PacketTooLarge(uncompressed_length));
+    }
+
+    let mut payload = Vec::with_capacity(uncompressed_length);
+    ZlibDecoder::new(&frame[offset..])
+        .take(uncompressed_length as u64 + 1)
+        .read_to_end(&mut payload)
+        .map_err(|e| ProtocolError::BadCompression(e.to_string()))?;
+    if payload.len() != uncompressed_length {
+        return Err(ProtocolError::BadCompression(format!(
+            "packet was {} bytes, expected {}",
+            payload.len(),
+            uncompressed_length
+        )));
+    }
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_packets_are_not_compressed() {
+        let frame = compress(vec![1, 2, 3], 256);
+        assert_eq!(vec![0, 1, 2, 3], frame);
+        assert_eq!(vec![1, 2, 3], decompress(frame).unwrap());
+    }
+
+    #[test]
+    fn large_packets_round_trip() {
+        let payload: Vec<u8> = (0..4096).map(|i| (i % 7) as u8).collect();
+        let frame = compress(payload.clone(), 256);
+        assert!(frame.len() < payload.len());
+        assert_eq!(payload, decompress(frame).unwrap());
+    }
+
+    #[test]
+    fn rejects_mismatched_length() {
+        let mut frame = compress(vec![7; 512], 256);
+        frame[0] = 0x81; // claim the payload is 513 bytes instead of 512
+        assert!(decompress(frame).is_err());
+    }
+}
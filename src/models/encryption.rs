@@ -0,0 +1,228 @@
+// Online mode login encrypts the connection with AES/CFB8, using the shared secret the client
+// sends us (encrypted with our RSA public key) as both the key and the IV.
+//
+// Packets are written by the messenger but read on the connection's own thread, so the
+// encrypting half of the cipher lives with the messenger while the decrypting half is shared
+// with the reader through a DecryptionSlot that starts out empty
+
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use aes::Aes128;
+use rand_core::OsRng;
+use rsa::pkcs8::EncodePublicKey;
+use rsa::{Pkcs1v15Encrypt, RsaPrivateKey};
+use sha1::{Digest, Sha1};
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+
+const KEY_SIZE: usize = 1024;
+const SHARED_SECRET_LENGTH: usize = 16;
+
+pub struct ServerKey {
+    private_key: RsaPrivateKey,
+    pub public_key_der: Vec<u8>,
+}
+
+impl ServerKey {
+    pub fn generate() -> Result<ServerKey, rsa::Error> {
+        let private_key = RsaPrivateKey::new(&mut OsRng, KEY_SIZE)?;
+        let public_key_der = private_key
+            .to_public_key()
+            .to_public_key_der()
+            .map_err(|e| rsa::Error::Pkcs8(e.into()))?
+            .into_vec();
+        Ok(ServerKey {
+            private_key,
+            public_key_der,
+        })
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, rsa::Error> {
+        self.private_key.decrypt(Pkcs1v15Encrypt, data)
+    }
+
+    pub fn decrypt_shared_secret(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let shared_secret = self
+            .decrypt(data)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        if shared_secret.len() != SHARED_SECRET_LENGTH {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("shared secret was {} bytes", shared_secret.len()),
+            ));
+        }
+        Ok(shared_secret)
+    }
+}
+
+// The hash the client sends to the session server when joining, which we have to send as well
+// when asking whether they did
+pub fn server_hash(server_id: &str, shared_secret: &[u8], public_key_der: &[u8]) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(server_id.as_bytes());
+    hasher.update(shared_secret);
+    hasher.update(public_key_der);
+    minecraft_hex_digest(hasher.finalize().into())
+}
+
+// Minecraft prints SHA-1 digests as a signed (two's complement) big integer in hex, without
+// leading zeros
+fn minecraft_hex_digest(mut digest: [u8; 20]) -> String {
+    let negative = digest[0] & 0x80 != 0;
+    if negative {
+        let mut carry = true;
+        for byte in digest.iter_mut().rev() {
+            *byte = !*byte;
+            if carry {
+                let (value, overflowed) = byte.overflowing_add(1);
+                *byte = value;
+                carry = overflowed;
+            }
+        }
+    }
+    let hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+    let hex = hex.trim_start_matches('0');
+    if negative {
+        format!("-{}", hex)
+    } else {
+        hex.to_string()
+    }
+}
+
+pub struct Encryptor(cfb8::Encryptor<Aes128>);
+
+impl Encryptor {
+    pub fn new(shared_secret: &[u8]) -> Encryptor {
+        Encryptor(cfb8::Encryptor::new(
+            GenericArray::from_slice(shared_secret),
+            GenericArray::from_slice(shared_secret),
+        ))
+    }
+
+    pub fn encrypt(&mut self, data: &mut [u8]) {
+        data.iter_mut().for_each(|byte| {
+            self.0
+                .encrypt_block_mut(GenericArray::from_mut_slice(std::slice::from_mut(byte)))
+        });
+    }
+}
+
+pub struct Decryptor(cfb8::Decryptor<Aes128>);
+
+impl Decryptor {
+    pub fn new(shared_secret: &[u8]) -> Decryptor {
+        Decryptor(cfb8::Decryptor::new(
+            GenericArray::from_slice(shared_secret),
+            GenericArray::from_slice(shared_secret),
+        ))
+    }
+
+    pub fn decrypt(&mut self, data: &mut [u8]) {
+        data.iter_mut().for_each(|byte| {
+            self.0
+                .decrypt_block_mut(GenericArray::from_mut_slice(std::slice::from_mut(byte)))
+        });
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct DecryptionSlot(Arc<Mutex<Option<Decryptor>>>);
+
+impl DecryptionSlot {
+    pub fn enable(&self, shared_secret: &[u8]) {
+        *self.0.lock().unwrap() = Some(Decryptor::new(shared_secret));
+    }
+
+    fn decrypt(&self, data: &mut [u8]) {
+        if let Some(decryptor) = self.0.lock().unwrap().as_mut() {
+            decryptor.decrypt(data);
+        }
+    }
+}
+
+impl fmt::Debug for DecryptionSlot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let enabled = self.0.lock().map(|slot| slot.is_some()).unwrap_or(false);
+        write!(f, "DecryptionSlot {{ enabled: {:?} }}", enabled)
+    }
+}
+
+// Decrypts everything read from the inner stream once the slot has been filled
+pub struct DecryptingReader<R: AsyncRead + Unpin> {
+    inner: R,
+    slot: DecryptionSlot,
+}
+
+impl<R: AsyncRead + Unpin> DecryptingReader<R> {
+    pub fn new(inner: R, slot: DecryptionSlot) -> DecryptingReader<R> {
+        DecryptingReader { inner, slot }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for DecryptingReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        let already_filled = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            this.slot.decrypt(&mut buf.filled_mut()[already_filled..]);
+        }
+        poll
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_digests_match_vanilla() {
+        let digest = |name: &str| minecraft_hex_digest(Sha1::digest(name.as_bytes()).into());
+        assert_eq!("4ed1f46bbe04bc756bcb17c0c7ce3e4632f06a48", digest("Notch"));
+        assert_eq!("-7c9d5b0044c130109a5d7b5fb5c317c02b4e28c1", digest("jeb_"));
+        assert_eq!("88e16a1019277b15d58faf0541e11910eb756f6", digest("simon"));
+    }
+
+    #[tokio::test]
+    async fn cfb8_round_trip_across_reads() {
+        use tokio::io::AsyncReadExt;
+
+        let secret = [7u8; SHARED_SECRET_LENGTH];
+        let mut data: Vec<u8> = (0..100).collect();
+        Encryptor::new(&secret).encrypt(&mut data);
+        assert_ne!((0..100).collect::<Vec<u8>>(), data);
+
+        let slot = DecryptionSlot::default();
+        slot.enable(&secret);
+        let mut reader = DecryptingReader::new(&data[..], slot);
+        let mut first = [0u8; 30];
+        let mut rest = Vec::new();
+        reader.read_exact(&mut first).await.unwrap();
+        reader.read_to_end(&mut rest).await.unwrap();
+        assert_eq!((0..30).collect::<Vec<u8>>(), first.to_vec());
+        assert_eq!((30..100).collect::<Vec<u8>>(), rest);
+    }
+
+    #[test]
+    fn shared_secret_round_trip() {
+        let key = ServerKey::generate().unwrap();
+        let public_key = key.private_key.to_public_key();
+        let secret = [42u8; SHARED_SECRET_LENGTH];
+        let encrypted = public_key
+            .encrypt(&mut OsRng, Pkcs1v15Encrypt, &secret)
+            .unwrap();
+        assert_eq!(
+            secret.to_vec(),
+            key.decrypt_shared_secret(&encrypted).unwrap()
+        );
+        assert!(key.decrypt_shared_secret(&[0; 128]).is_err());
+    }
+}
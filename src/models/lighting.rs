@@ -0,0 +1,195 @@
+// Works out the light in a chunk column the way vanilla does. Sky light shines straight down to the
+// first block that stops it and block light starts at blocks that give it off, and both then spread
+// out a level dimmer with every block they pass through. Light doesn't cross into neighbouring
+// columns yet
+
+use super::minecraft_types::{LIGHT_ARRAY_LENGTH, SECTIONS_PER_COLUMN};
+use std::collections::VecDeque;
+
+pub const MAX_LIGHT: u8 = 15;
+
+const BLOCKS_PER_SECTION: usize = 4096;
+const BLOCKS_PER_COLUMN: usize = BLOCKS_PER_SECTION * SECTIONS_PER_COLUMN;
+const HEIGHT: usize = 16 * SECTIONS_PER_COLUMN;
+
+// The light each section of a column gets, as nibble arrays ready to be sent
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnLight {
+    pub block_light: Vec<Vec<u8>>,
+    pub sky_light: Vec<Vec<u8>>,
+}
+
+// Sections hold block states indexed by x, then z, then y, the same as they're sent
+pub fn light_column(sections: &[Vec<i32>]) -> ColumnLight {
+    let block_id = |index: usize| {
+        sections
+            .get(index / BLOCKS_PER_SECTION)
+            .and_then(|section| section.get(index % BLOCKS_PER_SECTION))
+            .copied()
+            .unwrap_or(0)
+    };
+    let opacities: Vec<u8> = (0..BLOCKS_PER_COLUMN)
+        .map(|index| opacity(block_id(index)))
+        .collect();
+
+    let mut sky_light = vec![0; BLOCKS_PER_COLUMN];
+    for (x, z) in (0..16).flat_map(|x| (0..16).map(move |z| (x, z))) {
+        for y in height(&opacities, x, z)..HEIGHT {
+            sky_light[index(x, y, z)] = MAX_LIGHT;
+        }
+    }
+    spread(&mut sky_light, &opacities);
+
+    let mut block_light: Vec<u8> = (0..BLOCKS_PER_COLUMN)
+        .map(|index| emission(block_id(index)))
+        .collect();
+    spread(&mut block_light, &opacities);
+
+    ColumnLight {
+        block_light: nibble_arrays(&block_light),
+        sky_light: nibble_arrays(&sky_light),
+    }
+}
+
+// One above the highest block that stops any light, below which the sky can't be seen
+fn height(opacities: &[u8], x: usize, z: usize) -> usize {
+    (0..HEIGHT)
+        .rev()
+        .find(|y| opacities[index(x, *y, z)] > 0)
+        .map_or(0, |y| y + 1)
+}
+
+// Every lit block lights its neighbours, dimmed by at least a level and more if they're opaque
+fn spread(light: &mut [u8], opacities: &[u8]) {
+    let mut queue: VecDeque<usize> = (0..BLOCKS_PER_COLUMN)
+        .filter(|index| light[*index] > 1)
+        .collect();
+    while let Some(index) = queue.pop_front() {
+        for neighbour in neighbours(index).iter().flatten() {
+            let level = light[index].saturating_sub(opacities[*neighbour].max(1));
+            if level > light[*neighbour] {
+                light[*neighbour] = level;
+                queue.push_back(*neighbour);
+            }
+        }
+    }
+}
+
+fn neighbours(index: usize) -> [Option<usize>; 6] {
+    let (x, y, z) = (index % 16, index / 256, index / 16 % 16);
+    [
+        x.checked_sub(1).map(|x| self::index(x, y, z)),
+        Some(x + 1)
+            .filter(|x| *x < 16)
+            .map(|x| self::index(x, y, z)),
+        z.checked_sub(1).map(|z| self::index(x, y, z)),
+        Some(z + 1)
+            .filter(|z| *z < 16)
+            .map(|z| self::index(x, y, z)),
+        y.checked_sub(1).map(|y| self::index(x, y, z)),
+        Some(y + 1)
+            .filter(|y| *y < HEIGHT)
+            .map(|y| self::index(x, y, z)),
+    ]
+}
+
+fn index(x: usize, y: usize, z: usize) -> usize {
+    x + z * 16 + y * 256
+}
+
+// Two blocks to a byte, the first in the low nibble
+fn nibble_arrays(light: &[u8]) -> Vec<Vec<u8>> {
+    light
+        .chunks(BLOCKS_PER_SECTION)
+        .map(|section| {
+            let nibbles: Vec<u8> = section
+                .chunks(2)
+                .map(|pair| pair[0] | pair[1] << 4)
+                .collect();
+            debug_assert_eq!(LIGHT_ARRAY_LENGTH, nibbles.len());
+            nibbles
+        })
+        .collect()
+}
+
+pub fn nibble(light: &[u8], index: usize) -> u8 {
+    light[index / 2] >> (index % 2 * 4) & 0x0F
+}
+
+// How much light a block takes away on top of the level lost to distance. Anything we don't know
+// of is taken to be a full block
+fn opacity(block_state: i32) -> u8 {
+    match block_state {
+        // Air, torches and fire
+        0 | 1127..=1643 => 0,
+        // Water and leaves
+        34..=49 | 144..=227 => 1,
+        _ => MAX_LIGHT,
+    }
+}
+
+fn emission(block_state: i32) -> u8 {
+    match block_state {
+        // Lava, fire and glowstone
+        50..=65 | 1132..=1643 | 3780 => 15,
+        // Torches and wall torches
+        1127..=1131 => 14,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STONE: i32 = 1;
+    const GLOWSTONE: i32 = 3780;
+    const LEAVES: i32 = 180;
+
+    fn column(blocks: &[((usize, usize, usize), i32)]) -> Vec<Vec<i32>> {
+        let mut sections = vec![vec![0; BLOCKS_PER_SECTION]; SECTIONS_PER_COLUMN];
+        for ((x, y, z), block_id) in blocks.iter() {
+            sections[y / 16][index(*x, y % 16, *z)] = *block_id;
+        }
+        sections
+    }
+
+    fn light_at(light: &[Vec<u8>], x: usize, y: usize, z: usize) -> u8 {
+        nibble(&light[y / 16], index(x, y % 16, z))
+    }
+
+    #[test]
+    fn the_sky_lights_everything_above_the_ground() {
+        let mut blocks = Vec::new();
+        for (x, z) in (0..16).flat_map(|x| (0..16).map(move |z| (x, z))) {
+            blocks.push(((x, 40, z), STONE));
+        }
+        // A roof with a gap, and leaves letting most of the light through
+        blocks.retain(|((x, _, z), _)| (*x, *z) != (8, 8));
+        blocks.push(((3, 41, 3), LEAVES));
+        let light = light_column(&column(&blocks));
+
+        assert_eq!(15, light_at(&light.sky_light, 0, 255, 0));
+        assert_eq!(15, light_at(&light.sky_light, 0, 41, 0));
+        assert_eq!(0, light_at(&light.sky_light, 0, 40, 0));
+        assert_eq!(14, light_at(&light.sky_light, 3, 41, 3));
+        // Straight down through the gap, then dimming away from it
+        assert_eq!(15, light_at(&light.sky_light, 8, 20, 8));
+        assert_eq!(14, light_at(&light.sky_light, 9, 20, 8));
+        assert_eq!(11, light_at(&light.sky_light, 10, 20, 10));
+        assert_eq!(0, light_at(&light.block_light, 8, 20, 8));
+    }
+
+    #[test]
+    fn light_spreads_from_emitting_blocks() {
+        let light = light_column(&column(&[((8, 100, 8), GLOWSTONE), ((8, 100, 9), STONE)]));
+        assert_eq!(15, light_at(&light.block_light, 8, 100, 8));
+        assert_eq!(14, light_at(&light.block_light, 8, 101, 8));
+        assert_eq!(12, light_at(&light.block_light, 9, 102, 8));
+        // Around the stone rather than through it
+        assert_eq!(0, light_at(&light.block_light, 8, 100, 9));
+        assert_eq!(11, light_at(&light.block_light, 8, 100, 10));
+        assert_eq!(1, light_at(&light.block_light, 8, 114, 8));
+        assert_eq!(0, light_at(&light.block_light, 8, 115, 8));
+    }
+}
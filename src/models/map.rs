@@ -1,12 +1,13 @@
 use super::interfaces::messenger::Messenger;
 use super::interfaces::packet_processor::PacketProcessor;
 use super::interfaces::patchwork::PatchworkState;
-use super::packet::{Handshake, Packet};
-use super::server;
+use super::packet::{Handshake, Packet, SetCompression};
+use super::protocol_version::ProtocolVersion;
+use super::server::Network;
 use super::translation::TranslationUpdates;
 
-use std::net::TcpStream;
-use std::thread;
+use serde::Deserialize;
+use tokio::net::TcpStream;
 use uuid::Uuid;
 
 #[derive(Debug, Clone)]
@@ -15,7 +16,8 @@ pub struct PeerConnection {
     pub conn_id: Uuid,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Peer {
     pub port: u16,
     pub address: String,
@@ -26,6 +28,7 @@ pub struct Map {
     pub position: Position,
     pub entity_id_block: i32,
     pub peer_connection: Option<PeerConnection>,
+    pub dropped: bool,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -41,7 +44,7 @@ impl Map {
             messenger.send_packet(
                 peer_connection.conn_id,
                 Packet::Handshake(Handshake {
-                    protocol_version: 404,
+                    protocol_version: ProtocolVersion::NATIVE.number(),
                     server_address: String::from(""), //Neither of these fields are actually used
                     server_port: 0,
                     next_state: 5,
@@ -55,53 +58,59 @@ impl Map {
             position,
             entity_id_block,
             peer_connection: None,
+            dropped: false,
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn connect<
         M: 'static + Messenger + Clone + Send,
         PP: 'static + PacketProcessor + Clone + Send,
         PA: 'static + PatchworkState + Clone + Send,
     >(
         &self,
+        network: Network,
         messenger: M,
         inbound_packet_processor: PP,
         peer: Peer,
         patchwork_state: PA,
         map_index: usize,
+        compression_threshold: Option<i32>,
     ) {
         let conn_id = Uuid::new_v4();
-        let translation_updates = vec![
+        let mut translation_updates = vec![
             TranslationUpdates::State(5),
             TranslationUpdates::EntityIdBlock(self.entity_id_block),
             TranslationUpdates::XOrigin(self.position.x),
             TranslationUpdates::ZOrigin(self.position.z),
         ];
+        if let Some(threshold) = compression_threshold {
+            translation_updates.push(TranslationUpdates::Compression(threshold));
+        }
         let peer_clone = peer.clone();
+        let network_clone = network.clone();
         let on_connection = move |stream: TcpStream| {
-            messenger.new_connection(conn_id, stream.try_clone().unwrap());
             inbound_packet_processor.set_translation_data(conn_id, translation_updates);
-
-            let messenger_clone = messenger.clone();
-            let inbound_packet_processor_clone = inbound_packet_processor.clone();
-            thread::spawn(move || {
-                server::handle_connection(
-                    stream.try_clone().unwrap(),
-                    inbound_packet_processor_clone,
-                    messenger_clone,
-                    conn_id,
-                    || {},
-                );
-            });
+            let closure_patchwork_state = patchwork_state.clone();
+            network_clone.open(
+                stream,
+                conn_id,
+                &messenger,
+                inbound_packet_processor.clone(),
+                move || closure_patchwork_state.drop_map(map_index),
+            );
             messenger.send_packet(
                 conn_id,
                 Packet::Handshake(Handshake {
-                    protocol_version: 404,
+                    protocol_version: ProtocolVersion::NATIVE.number(),
                     server_address: String::from(""),
                     server_port: 0,
                     next_state: 6,
                 }),
             );
+            if let Some(threshold) = compression_threshold {
+                enable_peer_compression(conn_id, messenger.clone(), threshold);
+            }
             patchwork_state.connect_map(
                 map_index,
                 PeerConnection {
@@ -110,8 +119,17 @@ impl Map {
                 },
             );
         };
-        thread::spawn(move || {
-            server::wait_for_connection(peer.address.clone(), peer.port, on_connection);
-        });
+        // Peers are connected to for as long as the node runs
+        network.connect_with_backoff(peer.address, peer.port, on_connection);
     }
 }
+
+// Peers don't go through login, so the side opening the connection announces compression right
+// after its handshake and both sides compress everything from then on
+pub fn enable_peer_compression<M: Messenger>(conn_id: Uuid, messenger: M, threshold: i32) {
+    messenger.send_packet(
+        conn_id,
+        Packet::SetCompression(SetCompression { threshold }),
+    );
+    messenger.enable_compression(conn_id, threshold);
+}
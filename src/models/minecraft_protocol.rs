@@ -1,31 +1,142 @@
 extern crate byteorder;
 
-use super::minecraft_types::{BlockPosition, ChunkSection};
+use super::minecraft_types::{
+    BlockEntity, BlockPosition, ChunkColumn, ChunkSection, ProfileProperty, LIGHT_ARRAY_LENGTH,
+    SECTIONS_PER_COLUMN,
+};
+use super::nbt::{self, Tag};
+use super::protocol_error::ProtocolError;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use std::cmp::{max, min};
-use std::io::{Error, Read, Write};
+use std::collections::BTreeMap;
+use std::io::{Cursor, Error, ErrorKind, Read, Write};
 
-const PALETTE_SIZE: i64 = 14; // We don't define our own palette, so we just use the default all blocks palette which is 14 bits
+// Sections with few enough block states index into a palette of their own, which takes at least 4
+// bits per block and at most 8. Any more than that and blocks are written as global state ids
+const MIN_PALETTE_BITS: u8 = 4;
+const MAX_PALETTE_BITS: u8 = 8;
+const GLOBAL_PALETTE_BITS: u8 = 14;
+const BLOCKS_PER_SECTION: usize = 4096;
+
+// Vanilla never sends frames with a length that doesn't fit in a three byte VarInt
+pub const MAX_FRAME_LENGTH: usize = 2_097_151;
+
+// Checks the length a frame is prefixed with before anything is allocated for it, for whichever
+// side of a connection is reading
+pub fn frame_length(length: i32) -> Result<usize, ProtocolError> {
+    if length < 0 {
+        return Err(ProtocolError::InvalidLength(length));
+    }
+    if length as usize > MAX_FRAME_LENGTH {
+        return Err(ProtocolError::PacketTooLarge(length as usize));
+    }
+    Ok(length as usize)
+}
+
+// A section's blocks as they go on the wire, shared by every version's section layout
+pub struct PalettedBlocks {
+    pub bits_per_block: u8,
+    // None when the blocks are global state ids
+    pub palette: Option<Vec<i32>>,
+    pub data: Vec<u64>,
+}
+
+impl PalettedBlocks {
+    pub fn new(block_ids: &[i32], global_bits_per_block: u8) -> PalettedBlocks {
+        // Sections hold few enough states that searching the palette beats hashing
+        let mut palette: Vec<i32> = Vec::new();
+        let mut indexed = Vec::with_capacity(block_ids.len());
+        let mut last = 0;
+        for block_id in block_ids {
+            // Runs of the same block are the norm
+            let found = match palette.get(last) {
+                Some(state) if state == block_id => Some(last),
+                _ => palette.iter().position(|state| state == block_id),
+            };
+            let index = match found {
+                Some(index) => index,
+                None if palette.len() < 1 << MAX_PALETTE_BITS => {
+                    palette.push(*block_id);
+                    palette.len() - 1
+                }
+                None => {
+                    return PalettedBlocks {
+                        bits_per_block: global_bits_per_block,
+                        palette: None,
+                        data: pack_blocks(block_ids, global_bits_per_block),
+                    }
+                }
+            };
+            last = index;
+            indexed.push(index as i32);
+        }
+        let bits_per_block = bits_to_index(palette.len()).max(MIN_PALETTE_BITS);
+        PalettedBlocks {
+            bits_per_block,
+            palette: Some(palette),
+            data: pack_blocks(&indexed, bits_per_block),
+        }
+    }
+}
+
+fn bits_to_index(entries: usize) -> u8 {
+    (usize::BITS - entries.saturating_sub(1).leading_zeros()) as u8
+}
+
+// Values are packed end to end into longs, starting from the low bits and spilling over from one
+// long into the next
+pub fn pack_blocks(values: &[i32], bits_per_block: u8) -> Vec<u64> {
+    let bits = usize::from(bits_per_block);
+    let mask = (1u64 << bits) - 1;
+    let mut longs = vec![0u64; (values.len() * bits).div_ceil(64)];
+    for (index, value) in values.iter().enumerate() {
+        let value = *value as u64 & mask;
+        let (long, offset) = (index * bits / 64, index * bits % 64);
+        longs[long] |= value << offset;
+        if offset + bits > 64 {
+            longs[long + 1] |= value >> (64 - offset);
+        }
+    }
+    longs
+}
+
+pub fn unpack_blocks(longs: &[u64], bits_per_block: u8, count: usize) -> Vec<i32> {
+    let bits = usize::from(bits_per_block);
+    let mask = (1u64 << bits) - 1;
+    (0..count)
+        .map(|index| {
+            let (long, offset) = (index * bits / 64, index * bits % 64);
+            let mut value = longs[long] >> offset;
+            if offset + bits > 64 {
+                value |= longs[long + 1] << (64 - offset);
+            }
+            (value & mask) as i32
+        })
+        .collect()
+}
 
 pub trait MinecraftProtocolReader {
-    fn read_unsigned_short(&mut self) -> u16;
-    fn read_unsigned_long(&mut self) -> u64;
-    fn read_short(&mut self) -> i16;
-    fn read_var_int(&mut self) -> i32;
-    fn try_read_var_int(&mut self) -> Result<i32, Error>;
-    fn read_long(&mut self) -> i64;
-    fn read_string(&mut self) -> String;
-    fn read_u_128(&mut self) -> u128;
-    fn read_int(&mut self) -> i32;
-    fn read_int_array(&mut self, length: u32) -> Vec<i32>;
-    fn read_var_int_array(&mut self, length: u32) -> Vec<i32>;
-    fn read_chunk_section(&mut self) -> ChunkSection;
-    fn read_float(&mut self) -> f32;
-    fn read_double(&mut self) -> f64;
-    fn read_byte(&mut self) -> i8;
-    fn read_u_byte(&mut self) -> u8;
-    fn read_boolean(&mut self) -> bool;
-    fn read_position(&mut self) -> BlockPosition;
+    fn read_unsigned_short(&mut self) -> Result<u16, ProtocolError>;
+    fn read_unsigned_long(&mut self) -> Result<u64, ProtocolError>;
+    fn read_short(&mut self) -> Result<i16, ProtocolError>;
+    fn read_var_int(&mut self) -> Result<i32, ProtocolError>;
+    fn read_long(&mut self) -> Result<i64, ProtocolError>;
+    fn read_string(&mut self) -> Result<String, ProtocolError>;
+    fn read_byte_array(&mut self) -> Result<Vec<u8>, ProtocolError>;
+    fn read_u_128(&mut self) -> Result<u128, ProtocolError>;
+    fn read_int(&mut self) -> Result<i32, ProtocolError>;
+    fn read_int_array(&mut self, length: u32) -> Result<Vec<i32>, ProtocolError>;
+    fn read_var_int_array(&mut self, length: u32) -> Result<Vec<i32>, ProtocolError>;
+    fn read_chunk_section(&mut self) -> Result<ChunkSection, ProtocolError>;
+    fn read_chunk_column(&mut self) -> Result<ChunkColumn, ProtocolError>;
+    fn read_block_entity(&mut self) -> Result<BlockEntity, ProtocolError>;
+    fn read_nbt(&mut self) -> Result<Option<Tag>, ProtocolError>;
+    fn read_float(&mut self) -> Result<f32, ProtocolError>;
+    fn read_double(&mut self) -> Result<f64, ProtocolError>;
+    fn read_byte(&mut self) -> Result<i8, ProtocolError>;
+    fn read_u_byte(&mut self) -> Result<u8, ProtocolError>;
+    fn read_boolean(&mut self) -> Result<bool, ProtocolError>;
+    fn read_position(&mut self) -> Result<BlockPosition, ProtocolError>;
+    fn read_properties(&mut self) -> Result<Vec<ProfileProperty>, ProtocolError>;
 }
 
 pub trait MinecraftProtocolWriter {
@@ -35,110 +146,146 @@ pub trait MinecraftProtocolWriter {
     fn write_short(&mut self, v: i16);
     fn write_var_int(&mut self, v: i32);
     fn write_string(&mut self, v: String);
+    fn write_byte_array(&mut self, v: Vec<u8>);
     fn write_u_128(&mut self, v: u128);
     fn write_int(&mut self, v: i32);
     fn write_int_array(&mut self, v: Vec<i32>);
     fn write_var_int_array(&mut self, v: Vec<i32>);
     fn write_chunk_section(&mut self, v: ChunkSection);
+    fn write_chunk_column(&mut self, v: ChunkColumn);
+    fn write_block_entity(&mut self, v: BlockEntity);
+    fn write_nbt(&mut self, v: Option<Tag>);
     fn write_float(&mut self, v: f32);
     fn write_double(&mut self, v: f64);
     fn write_byte(&mut self, v: i8);
     fn write_u_byte(&mut self, v: u8);
     fn write_boolean(&mut self, v: bool);
     fn write_position(&mut self, v: BlockPosition);
+    fn write_properties(&mut self, v: Vec<ProfileProperty>);
 }
 
 impl<T: Read> MinecraftProtocolReader for T {
-    fn read_long(&mut self) -> i64 {
-        self.read_i64::<BigEndian>().unwrap()
+    fn read_long(&mut self) -> Result<i64, ProtocolError> {
+        Ok(self.read_i64::<BigEndian>()?)
     }
 
-    fn read_var_int(&mut self) -> i32 {
-        self.try_read_var_int().unwrap()
+    fn read_var_int(&mut self) -> Result<i32, ProtocolError> {
+        read_var_int(self)
     }
 
-    fn try_read_var_int(&mut self) -> Result<i32, Error> {
-        read_var_int(self)
+    fn read_unsigned_short(&mut self) -> Result<u16, ProtocolError> {
+        Ok(self.read_u16::<BigEndian>()?)
     }
 
-    fn read_unsigned_short(&mut self) -> u16 {
-        self.read_u16::<BigEndian>().unwrap()
+    fn read_unsigned_long(&mut self) -> Result<u64, ProtocolError> {
+        Ok(self.read_u64::<BigEndian>()?)
     }
 
-    fn read_unsigned_long(&mut self) -> u64 {
-        self.read_u64::<BigEndian>().unwrap()
+    fn read_short(&mut self) -> Result<i16, ProtocolError> {
+        Ok(self.read_i16::<BigEndian>()?)
     }
 
-    fn read_short(&mut self) -> i16 {
-        self.read_i16::<BigEndian>().unwrap()
+    fn read_string(&mut self) -> Result<String, ProtocolError> {
+        String::from_utf8(self.read_byte_array()?).map_err(|_| ProtocolError::InvalidString)
     }
 
-    fn read_string(&mut self) -> String {
-        let size = self.read_var_int();
+    fn read_byte_array(&mut self) -> Result<Vec<u8>, ProtocolError> {
+        let size = read_length(self)?;
 
-        let mut buffer = vec![0; size as usize];
-        self.read_exact(&mut buffer)
-            .expect("didn't find enough characters");
-        String::from_utf8(buffer).unwrap()
+        // Read through take so a bogus length can't make us allocate more than was sent
+        let mut buffer = Vec::new();
+        self.by_ref().take(size as u64).read_to_end(&mut buffer)?;
+        if buffer.len() != size {
+            return Err(ProtocolError::Io(Error::from(ErrorKind::UnexpectedEof)));
+        }
+        Ok(buffer)
     }
 
-    fn read_u_128(&mut self) -> u128 {
-        self.read_u128::<BigEndian>().unwrap()
+    fn read_u_128(&mut self) -> Result<u128, ProtocolError> {
+        Ok(self.read_u128::<BigEndian>()?)
     }
 
-    fn read_int(&mut self) -> i32 {
-        self.read_i32::<BigEndian>().unwrap()
+    fn read_int(&mut self) -> Result<i32, ProtocolError> {
+        Ok(self.read_i32::<BigEndian>()?)
     }
 
-    fn read_int_array(&mut self, length: u32) -> Vec<i32> {
+    fn read_int_array(&mut self, length: u32) -> Result<Vec<i32>, ProtocolError> {
         let mut v = Vec::<i32>::new();
         for _ in 0..length {
-            v.push(self.read_i32::<BigEndian>().unwrap());
+            v.push(self.read_i32::<BigEndian>()?);
         }
-        v
+        Ok(v)
     }
 
-    fn read_var_int_array(&mut self, length: u32) -> Vec<i32> {
+    fn read_var_int_array(&mut self, length: u32) -> Result<Vec<i32>, ProtocolError> {
         let mut v = Vec::<i32>::new();
         for _ in 0..length {
-            v.push(self.read_var_int());
+            v.push(self.read_var_int()?);
         }
-        v
+        Ok(v)
     }
 
-    fn read_float(&mut self) -> f32 {
-        self.read_f32::<BigEndian>().unwrap()
+    fn read_float(&mut self) -> Result<f32, ProtocolError> {
+        Ok(self.read_f32::<BigEndian>()?)
     }
 
-    fn read_chunk_section(&mut self) -> ChunkSection {
+    fn read_chunk_section(&mut self) -> Result<ChunkSection, ProtocolError> {
         read_chunk_section(self)
     }
 
-    fn read_double(&mut self) -> f64 {
-        self.read_f64::<BigEndian>().unwrap()
+    fn read_chunk_column(&mut self) -> Result<ChunkColumn, ProtocolError> {
+        read_chunk_column(self)
     }
 
-    fn read_byte(&mut self) -> i8 {
-        self.read_i8().unwrap()
+    fn read_block_entity(&mut self) -> Result<BlockEntity, ProtocolError> {
+        read_block_entity(self)
     }
 
-    fn read_u_byte(&mut self) -> u8 {
-        self.read_u8().unwrap()
+    // The root tag's name is always empty on the wire
+    fn read_nbt(&mut self) -> Result<Option<Tag>, ProtocolError> {
+        Ok(nbt::read(self)?.map(|(_, tag)| tag))
     }
 
-    fn read_boolean(&mut self) -> bool {
-        match self.read_u8().unwrap() {
-            1 => true,
-            0 => false,
-            _ => {
-                panic!("Error while unwrapping boolean");
-            }
+    fn read_double(&mut self) -> Result<f64, ProtocolError> {
+        Ok(self.read_f64::<BigEndian>()?)
+    }
+
+    fn read_byte(&mut self) -> Result<i8, ProtocolError> {
+        Ok(self.read_i8()?)
+    }
+
+    fn read_u_byte(&mut self) -> Result<u8, ProtocolError> {
+        Ok(self.read_u8()?)
+    }
+
+    fn read_boolean(&mut self) -> Result<bool, ProtocolError> {
+        match self.read_u8()? {
+            1 => Ok(true),
+            0 => Ok(false),
+            value => Err(ProtocolError::InvalidBoolean(value)),
         }
     }
 
-    fn read_position(&mut self) -> BlockPosition {
+    fn read_position(&mut self) -> Result<BlockPosition, ProtocolError> {
         read_position(self)
     }
+
+    fn read_properties(&mut self) -> Result<Vec<ProfileProperty>, ProtocolError> {
+        let length = read_length(self)?;
+        let mut properties = Vec::new();
+        for _ in 0..length {
+            properties.push(ProfileProperty {
+                name: self.read_string()?,
+                value: self.read_string()?,
+                signature: if self.read_boolean()? {
+                    Some(self.read_string()?)
+                } else {
+                    None
+                },
+            });
+        }
+        Ok(properties)
+    }
 }
 
 impl<T: Write> MinecraftProtocolWriter for T {
@@ -164,10 +311,15 @@ impl<T: Write> MinecraftProtocolWriter for T {
 
     fn write_string(&mut self, v: String) {
         let string_bytes = v.into_bytes();
-        self.write_var_int(string_bytes.clone().into_iter().count() as i32);
+        self.write_var_int(string_bytes.len() as i32);
         self.write_all(&string_bytes).unwrap();
     }
 
+    fn write_byte_array(&mut self, v: Vec<u8>) {
+        self.write_var_int(v.len() as i32);
+        self.write_all(&v).unwrap();
+    }
+
     fn write_u_128(&mut self, v: u128) {
         self.write_u128::<BigEndian>(v).unwrap();
     }
@@ -189,6 +341,21 @@ impl<T: Write> MinecraftProtocolWriter for T {
         write_chunk_section(self, v);
     }
 
+    fn write_chunk_column(&mut self, v: ChunkColumn) {
+        write_chunk_column(self, v);
+    }
+
+    fn write_block_entity(&mut self, v: BlockEntity) {
+        write_block_entity(self, v);
+    }
+
+    fn write_nbt(&mut self, v: Option<Tag>) {
+        match v {
+            Some(tag) => nbt::write(self, "", &tag).unwrap(),
+            None => self.write_u8(nbt::TAG_END).unwrap(),
+        }
+    }
+
     fn write_float(&mut self, v: f32) {
         self.write_f32::<BigEndian>(v).unwrap();
     }
@@ -216,9 +383,21 @@ impl<T: Write> MinecraftProtocolWriter for T {
     fn write_position(&mut self, v: BlockPosition) {
         write_position(self, v);
     }
+
+    fn write_properties(&mut self, v: Vec<ProfileProperty>) {
+        self.write_var_int(v.len() as i32);
+        v.into_iter().for_each(|property| {
+            self.write_string(property.name);
+            self.write_string(property.value);
+            self.write_boolean(property.signature.is_some());
+            if let Some(signature) = property.signature {
+                self.write_string(signature);
+            }
+        });
+    }
 }
 
-fn read_var_int<S: Read>(stream: &mut S) -> Result<i32, Error> {
+fn read_var_int<S: Read>(stream: &mut S) -> Result<i32, ProtocolError> {
     let mut num_read = 0;
     let mut result: i32 = 0;
 
@@ -226,22 +405,28 @@ fn read_var_int<S: Read>(stream: &mut S) -> Result<i32, Error> {
         let value = i32::from(stream.read_u8()?);
         result |= (value & 0b0111_1111) << (7 * num_read);
         num_read += 1;
-        if num_read > 5 {
-            panic!("VarInt is too big");
-        }
         if (value & 0b1000_0000) == 0 {
             break;
         }
+        if num_read == 5 {
+            return Err(ProtocolError::VarIntTooBig);
+        }
     }
 
     Ok(result)
 }
 
-fn write_var_int<S: Write>(stream: &mut S, v: i32) {
-    if v < 0 {
-        panic!("Tried to write negative VarInt {:?}", v);
+fn read_length<S: Read>(stream: &mut S) -> Result<usize, ProtocolError> {
+    let length = read_var_int(stream)?;
+    if length < 0 {
+        return Err(ProtocolError::InvalidLength(length));
     }
-    let mut value = v;
+    Ok(length as usize)
+}
+
+// Negative values are written as their two's complement, which always takes all five bytes
+fn write_var_int<S: Write>(stream: &mut S, v: i32) {
+    let mut value = v as u32;
     loop {
         let mut temp = value & 0b0111_1111;
         value >>= 7;
@@ -255,73 +440,178 @@ fn write_var_int<S: Write>(stream: &mut S, v: i32) {
     }
 }
 
-//We now have a functional though messy implementation of writing any block
 fn write_chunk_section<S: Write>(stream: &mut S, v: ChunkSection) {
-    stream.write_u_byte(v.bits_per_block);
-    stream.write_var_int(v.data_array_length);
-    let mut long: i64 = 0;
-    for i in 0..4096 {
-        let block_to_place = i64::from(v.block_ids[i as usize]);
-        let offset = (PALETTE_SIZE * i) % 64;
-        long += block_to_place << offset;
-        if ((i * PALETTE_SIZE) % 64) >= 64 - PALETTE_SIZE {
-            stream.write_long(long);
-            long = block_to_place >> (64 - offset);
-        }
-    }
-    for _ in 0..2048 {
-        stream
-            .write_u8(!0b0)
-            .expect("could not write max block light"); //write max block light
-    }
-    for _ in 0..2048 {
-        stream
-            .write_u8(!0b0)
-            .expect("could not write max sky light"); //write max sky light
-    }
+    let blocks = PalettedBlocks::new(&v.block_ids, GLOBAL_PALETTE_BITS);
+    stream.write_u_byte(blocks.bits_per_block);
+    if let Some(palette) = blocks.palette {
+        stream.write_var_int(palette.len() as i32);
+        stream.write_var_int_array(palette);
+    }
+    stream.write_var_int(blocks.data.len() as i32);
+    blocks
+        .data
+        .into_iter()
+        .for_each(|long| stream.write_unsigned_long(long));
+    stream
+        .write_all(&v.block_light)
+        .expect("could not write block light");
+    stream
+        .write_all(&v.sky_light)
+        .expect("could not write sky light");
 }
 
-fn read_chunk_section<S: Read>(stream: &mut S) -> ChunkSection {
-    let bits_per_block = stream.read_u_byte();
-    if bits_per_block != PALETTE_SIZE as u8 {
-        panic!("Cannot read palettes");
-    }
-    let data_array_length = stream.read_var_int();
-    if data_array_length != 896 {
-        panic!("Got unexpected data array length");
-    }
-    let mut block_ids = Vec::<i32>::new();
-    let mut long = stream.read_u64::<BigEndian>().unwrap();
-    let mut index = 0;
-    for i in 0..4096 {
-        let bits_to_read = min(64 - (index % 64), 14);
-        let left_shift = max(64 - (bits_to_read + (index % 64)), 0);
-        let right_shift = left_shift + (index % 64);
-        let mut block_id = (long << left_shift) >> right_shift;
-        if left_shift == 0 && i != 4095 {
-            long = stream.read_u64::<BigEndian>().unwrap();
+fn read_chunk_section<S: Read>(stream: &mut S) -> Result<ChunkSection, ProtocolError> {
+    let bits_per_block = stream.read_u_byte()?;
+    let palette = match bits_per_block {
+        MIN_PALETTE_BITS..=MAX_PALETTE_BITS => {
+            let length = read_length(stream)?;
+            if length > 1 << bits_per_block {
+                return Err(ProtocolError::UnsupportedChunkSection(format!(
+                    "{} palette entries at {} bits per block",
+                    length, bits_per_block
+                )));
+            }
+            Some(stream.read_var_int_array(length as u32)?)
         }
-        if bits_to_read < 14 {
-            let remainder_to_read = 14 - bits_to_read;
-            let remainder = long << (64 - remainder_to_read) >> (64 - remainder_to_read);
-            block_id += remainder << bits_to_read;
+        GLOBAL_PALETTE_BITS => None,
+        _ => {
+            return Err(ProtocolError::UnsupportedChunkSection(format!(
+                "cannot read {} bits per block",
+                bits_per_block
+            )))
         }
-        block_ids.push(block_id as i32);
-        index += 14;
-    }
-    //Still ignoring these values for now
-    for _ in 0..2048 {
-        stream.read_u8().unwrap();
-    }
-    for _ in 0..2048 {
-        stream.read_u8().unwrap();
-    }
-    ChunkSection {
-        bits_per_block,
-        data_array_length,
+    };
+    let data_array_length = stream.read_var_int()?;
+    let expected_length = BLOCKS_PER_SECTION * usize::from(bits_per_block) / 64;
+    if data_array_length != expected_length as i32 {
+        return Err(ProtocolError::UnsupportedChunkSection(format!(
+            "unexpected data array length {}",
+            data_array_length
+        )));
+    }
+    let mut data = Vec::with_capacity(expected_length);
+    for _ in 0..expected_length {
+        data.push(stream.read_u64::<BigEndian>()?);
+    }
+    let indices = unpack_blocks(&data, bits_per_block, BLOCKS_PER_SECTION);
+    let block_ids = match palette {
+        Some(palette) => indices
+            .into_iter()
+            .map(|index| {
+                palette.get(index as usize).copied().ok_or_else(|| {
+                    ProtocolError::UnsupportedChunkSection(format!(
+                        "palette index {} out of {} entries",
+                        index,
+                        palette.len()
+                    ))
+                })
+            })
+            .collect::<Result<Vec<i32>, ProtocolError>>()?,
+        None => indices,
+    };
+    let mut block_light = vec![0; LIGHT_ARRAY_LENGTH];
+    stream.read_exact(&mut block_light)?;
+    let mut sky_light = vec![0; LIGHT_ARRAY_LENGTH];
+    stream.read_exact(&mut sky_light)?;
+    Ok(ChunkSection {
         block_ids,
-        block_light: Vec::<u64>::new(),
-        sky_light: Vec::<u64>::new(),
+        block_light,
+        sky_light,
+    })
+}
+
+fn write_chunk_column<S: Write>(stream: &mut S, v: ChunkColumn) {
+    stream.write_boolean(v.biomes.is_some());
+    stream.write_var_int(v.primary_bit_mask());
+    let mut data = Vec::new();
+    for section in v.sections.into_iter().flatten() {
+        data.write_chunk_section(section);
+    }
+    if let Some(biomes) = v.biomes {
+        data.write_int_array(biomes);
+    }
+    stream.write_var_int(data.len() as i32);
+    stream.write_all(&data).unwrap();
+    stream.write_var_int(v.block_entities.len() as i32);
+    v.block_entities
+        .into_iter()
+        .for_each(|block_entity| stream.write_block_entity(block_entity));
+}
+
+fn read_chunk_column<S: Read>(stream: &mut S) -> Result<ChunkColumn, ProtocolError> {
+    let full_chunk = stream.read_boolean()?;
+    let primary_bit_mask = stream.read_var_int()?;
+    if !(0..1 << SECTIONS_PER_COLUMN).contains(&primary_bit_mask) {
+        return Err(ProtocolError::UnsupportedChunkColumn(format!(
+            "primary bit mask {:#x} has sections above the top of the world",
+            primary_bit_mask
+        )));
+    }
+    let size = read_length(stream)?;
+    let mut data = Vec::new();
+    stream.take(size as u64).read_to_end(&mut data)?;
+    if data.len() < size {
+        return Err(ProtocolError::Io(Error::from(ErrorKind::UnexpectedEof)));
+    }
+    let mut data = Cursor::new(data);
+    let mut sections = Vec::with_capacity(SECTIONS_PER_COLUMN);
+    for y in 0..SECTIONS_PER_COLUMN {
+        sections.push(match primary_bit_mask & 1 << y {
+            0 => None,
+            _ => Some(data.read_chunk_section()?),
+        });
+    }
+    let biomes = match full_chunk {
+        true => Some(data.read_int_array(256)?),
+        false => None,
+    };
+    if data.position() as usize != size {
+        return Err(ProtocolError::UnsupportedChunkColumn(format!(
+            "{} bytes left over after the sections",
+            size - data.position() as usize
+        )));
+    }
+    let mut block_entities = Vec::new();
+    for _ in 0..read_length(stream)? {
+        block_entities.push(stream.read_block_entity()?);
+    }
+    Ok(ChunkColumn {
+        sections,
+        biomes,
+        block_entities,
+    })
+}
+
+// Block entities are sent as NBT compounds. Only the tags that identify them are kept
+fn write_block_entity<S: Write>(stream: &mut S, v: BlockEntity) {
+    let mut tags = BTreeMap::new();
+    tags.insert(String::from("id"), Tag::String(v.id));
+    tags.insert(String::from("x"), Tag::Int(v.location.x as i32));
+    tags.insert(String::from("y"), Tag::Int(v.location.y as i32));
+    tags.insert(String::from("z"), Tag::Int(v.location.z as i32));
+    stream.write_nbt(Some(Tag::Compound(tags)));
+}
+
+fn read_block_entity<S: Read>(stream: &mut S) -> Result<BlockEntity, ProtocolError> {
+    let tag = stream.read_nbt()?;
+    let tag = tag.as_ref();
+    let int = |name| match tag.and_then(|tag| tag.get(name)) {
+        Some(Tag::Int(v)) => Some(*v as u32),
+        _ => None,
+    };
+    match (
+        tag.and_then(|tag| tag.get("id")),
+        int("x"),
+        int("y"),
+        int("z"),
+    ) {
+        (Some(Tag::String(id)), Some(x), Some(y), Some(z)) => Ok(BlockEntity {
+            id: id.clone(),
+            location: BlockPosition { x, y, z },
+        }),
+        _ => Err(ProtocolError::InvalidNbt(String::from(
+            "block entities need an id and a position",
+        ))),
     }
 }
 
@@ -338,16 +628,16 @@ fn write_position<S: Write>(stream: &mut S, v: BlockPosition) {
     stream.write_unsigned_long(encoded_position);
 }
 
-fn read_position<S: Read>(stream: &mut S) -> BlockPosition {
-    let encoded_position = stream.read_unsigned_long();
+fn read_position<S: Read>(stream: &mut S) -> Result<BlockPosition, ProtocolError> {
+    let encoded_position = stream.read_unsigned_long()?;
     let x = encoded_position >> 38;
     let y = (encoded_position & 0x0000_003F_FC00_0000) >> 26;
     let z = encoded_position & 0x0000_0000_03FF_FFFF;
-    BlockPosition {
+    Ok(BlockPosition {
         x: (x as u32),
         y: (y as u32),
         z: (z as u32),
-    }
+    })
 }
 
 // Tests
@@ -372,11 +662,9 @@ mod tests {
         }
 
         let chunk_section = ChunkSection {
-            bits_per_block: 14,
-            data_array_length: 896,
             block_ids,
-            block_light: Vec::new(),
-            sky_light: Vec::new(),
+            block_light: (0..2048).map(|i| (i % 256) as u8).collect(),
+            sky_light: (0..2048).map(|i| (255 - i % 256) as u8).collect(),
         };
 
         let mut stream = Vec::<u8>::new();
@@ -385,7 +673,133 @@ mod tests {
         // TODO compare this to an expected chunk section (too big to copy paste here)
 
         let mut stream = std::io::Cursor::new(stream);
-        assert_eq!(chunk_section, stream.read_chunk_section());
+        assert_eq!(chunk_section, stream.read_chunk_section().unwrap());
+    }
+
+    fn section_with_states(states: i32) -> ChunkSection {
+        ChunkSection::new((0..4096).map(|i| (i % states) * 7).collect())
+    }
+
+    #[test]
+    fn sections_pick_the_smallest_palette() {
+        for (states, bits_per_block, data_array_length) in [
+            (1, 4, 256),
+            (16, 4, 256),
+            (17, 5, 320),
+            (256, 8, 512),
+            (257, 14, 896),
+        ]
+        .iter()
+        {
+            let section = section_with_states(*states);
+            let mut stream = Vec::<u8>::new();
+            stream.write_chunk_section(section.clone());
+
+            let mut stream = std::io::Cursor::new(stream);
+            assert_eq!(*bits_per_block, stream.read_u_byte().unwrap());
+            if *bits_per_block <= MAX_PALETTE_BITS {
+                let palette = stream.read_var_int().unwrap();
+                assert_eq!(*states, palette);
+                stream.read_var_int_array(palette as u32).unwrap();
+            }
+            assert_eq!(*data_array_length, stream.read_var_int().unwrap());
+
+            stream.set_position(0);
+            assert_eq!(section, stream.read_chunk_section().unwrap());
+        }
+    }
+
+    #[test]
+    fn sections_indexing_past_their_palette_are_refused() {
+        let mut stream = Vec::<u8>::new();
+        stream.write_chunk_section(section_with_states(2));
+        // Shrink the palette to one entry, leaving the data to index a second
+        stream[1] = 1;
+        stream.remove(3);
+        let mut stream = std::io::Cursor::new(stream);
+        assert!(matches!(
+            stream.read_chunk_section(),
+            Err(ProtocolError::UnsupportedChunkSection(_))
+        ));
+    }
+
+    #[test]
+    fn columns_only_carry_the_sections_they_have() {
+        let mut sections = vec![None; 16];
+        sections[2] = Some(section_with_states(3));
+        sections[9] = Some(section_with_states(300));
+        // All air, so left out
+        sections[5] = Some(ChunkSection::new(vec![0; 4096]));
+        let mut column = ChunkColumn::new(sections);
+        column.block_entities.push(BlockEntity {
+            id: String::from("minecraft:chest"),
+            location: BlockPosition { x: 3, y: 40, z: 7 },
+        });
+        assert_eq!(1 << 2 | 1 << 9, column.primary_bit_mask());
+
+        let mut stream = Vec::<u8>::new();
+        stream.write_chunk_column(column.clone());
+        let mut stream = std::io::Cursor::new(stream);
+        assert_eq!(column, stream.read_chunk_column().unwrap());
+
+        // Bits past the top of the world
+        let mut stream = Vec::<u8>::new();
+        stream.write_boolean(true);
+        stream.write_var_int(1 << 16);
+        let mut stream = std::io::Cursor::new(stream);
+        assert!(matches!(
+            stream.read_chunk_column(),
+            Err(ProtocolError::UnsupportedChunkColumn(_))
+        ));
+    }
+
+    #[test]
+    fn block_entities_skip_tags_they_dont_keep() {
+        use super::super::nbt::{TAG_COMPOUND, TAG_END, TAG_INT, TAG_STRING};
+
+        let mut stream = vec![TAG_COMPOUND, 0, 0];
+        // A list of compounds holding a byte array, as chests keep their items
+        stream.extend(&[
+            9,
+            0,
+            5,
+            b'I',
+            b't',
+            b'e',
+            b'm',
+            b's',
+            TAG_COMPOUND,
+            0,
+            0,
+            0,
+            1,
+        ]);
+        stream.extend(&[7, 0, 1, b'b', 0, 0, 0, 2, 0xAA, 0xBB, TAG_END]);
+        stream.extend(&[TAG_STRING, 0, 2, b'i', b'd', 0, 4, b's', b'i', b'g', b'n']);
+        for (name, value) in [(b'x', 1i32), (b'y', -2), (b'z', 3)].iter() {
+            stream.extend(&[TAG_INT, 0, 1, *name]);
+            stream.extend(&value.to_be_bytes());
+        }
+        // An empty list, which has no element type
+        stream.extend(&[9, 0, 1, b'e', TAG_END, 0, 0, 0, 0, TAG_END]);
+        let mut stream = std::io::Cursor::new(stream);
+        assert_eq!(
+            BlockEntity {
+                id: String::from("sign"),
+                location: BlockPosition {
+                    x: 1,
+                    y: -2i32 as u32,
+                    z: 3
+                },
+            },
+            stream.read_block_entity().unwrap()
+        );
+
+        let mut stream = std::io::Cursor::new(vec![TAG_COMPOUND, 0, 0, 13, 0, 0]);
+        assert!(matches!(
+            stream.read_block_entity(),
+            Err(ProtocolError::InvalidNbt(_))
+        ));
     }
 
     #[test]
@@ -407,16 +821,21 @@ mod tests {
 
         //int max
         stream.clear();
-        stream.write_var_int(std::i32::MAX);
+        stream.write_var_int(i32::MAX);
         assert_eq!(vec![255, 255, 255, 255, 7], stream);
     }
 
     #[test]
-    #[should_panic]
-    fn test_write_var_int_negative() {
-        //negative
+    fn test_var_int_negative() {
         let mut stream = Vec::<u8>::new();
-        stream.write_var_int(std::i32::MIN);
+        stream.write_var_int(-1);
+        assert_eq!(vec![255, 255, 255, 255, 15], stream);
+
+        stream.clear();
+        stream.write_var_int(i32::MIN);
+        assert_eq!(vec![128, 128, 128, 128, 8], stream);
+        let mut stream = std::io::Cursor::new(stream);
+        assert_eq!(i32::MIN, read_var_int(&mut stream).unwrap());
     }
 
     #[test]
@@ -435,15 +854,17 @@ mod tests {
 
         //int max
         let mut stream = std::io::Cursor::new(vec![255, 255, 255, 255, 7]);
-        assert_eq!(std::i32::MAX, read_var_int(&mut stream).unwrap());
+        assert_eq!(i32::MAX, read_var_int(&mut stream).unwrap());
     }
 
     #[test]
-    #[should_panic]
     fn test_read_var_int_too_big() {
         //int max
         let mut stream = std::io::Cursor::new(vec![255, 255, 255, 255, 255]);
-        read_var_int(&mut stream).unwrap();
+        assert!(matches!(
+            read_var_int(&mut stream),
+            Err(ProtocolError::VarIntTooBig)
+        ));
     }
 
     #[test]
@@ -451,8 +872,51 @@ mod tests {
         let block_position = BlockPosition { x: 1, y: 1, z: 1 };
 
         let mut stream = Vec::<u8>::new();
-        stream.write_position(block_position.clone());
+        stream.write_position(block_position);
+        let mut stream = std::io::Cursor::new(stream);
+        assert_eq!(block_position, read_position(&mut stream).unwrap());
+    }
+
+    #[test]
+    fn test_read_properties() {
+        let properties = vec![
+            ProfileProperty {
+                name: String::from("textures"),
+                value: String::from("e30="),
+                signature: Some(String::from("c2lnbmVk")),
+            },
+            ProfileProperty {
+                name: String::from("unsigned"),
+                value: String::from("e30="),
+                signature: None,
+            },
+        ];
+
+        let mut stream = Vec::<u8>::new();
+        stream.write_properties(properties.clone());
         let mut stream = std::io::Cursor::new(stream);
-        assert_eq!(block_position, read_position(&mut stream));
+        assert_eq!(properties, stream.read_properties().unwrap());
+    }
+
+    #[test]
+    fn test_read_errors() {
+        let mut stream = std::io::Cursor::new(vec![2]);
+        assert!(matches!(
+            stream.read_boolean(),
+            Err(ProtocolError::InvalidBoolean(2))
+        ));
+
+        //a string claiming to be far longer than what was sent
+        let mut stream = Vec::<u8>::new();
+        stream.write_var_int(i32::MAX);
+        stream.extend(b"short");
+        let mut stream = std::io::Cursor::new(stream);
+        assert!(matches!(stream.read_string(), Err(ProtocolError::Io(_))));
+
+        let mut stream = std::io::Cursor::new(vec![14, 1]);
+        assert!(matches!(
+            stream.read_chunk_section(),
+            Err(ProtocolError::UnsupportedChunkSection(_))
+        ));
     }
 }
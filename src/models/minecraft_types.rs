@@ -4,13 +4,82 @@ pub fn float_to_angle(f: f32) -> u8 {
     ((f / 360.0) * 256.0) as u8
 }
 
+pub const LIGHT_ARRAY_LENGTH: usize = 2048;
+
+// How many bits each block takes and whether it uses a palette are worked out from the block ids
+// when the section is written
 #[derive(Debug, Clone, PartialEq)]
 pub struct ChunkSection {
-    pub bits_per_block: u8, //always 14 until we implement palettes
-    pub data_array_length: i32,
-    pub block_ids: Vec<i32>,   //4096 block ids
-    pub block_light: Vec<u64>, //2048 bytes (all 1s)
-    pub sky_light: Vec<u64>,   //2048 bytes (all 1s)
+    pub block_ids: Vec<i32>,  //4096 block ids
+    pub block_light: Vec<u8>, //4096 light levels, two to a byte
+    pub sky_light: Vec<u8>,   //4096 light levels, two to a byte
+}
+
+impl ChunkSection {
+    // Lit as brightly as can be, for sections whose light hasn't been worked out
+    pub fn new(block_ids: Vec<i32>) -> ChunkSection {
+        ChunkSection {
+            block_ids,
+            block_light: vec![0xFF; LIGHT_ARRAY_LENGTH],
+            sky_light: vec![0xFF; LIGHT_ARRAY_LENGTH],
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.block_ids.iter().all(|block_id| *block_id == 0)
+    }
+}
+
+pub const SECTIONS_PER_COLUMN: usize = 16;
+pub const VOID_BIOME: i32 = 127;
+
+// Everything in a chunk from the bottom of the world to the top. Sections are indexed by height,
+// with the ones that are all air left out, which is what the primary bit mask tells clients.
+// Columns without biomes only update the sections they have
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkColumn {
+    pub sections: Vec<Option<ChunkSection>>,
+    pub biomes: Option<Vec<i32>>,
+    pub block_entities: Vec<BlockEntity>,
+}
+
+impl ChunkColumn {
+    // A full column of the void biome, leaving out sections that are all air
+    pub fn new(mut sections: Vec<Option<ChunkSection>>) -> ChunkColumn {
+        sections.resize(SECTIONS_PER_COLUMN, None);
+        ChunkColumn {
+            sections: sections
+                .into_iter()
+                .map(|section| section.filter(|section| !section.is_empty()))
+                .collect(),
+            biomes: Some(vec![VOID_BIOME; 256]),
+            block_entities: Vec::new(),
+        }
+    }
+
+    pub fn primary_bit_mask(&self) -> i32 {
+        self.sections
+            .iter()
+            .enumerate()
+            .filter(|(_, section)| section.is_some())
+            .fold(0, |mask, (y, _)| mask | 1 << y)
+    }
+}
+
+// Blocks like signs and chests that hold more than a block state, sent along with their chunk.
+// Only what identifies them is kept for now
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockEntity {
+    pub id: String,
+    pub location: BlockPosition,
+}
+
+// Signed profile data such as skins, as handed out by the session server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProfileProperty {
+    pub name: String,
+    pub value: String,
+    pub signature: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -37,6 +106,25 @@ pub struct Description {
     pub text: String,
 }
 
+// The JSON text format used for anything shown to the player, such as disconnect reasons. We only
+// send plain text for now
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatComponent {
+    pub text: String,
+}
+
+impl ChatComponent {
+    pub fn text(text: &str) -> ChatComponent {
+        ChatComponent {
+            text: String::from(text),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct StatusResponse {
     pub version: Version,
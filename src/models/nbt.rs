@@ -0,0 +1,1217 @@
+// Named Binary Tag, the format Minecraft keeps structured data in: block entities and items on the
+// wire, and level and region files on disk. A field or file holds a single named root tag, nearly
+// always a compound, and everything in it is big-endian. Strings are taken to be plain UTF-8, which
+// Java's modified UTF-8 only differs from for nulls and characters outside the BMP
+
+use super::protocol_error::ProtocolError;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+use serde::de::value::{MapAccessDeserializer, MapDeserializer, SeqDeserializer};
+use serde::de::{self, DeserializeOwned, IntoDeserializer};
+use serde::ser::{self, Serialize};
+use serde::{forward_to_deserialize_any, Deserialize};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+pub const TAG_END: u8 = 0;
+pub const TAG_BYTE: u8 = 1;
+pub const TAG_SHORT: u8 = 2;
+pub const TAG_INT: u8 = 3;
+pub const TAG_LONG: u8 = 4;
+pub const TAG_FLOAT: u8 = 5;
+pub const TAG_DOUBLE: u8 = 6;
+pub const TAG_BYTE_ARRAY: u8 = 7;
+pub const TAG_STRING: u8 = 8;
+pub const TAG_LIST: u8 = 9;
+pub const TAG_COMPOUND: u8 = 10;
+pub const TAG_INT_ARRAY: u8 = 11;
+pub const TAG_LONG_ARRAY: u8 = 12;
+
+// The same limit vanilla puts on nesting, so that reading deep NBT can't blow the stack
+const MAX_DEPTH: usize = 512;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Tag {
+    Byte(i8),
+    Short(i16),
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    ByteArray(Vec<i8>),
+    String(String),
+    // Every element of a list has the same type
+    List(Vec<Tag>),
+    Compound(BTreeMap<String, Tag>),
+    IntArray(Vec<i32>),
+    LongArray(Vec<i64>),
+}
+
+impl Tag {
+    pub fn id(&self) -> u8 {
+        match self {
+            Tag::Byte(_) => TAG_BYTE,
+            Tag::Short(_) => TAG_SHORT,
+            Tag::Int(_) => TAG_INT,
+            Tag::Long(_) => TAG_LONG,
+            Tag::Float(_) => TAG_FLOAT,
+            Tag::Double(_) => TAG_DOUBLE,
+            Tag::ByteArray(_) => TAG_BYTE_ARRAY,
+            Tag::String(_) => TAG_STRING,
+            Tag::List(_) => TAG_LIST,
+            Tag::Compound(_) => TAG_COMPOUND,
+            Tag::IntArray(_) => TAG_INT_ARRAY,
+            Tag::LongArray(_) => TAG_LONG_ARRAY,
+        }
+    }
+
+    // Looks a tag up by name, if this is a compound
+    pub fn get(&self, name: &str) -> Option<&Tag> {
+        match self {
+            Tag::Compound(tags) => tags.get(name),
+            _ => None,
+        }
+    }
+}
+
+// Binary
+
+// Reads a named root tag. The protocol sends a lone end tag where there's nothing to send
+pub fn read<R: Read>(stream: &mut R) -> Result<Option<(String, Tag)>, ProtocolError> {
+    let id = stream.read_u8()?;
+    if id == TAG_END {
+        return Ok(None);
+    }
+    let name = read_string(stream)?;
+    Ok(Some((name, read_payload(stream, id, 0)?)))
+}
+
+pub fn write<W: Write>(stream: &mut W, name: &str, tag: &Tag) -> io::Result<()> {
+    stream.write_u8(tag.id())?;
+    write_string(stream, name)?;
+    write_payload(stream, tag)
+}
+
+// Level files are gzipped
+pub fn read_gzip<R: Read>(stream: R) -> Result<(String, Tag), ProtocolError> {
+    read_root(&mut GzDecoder::new(stream))
+}
+
+pub fn write_gzip<W: Write>(stream: W, name: &str, tag: &Tag) -> io::Result<()> {
+    let mut encoder = GzEncoder::new(stream, Compression::default());
+    write(&mut encoder, name, tag)?;
+    encoder.finish().map(|_| ())
+}
+
+// Chunks in region files are usually zlib compressed
+pub fn read_zlib<R: Read>(stream: R) -> Result<(String, Tag), ProtocolError> {
+    read_root(&mut ZlibDecoder::new(stream))
+}
+
+pub fn write_zlib<W: Write>(stream: W, name: &str, tag: &Tag) -> io::Result<()> {
+    let mut encoder = ZlibEncoder::new(stream, Compression::default());
+    write(&mut encoder, name, tag)?;
+    encoder.finish().map(|_| ())
+}
+
+fn read_root<R: Read>(stream: &mut R) -> Result<(String, Tag), ProtocolError> {
+    read(stream)?.ok_or_else(|| ProtocolError::InvalidNbt(String::from("no root tag")))
+}
+
+fn read_payload<R: Read>(stream: &mut R, id: u8, depth: usize) -> Result<Tag, ProtocolError> {
+    if depth > MAX_DEPTH {
+        return Err(ProtocolError::InvalidNbt(String::from("nested too deep")));
+    }
+    Ok(match id {
+        TAG_BYTE => Tag::Byte(stream.read_i8()?),
+        TAG_SHORT => Tag::Short(stream.read_i16::<BigEndian>()?),
+        TAG_INT => Tag::Int(stream.read_i32::<BigEndian>()?),
+        TAG_LONG => Tag::Long(stream.read_i64::<BigEndian>()?),
+        TAG_FLOAT => Tag::Float(stream.read_f32::<BigEndian>()?),
+        TAG_DOUBLE => Tag::Double(stream.read_f64::<BigEndian>()?),
+        TAG_BYTE_ARRAY => Tag::ByteArray(read_array(stream, |stream| stream.read_i8())?),
+        TAG_STRING => Tag::String(read_string(stream)?),
+        TAG_LIST => {
+            let element = stream.read_u8()?;
+            let length = read_length(stream)?;
+            // Empty lists are written as lists of end tags
+            if element == TAG_END {
+                return Ok(Tag::List(Vec::new()));
+            }
+            let mut tags = Vec::new();
+            for _ in 0..length {
+                tags.push(read_payload(stream, element, depth + 1)?);
+            }
+            Tag::List(tags)
+        }
+        TAG_COMPOUND => {
+            let mut tags = BTreeMap::new();
+            loop {
+                let id = stream.read_u8()?;
+                if id == TAG_END {
+                    break;
+                }
+                let name = read_string(stream)?;
+                tags.insert(name, read_payload(stream, id, depth + 1)?);
+            }
+            Tag::Compound(tags)
+        }
+        TAG_INT_ARRAY => {
+            Tag::IntArray(read_array(stream, |stream| stream.read_i32::<BigEndian>())?)
+        }
+        TAG_LONG_ARRAY => {
+            Tag::LongArray(read_array(stream, |stream| stream.read_i64::<BigEndian>())?)
+        }
+        _ => return Err(ProtocolError::InvalidNbt(format!("unknown tag {}", id))),
+    })
+}
+
+fn write_payload<W: Write>(stream: &mut W, tag: &Tag) -> io::Result<()> {
+    match tag {
+        Tag::Byte(v) => stream.write_i8(*v),
+        Tag::Short(v) => stream.write_i16::<BigEndian>(*v),
+        Tag::Int(v) => stream.write_i32::<BigEndian>(*v),
+        Tag::Long(v) => stream.write_i64::<BigEndian>(*v),
+        Tag::Float(v) => stream.write_f32::<BigEndian>(*v),
+        Tag::Double(v) => stream.write_f64::<BigEndian>(*v),
+        Tag::ByteArray(v) => write_array(stream, v, |stream, v| stream.write_i8(*v)),
+        Tag::String(v) => write_string(stream, v),
+        Tag::List(tags) => {
+            let element = tags.first().map_or(TAG_END, Tag::id);
+            if tags.iter().any(|tag| tag.id() != element) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "lists can only hold tags of one type",
+                ));
+            }
+            stream.write_u8(element)?;
+            stream.write_i32::<BigEndian>(tags.len() as i32)?;
+            tags.iter().try_for_each(|tag| write_payload(stream, tag))
+        }
+        Tag::Compound(tags) => {
+            for (name, tag) in tags.iter() {
+                stream.write_u8(tag.id())?;
+                write_string(stream, name)?;
+                write_payload(stream, tag)?;
+            }
+            stream.write_u8(TAG_END)
+        }
+        Tag::IntArray(v) => write_array(stream, v, |stream, v| stream.write_i32::<BigEndian>(*v)),
+        Tag::LongArray(v) => write_array(stream, v, |stream, v| stream.write_i64::<BigEndian>(*v)),
+    }
+}
+
+fn read_length<R: Read>(stream: &mut R) -> Result<i32, ProtocolError> {
+    let length = stream.read_i32::<BigEndian>()?;
+    if length < 0 {
+        return Err(ProtocolError::InvalidLength(length));
+    }
+    Ok(length)
+}
+
+// Elements are read one at a time so that a bogus length can't make us allocate more than was sent
+fn read_array<R: Read, T, F: Fn(&mut R) -> io::Result<T>>(
+    stream: &mut R,
+    read_element: F,
+) -> Result<Vec<T>, ProtocolError> {
+    let length = read_length(stream)?;
+    let mut elements = Vec::new();
+    for _ in 0..length {
+        elements.push(read_element(stream)?);
+    }
+    Ok(elements)
+}
+
+fn write_array<W: Write, T, F: Fn(&mut W, &T) -> io::Result<()>>(
+    stream: &mut W,
+    elements: &[T],
+    write_element: F,
+) -> io::Result<()> {
+    stream.write_i32::<BigEndian>(elements.len() as i32)?;
+    elements
+        .iter()
+        .try_for_each(|element| write_element(stream, element))
+}
+
+fn read_string<R: Read>(stream: &mut R) -> Result<String, ProtocolError> {
+    let length = stream.read_u16::<BigEndian>()?;
+    let mut bytes = vec![0; usize::from(length)];
+    stream.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|_| ProtocolError::InvalidString)
+}
+
+fn write_string<W: Write>(stream: &mut W, v: &str) -> io::Result<()> {
+    if v.len() > usize::from(u16::MAX) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "NBT strings are at most 65535 bytes",
+        ));
+    }
+    stream.write_u16::<BigEndian>(v.len() as u16)?;
+    stream.write_all(v.as_bytes())
+}
+
+// SNBT, the text form commands take. `{}` prints it on one line and `{:#}` spreads compounds and
+// lists of them over several, for reading through while debugging
+
+impl fmt::Display for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let indent = if f.alternate() { Some(0) } else { None };
+        write_snbt(f, self, indent)
+    }
+}
+
+fn write_snbt(f: &mut fmt::Formatter, tag: &Tag, indent: Option<usize>) -> fmt::Result {
+    match tag {
+        Tag::Byte(v) => write!(f, "{}b", v),
+        Tag::Short(v) => write!(f, "{}s", v),
+        Tag::Int(v) => write!(f, "{}", v),
+        Tag::Long(v) => write!(f, "{}L", v),
+        Tag::Float(v) => write!(f, "{}f", v),
+        Tag::Double(v) => write!(f, "{}d", v),
+        Tag::ByteArray(v) => write_snbt_array(f, "B", v.iter().map(|v| format!("{}b", v))),
+        Tag::String(v) => write_snbt_string(f, v),
+        Tag::List(tags) => {
+            let nested = tags
+                .iter()
+                .any(|tag| matches!(tag, Tag::List(_) | Tag::Compound(_)));
+            write_snbt_entries(
+                f,
+                ('[', ']'),
+                tags.iter().map(|tag| (None, tag)),
+                indent.filter(|_| nested),
+            )
+        }
+        Tag::Compound(tags) => write_snbt_entries(
+            f,
+            ('{', '}'),
+            tags.iter().map(|(name, tag)| (Some(name.as_str()), tag)),
+            indent,
+        ),
+        Tag::IntArray(v) => write_snbt_array(f, "I", v.iter().map(|v| v.to_string())),
+        Tag::LongArray(v) => write_snbt_array(f, "L", v.iter().map(|v| format!("{}L", v))),
+    }
+}
+
+fn write_snbt_entries<'a, I: ExactSizeIterator<Item = (Option<&'a str>, &'a Tag)>>(
+    f: &mut fmt::Formatter,
+    (open, close): (char, char),
+    entries: I,
+    indent: Option<usize>,
+) -> fmt::Result {
+    write!(f, "{}", open)?;
+    let length = entries.len();
+    for (index, (name, tag)) in entries.enumerate() {
+        if let Some(indent) = indent {
+            write!(f, "\n{:width$}", "", width = (indent + 1) * 4)?;
+        }
+        if let Some(name) = name {
+            write_snbt_name(f, name)?;
+            write!(f, ": ")?;
+        }
+        write_snbt(f, tag, indent.map(|indent| indent + 1))?;
+        if index + 1 < length {
+            write!(f, ",")?;
+            if indent.is_none() {
+                write!(f, " ")?;
+            }
+        }
+    }
+    match indent {
+        Some(indent) if length > 0 => write!(f, "\n{:width$}{}", "", close, width = indent * 4),
+        _ => write!(f, "{}", close),
+    }
+}
+
+fn write_snbt_array<I: Iterator<Item = String>>(
+    f: &mut fmt::Formatter,
+    prefix: &str,
+    elements: I,
+) -> fmt::Result {
+    write!(f, "[{};", prefix)?;
+    for (index, element) in elements.enumerate() {
+        write!(f, "{}{}", if index == 0 { " " } else { ", " }, element)?;
+    }
+    write!(f, "]")
+}
+
+// Names are left bare where SNBT allows it
+fn write_snbt_name(f: &mut fmt::Formatter, name: &str) -> fmt::Result {
+    let bare = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-.+".contains(c));
+    match bare {
+        true => write!(f, "{}", name),
+        false => write_snbt_string(f, name),
+    }
+}
+
+fn write_snbt_string(f: &mut fmt::Formatter, v: &str) -> fmt::Result {
+    write!(f, "\"{}\"", v.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// Serde. Any serializable value can be turned into a tag and back, with structs and maps as
+// compounds and sequences as lists. Integers keep their width, unsigned ones being widened to the
+// next signed type, and None fields are left out. Sequences that should be written as arrays
+// rather than lists can say so with `#[serde(with = "nbt::int_array")]` and the like
+
+impl ser::Error for ProtocolError {
+    fn custom<T: fmt::Display>(msg: T) -> ProtocolError {
+        ProtocolError::InvalidNbt(msg.to_string())
+    }
+}
+
+impl de::Error for ProtocolError {
+    fn custom<T: fmt::Display>(msg: T) -> ProtocolError {
+        ProtocolError::InvalidNbt(msg.to_string())
+    }
+}
+
+pub fn to_tag<T: Serialize + ?Sized>(value: &T) -> Result<Tag, ProtocolError> {
+    value
+        .serialize(TagSerializer)?
+        .ok_or_else(|| ProtocolError::InvalidNbt(String::from("nothing to serialize")))
+}
+
+pub fn from_tag<T: DeserializeOwned>(tag: Tag) -> Result<T, ProtocolError> {
+    T::deserialize(tag)
+}
+
+// Names the array helpers wrap their sequences in, so the serializer knows to write an array
+const BYTE_ARRAY: &str = "__nbt_byte_array";
+const INT_ARRAY: &str = "__nbt_int_array";
+const LONG_ARRAY: &str = "__nbt_long_array";
+
+macro_rules! array_helper {
+    ($module:ident, $name:ident, $type:ty) => {
+        pub mod $module {
+            use serde::{Deserialize, Deserializer, Serializer};
+
+            pub fn serialize<S: Serializer>(v: &[$type], serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_newtype_struct(super::$name, v)
+            }
+
+            pub fn deserialize<'de, D: Deserializer<'de>>(
+                deserializer: D,
+            ) -> Result<Vec<$type>, D::Error> {
+                Vec::deserialize(deserializer)
+            }
+        }
+    };
+}
+
+array_helper!(byte_array, BYTE_ARRAY, i8);
+array_helper!(int_array, INT_ARRAY, i32);
+array_helper!(long_array, LONG_ARRAY, i64);
+
+impl Serialize for Tag {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Tag::Byte(v) => serializer.serialize_i8(*v),
+            Tag::Short(v) => serializer.serialize_i16(*v),
+            Tag::Int(v) => serializer.serialize_i32(*v),
+            Tag::Long(v) => serializer.serialize_i64(*v),
+            Tag::Float(v) => serializer.serialize_f32(*v),
+            Tag::Double(v) => serializer.serialize_f64(*v),
+            Tag::ByteArray(v) => serializer.serialize_newtype_struct(BYTE_ARRAY, v),
+            Tag::String(v) => serializer.serialize_str(v),
+            Tag::List(v) => v.serialize(serializer),
+            Tag::Compound(v) => v.serialize(serializer),
+            Tag::IntArray(v) => serializer.serialize_newtype_struct(INT_ARRAY, v),
+            Tag::LongArray(v) => serializer.serialize_newtype_struct(LONG_ARRAY, v),
+        }
+    }
+}
+
+// Formats that don't describe themselves as well as NBT does give the closest tag. Sequences come
+// back as lists, so arrays only survive going through formats that keep them apart
+impl<'de> Deserialize<'de> for Tag {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Tag, D::Error> {
+        deserializer.deserialize_any(TagVisitor)
+    }
+}
+
+struct TagVisitor;
+
+impl<'de> de::Visitor<'de> for TagVisitor {
+    type Value = Tag;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an NBT tag")
+    }
+
+    fn visit_bool<E>(self, v: bool) -> Result<Tag, E> {
+        Ok(Tag::Byte(v as i8))
+    }
+
+    fn visit_i8<E>(self, v: i8) -> Result<Tag, E> {
+        Ok(Tag::Byte(v))
+    }
+
+    fn visit_i16<E>(self, v: i16) -> Result<Tag, E> {
+        Ok(Tag::Short(v))
+    }
+
+    fn visit_i32<E>(self, v: i32) -> Result<Tag, E> {
+        Ok(Tag::Int(v))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Tag, E> {
+        Ok(Tag::Long(v))
+    }
+
+    fn visit_u8<E>(self, v: u8) -> Result<Tag, E> {
+        Ok(Tag::Short(i16::from(v)))
+    }
+
+    fn visit_u16<E>(self, v: u16) -> Result<Tag, E> {
+        Ok(Tag::Int(i32::from(v)))
+    }
+
+    fn visit_u32<E>(self, v: u32) -> Result<Tag, E> {
+        Ok(Tag::Long(i64::from(v)))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Tag, E> {
+        i64::try_from(v)
+            .map(Tag::Long)
+            .map_err(|_| E::custom(format!("{} is too large for a long", v)))
+    }
+
+    fn visit_f32<E>(self, v: f32) -> Result<Tag, E> {
+        Ok(Tag::Float(v))
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<Tag, E> {
+        Ok(Tag::Double(v))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Tag, E> {
+        Ok(Tag::String(String::from(v)))
+    }
+
+    fn visit_string<E>(self, v: String) -> Result<Tag, E> {
+        Ok(Tag::String(v))
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Tag, E> {
+        Ok(Tag::ByteArray(v.iter().map(|byte| *byte as i8).collect()))
+    }
+
+    fn visit_newtype_struct<D: de::Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<Tag, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Tag, A::Error> {
+        let mut tags = Vec::new();
+        while let Some(tag) = seq.next_element()? {
+            tags.push(tag);
+        }
+        Ok(Tag::List(tags))
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Tag, A::Error> {
+        let mut tags = BTreeMap::new();
+        while let Some((name, tag)) = map.next_entry()? {
+            tags.insert(name, tag);
+        }
+        Ok(Tag::Compound(tags))
+    }
+}
+
+struct TagSerializer;
+
+// None is what values that should be left out serialize to
+impl ser::Serializer for TagSerializer {
+    type Ok = Option<Tag>;
+    type Error = ProtocolError;
+    type SerializeSeq = ListSerializer;
+    type SerializeTuple = ListSerializer;
+    type SerializeTupleStruct = ListSerializer;
+    type SerializeTupleVariant = VariantSerializer<ListSerializer>;
+    type SerializeMap = CompoundSerializer;
+    type SerializeStruct = CompoundSerializer;
+    type SerializeStructVariant = VariantSerializer<CompoundSerializer>;
+
+    fn serialize_bool(self, v: bool) -> Result<Option<Tag>, ProtocolError> {
+        Ok(Some(Tag::Byte(v as i8)))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Option<Tag>, ProtocolError> {
+        Ok(Some(Tag::Byte(v)))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Option<Tag>, ProtocolError> {
+        Ok(Some(Tag::Short(v)))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Option<Tag>, ProtocolError> {
+        Ok(Some(Tag::Int(v)))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Option<Tag>, ProtocolError> {
+        Ok(Some(Tag::Long(v)))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Option<Tag>, ProtocolError> {
+        Ok(Some(Tag::Short(i16::from(v))))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Option<Tag>, ProtocolError> {
+        Ok(Some(Tag::Int(i32::from(v))))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Option<Tag>, ProtocolError> {
+        Ok(Some(Tag::Long(i64::from(v))))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Option<Tag>, ProtocolError> {
+        i64::try_from(v)
+            .map(|v| Some(Tag::Long(v)))
+            .map_err(|_| ProtocolError::InvalidNbt(format!("{} is too large for a long", v)))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Option<Tag>, ProtocolError> {
+        Ok(Some(Tag::Float(v)))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Option<Tag>, ProtocolError> {
+        Ok(Some(Tag::Double(v)))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Option<Tag>, ProtocolError> {
+        Ok(Some(Tag::String(v.to_string())))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Option<Tag>, ProtocolError> {
+        Ok(Some(Tag::String(String::from(v))))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Option<Tag>, ProtocolError> {
+        Ok(Some(Tag::ByteArray(
+            v.iter().map(|byte| *byte as i8).collect(),
+        )))
+    }
+
+    fn serialize_none(self) -> Result<Option<Tag>, ProtocolError> {
+        Ok(None)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(
+        self,
+        value: &T,
+    ) -> Result<Option<Tag>, ProtocolError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Option<Tag>, ProtocolError> {
+        Ok(Some(Tag::Compound(BTreeMap::new())))
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Option<Tag>, ProtocolError> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<Option<Tag>, ProtocolError> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<Option<Tag>, ProtocolError> {
+        let tag = value.serialize(self)?;
+        let elements = match (name, &tag) {
+            (BYTE_ARRAY, Some(Tag::List(elements)))
+            | (INT_ARRAY, Some(Tag::List(elements)))
+            | (LONG_ARRAY, Some(Tag::List(elements))) => elements,
+            _ => return Ok(tag),
+        };
+        let array = match name {
+            BYTE_ARRAY => elements
+                .iter()
+                .map(|tag| match tag {
+                    Tag::Byte(v) => Some(*v),
+                    _ => None,
+                })
+                .collect::<Option<Vec<i8>>>()
+                .map(Tag::ByteArray),
+            INT_ARRAY => elements
+                .iter()
+                .map(|tag| match tag {
+                    Tag::Int(v) => Some(*v),
+                    _ => None,
+                })
+                .collect::<Option<Vec<i32>>>()
+                .map(Tag::IntArray),
+            _ => elements
+                .iter()
+                .map(|tag| match tag {
+                    Tag::Long(v) => Some(*v),
+                    _ => None,
+                })
+                .collect::<Option<Vec<i64>>>()
+                .map(Tag::LongArray),
+        };
+        array.map(Some).ok_or_else(|| {
+            ProtocolError::InvalidNbt(String::from("arrays can only hold numbers of their type"))
+        })
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Option<Tag>, ProtocolError> {
+        let mut tags = BTreeMap::new();
+        if let Some(tag) = value.serialize(self)? {
+            tags.insert(String::from(variant), tag);
+        }
+        Ok(Some(Tag::Compound(tags)))
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<ListSerializer, ProtocolError> {
+        Ok(ListSerializer { tags: Vec::new() })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<ListSerializer, ProtocolError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<ListSerializer, ProtocolError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<VariantSerializer<ListSerializer>, ProtocolError> {
+        Ok(VariantSerializer {
+            variant,
+            inner: self.serialize_seq(Some(len))?,
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<CompoundSerializer, ProtocolError> {
+        Ok(CompoundSerializer {
+            tags: BTreeMap::new(),
+            name: None,
+        })
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<CompoundSerializer, ProtocolError> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<VariantSerializer<CompoundSerializer>, ProtocolError> {
+        Ok(VariantSerializer {
+            variant,
+            inner: self.serialize_map(Some(len))?,
+        })
+    }
+}
+
+struct ListSerializer {
+    tags: Vec<Tag>,
+}
+
+impl ListSerializer {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ProtocolError> {
+        let tag = value.serialize(TagSerializer)?.ok_or_else(|| {
+            ProtocolError::InvalidNbt(String::from("lists cannot hold missing values"))
+        })?;
+        if self.tags.iter().any(|other| other.id() != tag.id()) {
+            return Err(ProtocolError::InvalidNbt(String::from(
+                "lists can only hold tags of one type",
+            )));
+        }
+        self.tags.push(tag);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Option<Tag>, ProtocolError> {
+        Ok(Some(Tag::List(self.tags)))
+    }
+}
+
+impl ser::SerializeSeq for ListSerializer {
+    type Ok = Option<Tag>;
+    type Error = ProtocolError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ProtocolError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Option<Tag>, ProtocolError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for ListSerializer {
+    type Ok = Option<Tag>;
+    type Error = ProtocolError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ProtocolError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Option<Tag>, ProtocolError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for ListSerializer {
+    type Ok = Option<Tag>;
+    type Error = ProtocolError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ProtocolError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Option<Tag>, ProtocolError> {
+        self.finish()
+    }
+}
+
+struct CompoundSerializer {
+    tags: BTreeMap<String, Tag>,
+    name: Option<String>,
+}
+
+impl CompoundSerializer {
+    fn insert<T: Serialize + ?Sized>(
+        &mut self,
+        name: String,
+        value: &T,
+    ) -> Result<(), ProtocolError> {
+        if let Some(tag) = value.serialize(TagSerializer)? {
+            self.tags.insert(name, tag);
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Option<Tag>, ProtocolError> {
+        Ok(Some(Tag::Compound(self.tags)))
+    }
+}
+
+impl ser::SerializeMap for CompoundSerializer {
+    type Ok = Option<Tag>;
+    type Error = ProtocolError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), ProtocolError> {
+        match key.serialize(TagSerializer)? {
+            Some(Tag::String(name)) => {
+                self.name = Some(name);
+                Ok(())
+            }
+            _ => Err(ProtocolError::InvalidNbt(String::from(
+                "compounds can only be keyed by strings",
+            ))),
+        }
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ProtocolError> {
+        let name = self
+            .name
+            .take()
+            .expect("serde always serializes a key before its value");
+        self.insert(name, value)
+    }
+
+    fn end(self) -> Result<Option<Tag>, ProtocolError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for CompoundSerializer {
+    type Ok = Option<Tag>;
+    type Error = ProtocolError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<(), ProtocolError> {
+        self.insert(String::from(name), value)
+    }
+
+    fn end(self) -> Result<Option<Tag>, ProtocolError> {
+        self.finish()
+    }
+}
+
+// Enum variants with data are written as a compound holding just the variant
+struct VariantSerializer<T> {
+    variant: &'static str,
+    inner: T,
+}
+
+impl<T> VariantSerializer<T> {
+    fn wrap(variant: &'static str, tag: Option<Tag>) -> Result<Option<Tag>, ProtocolError> {
+        let mut tags = BTreeMap::new();
+        if let Some(tag) = tag {
+            tags.insert(String::from(variant), tag);
+        }
+        Ok(Some(Tag::Compound(tags)))
+    }
+}
+
+impl ser::SerializeTupleVariant for VariantSerializer<ListSerializer> {
+    type Ok = Option<Tag>;
+    type Error = ProtocolError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ProtocolError> {
+        self.inner.push(value)
+    }
+
+    fn end(self) -> Result<Option<Tag>, ProtocolError> {
+        Self::wrap(self.variant, self.inner.finish()?)
+    }
+}
+
+impl ser::SerializeStructVariant for VariantSerializer<CompoundSerializer> {
+    type Ok = Option<Tag>;
+    type Error = ProtocolError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<(), ProtocolError> {
+        self.inner.insert(String::from(name), value)
+    }
+
+    fn end(self) -> Result<Option<Tag>, ProtocolError> {
+        Self::wrap(self.variant, self.inner.finish()?)
+    }
+}
+
+impl<'de> IntoDeserializer<'de, ProtocolError> for Tag {
+    type Deserializer = Tag;
+
+    fn into_deserializer(self) -> Tag {
+        self
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Tag {
+    type Error = ProtocolError;
+
+    fn deserialize_any<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, ProtocolError> {
+        match self {
+            Tag::Byte(v) => visitor.visit_i8(v),
+            Tag::Short(v) => visitor.visit_i16(v),
+            Tag::Int(v) => visitor.visit_i32(v),
+            Tag::Long(v) => visitor.visit_i64(v),
+            Tag::Float(v) => visitor.visit_f32(v),
+            Tag::Double(v) => visitor.visit_f64(v),
+            Tag::ByteArray(v) => visit_seq(visitor, v),
+            Tag::String(v) => visitor.visit_string(v),
+            Tag::List(v) => visit_seq(visitor, v),
+            Tag::Compound(v) => {
+                let mut map = MapDeserializer::new(v.into_iter());
+                let value = visitor.visit_map(&mut map)?;
+                map.end()?;
+                Ok(value)
+            }
+            Tag::IntArray(v) => visit_seq(visitor, v),
+            Tag::LongArray(v) => visit_seq(visitor, v),
+        }
+    }
+
+    fn deserialize_bool<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, ProtocolError> {
+        match self {
+            Tag::Byte(v) => visitor.visit_bool(v != 0),
+            tag => tag.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_option<V: de::Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> Result<V::Value, ProtocolError> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, ProtocolError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, ProtocolError> {
+        match self {
+            Tag::String(variant) => visitor.visit_enum(variant.into_deserializer()),
+            Tag::Compound(v) if v.len() == 1 => visitor.visit_enum(MapAccessDeserializer::new(
+                MapDeserializer::new(v.into_iter()),
+            )),
+            _ => Err(ProtocolError::InvalidNbt(String::from(
+                "enums are a string or a compound holding one variant",
+            ))),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf unit
+        unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+fn visit_seq<'de, V: de::Visitor<'de>, T: IntoDeserializer<'de, ProtocolError>>(
+    visitor: V,
+    elements: Vec<T>,
+) -> Result<V::Value, ProtocolError> {
+    let mut seq = SeqDeserializer::new(elements.into_iter());
+    let value = visitor.visit_seq(&mut seq)?;
+    seq.end()?;
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use serde::Serialize;
+
+    // The example every NBT library starts with
+    const HELLO_WORLD: &[u8] = &[
+        0x0a, 0x00, 0x0b, b'h', b'e', b'l', b'l', b'o', b' ', b'w', b'o', b'r', b'l', b'd', 0x08,
+        0x00, 0x04, b'n', b'a', b'm', b'e', 0x00, 0x09, b'B', b'a', b'n', b'a', b'n', b'r', b'a',
+        b'm', b'a', 0x00,
+    ];
+
+    fn compound(tags: Vec<(&str, Tag)>) -> Tag {
+        Tag::Compound(
+            tags.into_iter()
+                .map(|(name, tag)| (String::from(name), tag))
+                .collect(),
+        )
+    }
+
+    // Floats are kept finite since NaN never equals itself, and lists hold a single type
+    fn arbitrary_tag() -> impl Strategy<Value = Tag> {
+        let leaf = prop_oneof![
+            any::<i8>().prop_map(Tag::Byte),
+            any::<i16>().prop_map(Tag::Short),
+            any::<i32>().prop_map(Tag::Int),
+            any::<i64>().prop_map(Tag::Long),
+            (-1.0e6f32..1.0e6).prop_map(Tag::Float),
+            (-1.0e9f64..1.0e9).prop_map(Tag::Double),
+            proptest::collection::vec(any::<i8>(), 0..16).prop_map(Tag::ByteArray),
+            ".{0,16}".prop_map(Tag::String),
+            proptest::collection::vec(any::<i32>(), 0..16).prop_map(Tag::IntArray),
+            proptest::collection::vec(any::<i64>(), 0..16).prop_map(Tag::LongArray),
+        ];
+        leaf.prop_recursive(4, 64, 8, |inner| {
+            prop_oneof![
+                (inner.clone(), 0..8usize).prop_map(|(tag, length)| Tag::List(vec![tag; length])),
+                proptest::collection::btree_map(".{0,8}", inner, 0..8).prop_map(Tag::Compound),
+            ]
+        })
+    }
+
+    #[test]
+    fn hello_world_is_read_and_written() {
+        let expected = compound(vec![("name", Tag::String(String::from("Bananrama")))]);
+        let mut stream = std::io::Cursor::new(HELLO_WORLD);
+        assert_eq!(
+            Some((String::from("hello world"), expected.clone())),
+            read(&mut stream).unwrap()
+        );
+
+        let mut bytes = Vec::new();
+        write(&mut bytes, "hello world", &expected).unwrap();
+        assert_eq!(HELLO_WORLD, bytes.as_slice());
+    }
+
+    proptest! {
+        #[test]
+        fn tags_round_trip(tag in arbitrary_tag()) {
+            let mut bytes = Vec::new();
+            write(&mut bytes, "root", &tag).unwrap();
+            let mut stream = std::io::Cursor::new(bytes);
+            prop_assert_eq!(Some((String::from("root"), tag.clone())), read(&mut stream).unwrap());
+
+            let mut gzipped = Vec::new();
+            write_gzip(&mut gzipped, "level", &tag).unwrap();
+            prop_assert_eq!((String::from("level"), tag.clone()), read_gzip(gzipped.as_slice()).unwrap());
+
+            let mut zlibbed = Vec::new();
+            write_zlib(&mut zlibbed, "chunk", &tag).unwrap();
+            prop_assert_eq!((String::from("chunk"), tag.clone()), read_zlib(zlibbed.as_slice()).unwrap());
+
+            prop_assert_eq!(tag.clone(), to_tag(&tag).unwrap());
+        }
+    }
+
+    #[test]
+    fn malformed_nbt_is_refused() {
+        let mut deep = vec![TAG_LIST, 0, 0];
+        for _ in 0..=MAX_DEPTH {
+            deep.extend(&[TAG_LIST, 0, 0, 0, 1]);
+        }
+        assert!(matches!(
+            read(&mut deep.as_slice()),
+            Err(ProtocolError::InvalidNbt(_))
+        ));
+
+        let negative = [TAG_INT_ARRAY, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF];
+        assert!(matches!(
+            read(&mut &negative[..]),
+            Err(ProtocolError::InvalidLength(-1))
+        ));
+
+        let unknown = [TAG_COMPOUND, 0, 0, 13, 0, 0];
+        assert!(matches!(
+            read(&mut &unknown[..]),
+            Err(ProtocolError::InvalidNbt(_))
+        ));
+
+        // A length far beyond what was sent
+        let truncated = [TAG_LONG_ARRAY, 0, 0, 0x7F, 0xFF, 0xFF, 0xFF, 0];
+        assert!(matches!(
+            read(&mut &truncated[..]),
+            Err(ProtocolError::Io(_))
+        ));
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Facing {
+        North,
+        Up { steps: u8 },
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Sign {
+        id: String,
+        x: i32,
+        glowing: bool,
+        text: Vec<String>,
+        #[serde(with = "long_array")]
+        heights: Vec<i64>,
+        facing: Facing,
+        lit: Facing,
+        owner: Option<String>,
+    }
+
+    #[test]
+    fn structs_are_serialized_as_compounds() {
+        let sign = Sign {
+            id: String::from("minecraft:sign"),
+            x: -4,
+            glowing: true,
+            text: vec![String::from("hello"), String::from("world")],
+            heights: vec![1, 2],
+            facing: Facing::North,
+            lit: Facing::Up { steps: 200 },
+            owner: None,
+        };
+        let tag = compound(vec![
+            ("id", Tag::String(String::from("minecraft:sign"))),
+            ("x", Tag::Int(-4)),
+            ("glowing", Tag::Byte(1)),
+            (
+                "text",
+                Tag::List(vec![
+                    Tag::String(String::from("hello")),
+                    Tag::String(String::from("world")),
+                ]),
+            ),
+            ("heights", Tag::LongArray(vec![1, 2])),
+            ("facing", Tag::String(String::from("North"))),
+            (
+                "lit",
+                compound(vec![("Up", compound(vec![("steps", Tag::Short(200))]))]),
+            ),
+        ]);
+        assert_eq!(tag, to_tag(&sign).unwrap());
+        assert_eq!(sign, from_tag(tag).unwrap());
+
+        assert!(matches!(
+            to_tag(&vec![Tag::Int(1), Tag::Byte(1)]),
+            Err(ProtocolError::InvalidNbt(_))
+        ));
+    }
+
+    #[test]
+    fn tags_are_printed_as_snbt() {
+        let tag = compound(vec![
+            ("id", Tag::String(String::from("minecraft:\"chest\""))),
+            ("Lock", Tag::String(String::new())),
+            ("light level", Tag::Byte(15)),
+            ("Pos", Tag::List(vec![Tag::Double(0.5), Tag::Double(64.0)])),
+            (
+                "Items",
+                Tag::List(vec![compound(vec![
+                    ("Count", Tag::Byte(1)),
+                    ("Slot", Tag::Short(3)),
+                ])]),
+            ),
+            ("Heights", Tag::LongArray(vec![1, 2])),
+            ("Empty", compound(vec![])),
+        ]);
+        assert_eq!(
+            "{Empty: {}, Heights: [L; 1L, 2L], Items: [{Count: 1b, Slot: 3s}], Lock: \"\", \
+             Pos: [0.5d, 64d], id: \"minecraft:\\\"chest\\\"\", \"light level\": 15b}",
+            tag.to_string()
+        );
+        assert_eq!(
+            "{
+    Empty: {},
+    Heights: [L; 1L, 2L],
+    Items: [
+        {
+            Count: 1b,
+            Slot: 3s
+        }
+    ],
+    Lock: \"\",
+    Pos: [0.5d, 64d],
+    id: \"minecraft:\\\"chest\\\"\",
+    \"light level\": 15b
+}",
+            format!("{:#}", tag)
+        );
+    }
+}
@@ -0,0 +1,114 @@
+// The messenger's end of a connection. Packets are serialized by the messenger and queued here,
+// and the network writes them out in the background, so a slow client only ever holds up its own
+// packets. The messenger keeps an eye on how many bytes are still waiting, which is what bounds
+// the queue: see the outbound limits in the messenger service
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+#[derive(Debug)]
+pub enum OutboundMessage {
+    Bytes(Vec<u8>),
+    Close,
+}
+
+#[derive(Debug, Clone)]
+pub struct Outbound {
+    sender: UnboundedSender<OutboundMessage>,
+    queued: Arc<AtomicUsize>,
+    evicted: Arc<AtomicBool>,
+}
+
+impl Outbound {
+    pub fn channel() -> (Outbound, OutboundReceiver) {
+        let (sender, receiver) = unbounded_channel();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let evicted = Arc::new(AtomicBool::new(false));
+        (
+            Outbound {
+                sender,
+                queued: queued.clone(),
+                evicted: evicted.clone(),
+            },
+            OutboundReceiver {
+                receiver,
+                queued,
+                evicted,
+            },
+        )
+    }
+
+    // The connection may already be gone, in which case there's nobody left to write to
+    pub fn send(&self, bytes: Vec<u8>) {
+        let length = bytes.len();
+        if self.sender.send(OutboundMessage::Bytes(bytes)).is_ok() {
+            self.queued.fetch_add(length, Ordering::SeqCst);
+        }
+    }
+
+    // Bytes queued that the network hasn't picked up yet
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    // Everything queued before this is still written before the socket is shut down
+    pub fn close(&self) {
+        let _ = self.sender.send(OutboundMessage::Close);
+    }
+
+    // Like close, but whatever is still queued is thrown away
+    pub fn evict(&self) {
+        self.evicted.store(true, Ordering::SeqCst);
+        self.close();
+    }
+}
+
+pub struct OutboundReceiver {
+    receiver: UnboundedReceiver<OutboundMessage>,
+    queued: Arc<AtomicUsize>,
+    evicted: Arc<AtomicBool>,
+}
+
+impl OutboundReceiver {
+    // The next bytes to write, or None once the connection should be shut down
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        match self.receiver.recv().await {
+            Some(OutboundMessage::Bytes(bytes)) => {
+                self.queued.fetch_sub(bytes.len(), Ordering::SeqCst);
+                if self.evicted.load(Ordering::SeqCst) {
+                    None
+                } else {
+                    Some(bytes)
+                }
+            }
+            Some(OutboundMessage::Close) | None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn queued_bytes_are_counted_until_picked_up() {
+        let (outbound, mut receiver) = Outbound::channel();
+        outbound.send(vec![0; 10]);
+        outbound.send(vec![0; 5]);
+        assert_eq!(15, outbound.queued());
+        assert_eq!(Some(vec![0; 10]), receiver.recv().await);
+        assert_eq!(5, outbound.queued());
+        outbound.close();
+        assert_eq!(Some(vec![0; 5]), receiver.recv().await);
+        assert_eq!(None, receiver.recv().await);
+    }
+
+    #[tokio::test]
+    async fn evicted_connections_drop_what_is_queued() {
+        let (outbound, mut receiver) = Outbound::channel();
+        outbound.send(vec![0; 10]);
+        outbound.evict();
+        assert_eq!(None, receiver.recv().await);
+    }
+}
@@ -1,17 +1,67 @@
 #![allow(unused_variables)]
 //The macro is much cleaner if we allow for unused variables
+use super::compression::compress;
 use super::constants::{CHUNK_SIZE, ENTITY_ID_BLOCK_SIZE};
 use super::minecraft_protocol::{MinecraftProtocolReader, MinecraftProtocolWriter};
-use super::minecraft_types::{BlockPosition, ChunkSection};
+#[cfg(test)]
+use super::minecraft_types::{BlockEntity, ChunkSection};
+use super::minecraft_types::{BlockPosition, ChunkColumn, ProfileProperty};
+use super::protocol_error::ProtocolError;
+use super::protocol_version::ProtocolVersion;
 use super::translation::TranslationInfo;
 use std::any::type_name;
 use std::io::{Cursor, Read, Write};
 
-// Format: (state (99 is outgoing), name, id, [ list of (field name, field type) ]
+// The states a connection goes through. Clients handshake into status or login and go on to play,
+// peers handshake straight into one of the peer states. The values are what handshakes ask for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Handshake = 0,
+    ClientPing = 1,
+    Login = 2,
+    Play = 3,
+    BorderCrossLogin = 4,
+    InPeerSub = 5,
+    OutPeerSub = 6,
+}
+
+impl Status {
+    pub fn from_i32(status: i32) -> Result<Status, ProtocolError> {
+        match status {
+            0 => Ok(Status::Handshake),
+            1 => Ok(Status::ClientPing),
+            2 => Ok(Status::Login),
+            3 => Ok(Status::Play),
+            4 => Ok(Status::BorderCrossLogin),
+            5 => Ok(Status::InPeerSub),
+            6 => Ok(Status::OutPeerSub),
+            _ => Err(ProtocolError::InvalidState(status)),
+        }
+    }
+
+    // Which way the packets we read in this state are headed. We're the server on every connection
+    // except the ones we open to subscribe to a peer's map
+    pub fn inbound(self) -> Direction {
+        match self {
+            Status::InPeerSub => Direction::Clientbound,
+            _ => Direction::Serverbound,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Serverbound,
+    Clientbound,
+}
+
+// Format: ([ list of Direction(states) it is read in ], name, id, [ list of (field name, field type) ]
+// Packets sharing an id in a state are registered with the value of their first field, a VarInt,
+// e.g. Clientbound(Play) if action == 2
 #[rustfmt::skip::macros(packet_boilerplate)]
 packet_boilerplate!(
     (
-        0,
+        [Serverbound(Handshake, OutPeerSub)], //Subscribers handshake again to ask for a report
         Handshake,
         0,
         [
@@ -21,12 +71,24 @@ packet_boilerplate!(
             (next_state, VarInt)
         ]
     ),
-    (1, StatusRequest, 0, []),
-    (1, Ping, 1, [(payload, Long)]),
-    (2, LoginStart, 0, [(username, String)]),
-    (3, KeepAlive, 0x21, [(id, Long)]),
+    ([Serverbound(ClientPing)], StatusRequest, 0, []),
+    ([Serverbound(ClientPing)], Ping, 1, [(payload, Long)]),
+    ([Serverbound(Login)], LoginStart, 0, [(username, String)]),
+    (
+        [Serverbound(Login)],
+        EncryptionResponse,
+        0x01,
+        [(shared_secret, ByteArray), (verify_token, ByteArray)]
+    ),
     (
-        3,
+        [Clientbound(Login), Serverbound(BorderCrossLogin, OutPeerSub)], //Peers send it right after their handshake
+        SetCompression,
+        0x03,
+        [(threshold, VarInt)]
+    ),
+    ([Serverbound(Play)], KeepAlive, 0x0E, [(id, Long)]),
+    (
+        [Serverbound(Play)],
         PlayerPosition,
         0x10,
         [
@@ -37,19 +99,19 @@ packet_boilerplate!(
         ]
     ),
     (
-        3,
+        [Serverbound(Play)],
         ChatMessage,
         0x02,
         [(message, String)]
     ),
     (
-        6,
+        [Clientbound(Play)],
         ClientboundChatMessage,
         0x0E,
         [(message, String),(position, UByte)]
     ),
     (
-        _, //Temporary for border crossing, once it has its own packet change this back to 3
+        [Serverbound(Play)],
         PlayerPositionAndLook,
         0x11,
         [
@@ -62,7 +124,7 @@ packet_boilerplate!(
         ]
     ),
     (
-        3,
+        [Serverbound(Play)],
         PlayerLook,
         0x12,
         [
@@ -72,7 +134,7 @@ packet_boilerplate!(
         ]
     ),
     (
-        3,
+        [Serverbound(Play)],
         PlayerBlockPlacement,
         0x29,
         [
@@ -84,8 +146,8 @@ packet_boilerplate!(
             (cursor_z, Float)
         ]
     ),
-    (6, ReportState, 0x1, []),
-    (_, BorderCrossLogin, 0xA0, [
+    ([Serverbound(OutPeerSub)], ReportState, 0x1, []),
+    ([Serverbound(BorderCrossLogin)], BorderCrossLogin, 0xA0, [
             (x, Double, XEntity),
             (feet_y, Double),
             (z, Double, ZEntity),
@@ -93,13 +155,24 @@ packet_boilerplate!(
             (pitch, Float),
             (on_ground, Boolean),
             (username, String),
-            (entity_id, Int, EntityId)
+            (entity_id, Int, EntityId),
+            (uuid, u128),
+            (properties, PropertyArray)
     ]),
-    (99, Pong, 1, [(payload, Long)]),
-    (99, StatusResponse, 0, [(json_response, String)]),
-    (99, LoginSuccess, 2, [(uuid, String), (username, String)]),
+    ([Clientbound(ClientPing)], Pong, 1, [(payload, Long)]),
+    ([Clientbound(ClientPing)], StatusResponse, 0, [(json_response, String)]),
     (
-        99,
+        [Clientbound(Login)],
+        EncryptionRequest,
+        0x01,
+        [(server_id, String), (public_key, ByteArray), (verify_token, ByteArray)]
+    ),
+    ([Clientbound(Login)], LoginSuccess, 2, [(uuid, String), (username, String)]),
+    ([Clientbound(Login)], LoginDisconnect, 0x00, [(reason, String)]),
+    ([Clientbound(Play)], ClientboundKeepAlive, 0x21, [(id, Long)]),
+    ([Clientbound(Play)], PlayDisconnect, 0x1B, [(reason, String)]),
+    (
+        [Clientbound(Play)],
         JoinGame,
         0x25,
         [
@@ -113,7 +186,7 @@ packet_boilerplate!(
         ]
     ),
     (
-        99,
+        [Clientbound(Play)],
         ClientboundPlayerPositionAndLook,
         0x32,
         [
@@ -127,22 +200,17 @@ packet_boilerplate!(
         ]
     ),
     (
-        _,
+        [Clientbound(Play, InPeerSub)],
         ChunkData,
         0x22,
         [
             (chunk_x, Int, XChunk),
             (chunk_z, Int, ZChunk),
-            (full_chunk, Boolean), //always true
-            (primary_bit_mask, VarInt),
-            (size, VarInt),
-            (data, ChunkSection), //actually a chunk array, but can pretend its 1 for now
-            (biomes, Array(Int, 256)),
-            (number_of_block_entities, VarInt)
+            (column, ChunkColumn, ChunkColumn)
         ]
     ),
     (
-        _,
+        [Clientbound(Play, InPeerSub) if action == 0],
         PlayerInfo,
         0x30,
         [
@@ -150,14 +218,25 @@ packet_boilerplate!(
             (number_of_players, VarInt),
             (uuid, u128),
             (name, String),
-            (number_of_properties, VarInt),
+            (properties, PropertyArray),
             (gamemode, VarInt),
             (ping, VarInt),
             (has_display_name, Boolean)
         ]
     ),
     (
-        _,
+        [Clientbound(Play) if action == 2],
+        PlayerInfoUpdateLatency, // the same packet as PlayerInfo, with action 2
+        0x30,
+        [
+            (action, VarInt),
+            (number_of_players, VarInt),
+            (uuid, u128),
+            (ping, VarInt)
+        ]
+    ),
+    (
+        [Clientbound(Play, InPeerSub)],
         SpawnPlayer,
         0x05,
         [
@@ -172,7 +251,7 @@ packet_boilerplate!(
         ]
     ),
     (
-        _,
+        [Clientbound(Play, InPeerSub)],
         EntityHeadLook,
         0x39,
         [
@@ -181,7 +260,7 @@ packet_boilerplate!(
         ]
     ),
     (
-        _,
+        [Clientbound(Play, InPeerSub)],
         DestroyEntities,
         0x35,
         [
@@ -189,7 +268,7 @@ packet_boilerplate!(
         ]
     ),
     (
-        _,
+        [Clientbound(Play, InPeerSub)],
         EntityLookAndMove,
         0x29,
         [
@@ -203,7 +282,7 @@ packet_boilerplate!(
         ]
     ),
     (
-        _,
+        [Clientbound(Play, InPeerSub)],
         EntityTeleport,
         0x50,
         [
@@ -217,7 +296,7 @@ packet_boilerplate!(
         ]
     ),
     (
-        _,
+        [Clientbound(Play, InPeerSub)],
         BlockChange, // clientbound
         0x0b,
         [
@@ -226,7 +305,7 @@ packet_boilerplate!(
         ]
     ),
     (
-        _,
+        [Serverbound(Play)],
         PlayerDigging, // serverbound
         0x18,
         [
@@ -236,3 +315,111 @@ packet_boilerplate!(
         ]
     )
 );
+
+fn peek_var_int(stream: &mut Cursor<Vec<u8>>) -> Option<i32> {
+    let position = stream.position();
+    let value = stream.read_var_int().ok();
+    stream.set_position(position);
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(packet: Packet) -> Cursor<Vec<u8>> {
+        let mut bytes = Vec::new();
+        write(&mut bytes, packet, None, ProtocolVersion::NATIVE);
+        Cursor::new(bytes[1..].to_vec())
+    }
+
+    #[test]
+    fn unknown_packets_are_not_written() {
+        let mut bytes = Vec::new();
+        write(&mut bytes, Packet::Unknown, None, ProtocolVersion::NATIVE);
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn shared_ids_are_read_by_direction() {
+        let placement = Packet::PlayerBlockPlacement(PlayerBlockPlacement {
+            location: BlockPosition { x: 4, y: 16, z: 4 },
+            face: 1,
+            hand: 0,
+            cursor_x: 0.5,
+            cursor_y: 1.0,
+            cursor_z: 0.5,
+        });
+        let movement = Packet::EntityLookAndMove(EntityLookAndMove {
+            entity_id: 5,
+            delta_x: 1,
+            delta_y: 0,
+            delta_z: -1,
+            yaw: 0,
+            pitch: 0,
+            on_ground: true,
+        });
+        assert_eq!(PlayerBlockPlacement::ID, EntityLookAndMove::ID);
+        assert_eq!(
+            placement,
+            read(
+                &mut frame(placement.clone()),
+                Status::Play,
+                Direction::Serverbound,
+                ProtocolVersion::NATIVE
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            movement,
+            read(
+                &mut frame(movement.clone()),
+                Status::Play,
+                Direction::Clientbound,
+                ProtocolVersion::NATIVE
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            Packet::Unknown,
+            read(
+                &mut frame(movement),
+                Status::Handshake,
+                Direction::Clientbound,
+                ProtocolVersion::NATIVE
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn player_info_is_read_by_action() {
+        let latency = Packet::PlayerInfoUpdateLatency(PlayerInfoUpdateLatency {
+            action: 2,
+            number_of_players: 1,
+            uuid: 7,
+            ping: 40,
+        });
+        assert_eq!(
+            latency,
+            read(
+                &mut frame(latency.clone()),
+                Status::Play,
+                Direction::Clientbound,
+                ProtocolVersion::NATIVE
+            )
+            .unwrap()
+        );
+        // Peers are never sent latencies, they keep their own
+        assert_eq!(
+            Packet::Unknown,
+            read(
+                &mut frame(latency),
+                Status::InPeerSub,
+                Direction::Clientbound,
+                ProtocolVersion::NATIVE
+            )
+            .unwrap()
+        );
+    }
+}
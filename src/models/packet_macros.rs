@@ -1,10 +1,11 @@
 macro_rules! packet_boilerplate {
-    ( $( ( $state:pat, $name:ident, $id:expr,
+    ( $( ( [$($direction:ident($($status:ident),+) $(if $discriminant:ident == $value:literal)?),*],
+           $name:ident, $id:literal,
            [$(($fieldname:ident, $datatype:ident$(($($typearg:tt),*))* $(, $transtype:tt$(($($transarg:tt),*))*),* ) ),*]
     )),*) => (
         //Create an enum with a struct variant for each packet we've defined
         //and a special variant for a packet we haven't defined
-        #[derive(Debug, Clone)]
+        #[derive(Debug, Clone, PartialEq)]
         pub enum Packet {
             Unknown,
             $($name($name)),*
@@ -20,46 +21,104 @@ macro_rules! packet_boilerplate {
             }
         }
 
-        pub fn read<S: MinecraftProtocolReader + Read>(stream: &mut S, state: i32) -> Packet {
-            let id = stream.read_var_int();
+        //call the initializer method of the packet class registered for this direction, state and
+        //packet id. Registering the same combination twice leaves one of the arms unreachable,
+        //which is denied so that the mistake never compiles
+        #[deny(unreachable_patterns)]
+        pub fn read(
+            stream: &mut Cursor<Vec<u8>>,
+            status: Status,
+            direction: Direction,
+            version: ProtocolVersion,
+        ) -> Result<Packet, ProtocolError> {
+            let id = stream.read_var_int()?;
+            //Packets are registered by their 1.13.2 ids, which only differ from the client's in play
+            let id = match status {
+                Status::Play => match version.native_play_id(direction, id) {
+                    Some(id) => id,
+                    None => return Ok(Packet::Unknown),
+                },
+                _ => id,
+            };
+            let discriminant = peek_var_int(stream);
 
-            //call the initializer method of the packet class associated with
-            //this state and packet id combination
-            match (state,id) {
-                $( ($state, $id) => {
-                    let packet = Packet::$name($name::new(stream));
-                    if stream.bytes().next().is_some() {
-                        panic!(
-                            "Failed to read entire buffer for packet with id {:?} in state {:?}",
+            match (direction, status, id, discriminant) {
+                $($( (
+                    Direction::$direction,
+                    $(Status::$status)|+,
+                    $id,
+                    packet_discriminant!($($value)?)
+                ) => {
+                    let layout = match status {
+                        Status::Play => version.read_fields(direction, id, stream)?,
+                        _ => None,
+                    };
+                    let packet = match layout {
+                        Some(packet) => packet,
+                        None => Packet::$name($name::new(stream)?),
+                    };
+                    let mut remaining = Vec::new();
+                    stream.read_to_end(&mut remaining)?;
+                    if !remaining.is_empty() {
+                        return Err(ProtocolError::TrailingBytes {
+                            state: status as i32,
                             id,
-                            state
-                        );
+                            remaining: remaining.len(),
+                        });
                     }
-                    packet
-                } )*
+                    Ok(packet)
+                } )*)*
                 _ => {
-                    Packet::Unknown
+                    Ok(Packet::Unknown)
                 }
             }
         }
 
-        pub fn write<S: MinecraftProtocolWriter + Write>(stream: &mut S, packet: Packet) {
+        //Compression is only applied once it has been enabled for the connection, at which point
+        //the threshold decides which packets are worth compressing. Unknown packets have nothing to
+        //write and are left out, since they could have come from anyone
+        pub fn write<S: MinecraftProtocolWriter + Write>(
+            stream: &mut S,
+            packet: Packet,
+            compression_threshold: Option<i32>,
+            version: ProtocolVersion,
+        ) {
+            //Some versions need packets 1.13.2 doesn't have sent ahead of this one
+            for frame in version.preceding_frames(&packet) {
+                write_frame(stream, frame, compression_threshold);
+            }
+
             //Write the ID and the values of the packet fields
             let mut cursor = Cursor::new(Vec::new());
-            match packet {
-                $(Packet::$name(packet) => {
-                    cursor.write_var_int($name::ID);
-                    packet.write_fields(&mut cursor)
+            match &packet {
+                $(Packet::$name(fields) => {
+                    cursor.write_var_int(version.id($name::REGISTRATIONS, $name::ID));
+                    if !version.write_fields(&packet, &mut cursor) {
+                        fields.write_fields(&mut cursor)
+                    }
                 })*
-                _ => { panic!("I don't know how to write this packet {:?}", packet) }
+                Packet::Unknown => {
+                    warn!("Not writing an unknown packet");
+                    return;
+                }
             }
+            write_frame(stream, cursor.into_inner(), compression_threshold);
+        }
 
+        fn write_frame<S: MinecraftProtocolWriter + Write>(
+            stream: &mut S,
+            frame: Vec<u8>,
+            compression_threshold: Option<i32>,
+        ) {
             //Measure what we've written so far to determine packet length
-            let size_vec = cursor.into_inner();
+            let size_vec = match compression_threshold {
+                Some(threshold) => compress(frame, threshold),
+                None => frame,
+            };
             let size = size_vec.len();
 
             //Write the length into a vector
-            cursor = Cursor::new(Vec::new());
+            let mut cursor = Cursor::new(Vec::new());
             cursor.write_var_int(size as i32);
 
             //combine the length vector with the sizing vector to get
@@ -93,17 +152,103 @@ macro_rules! packet_boilerplate {
 
         //Define the packet struct
         $(packet!{$name, $id, [ $( ( $fieldname, $datatype$(($($typearg),*))* $(, $transtype$(($($transarg),*))*),* ) ),* ]})*
+
+        //Which states each packet is registered for, so that it is written with the id the
+        //connection's version gives it there
+        $(impl $name {
+            pub const REGISTRATIONS: &'static [(Direction, Status)] =
+                &[$($((Direction::$direction, Status::$status)),+),*];
+        })*
+
+        //Every packet has to come out of write and back in exactly as it was, with or without
+        //compression. Packets are read back with their own struct rather than read, since ids
+        //are only unique within a state and direction
+        #[cfg(test)]
+        mod round_trip {
+            use super::*;
+            use super::super::compression::decompress;
+            use proptest::prelude::*;
+
+            fn compression_threshold() -> impl Strategy<Value = Option<i32>> {
+                prop_oneof![Just(None), Just(Some(0)), Just(Some(256))]
+            }
+
+            fn frame_body(bytes: Vec<u8>, compression_threshold: Option<i32>) -> Cursor<Vec<u8>> {
+                let mut cursor = Cursor::new(bytes);
+                let length = cursor.read_var_int().unwrap() as usize;
+                let frame = cursor.get_ref()[cursor.position() as usize..].to_vec();
+                assert_eq!(length, frame.len());
+                match compression_threshold {
+                    Some(_) => Cursor::new(decompress(frame).unwrap()),
+                    None => Cursor::new(frame),
+                }
+            }
+
+            proptest! {
+                $(
+                    #[test]
+                    #[allow(non_snake_case)]
+                    fn $name(
+                        packet in $name::arbitrary(),
+                        compression_threshold in compression_threshold(),
+                    ) {
+                        let mut bytes = Vec::new();
+                        write(
+                            &mut bytes,
+                            Packet::$name(packet.clone()),
+                            compression_threshold,
+                            ProtocolVersion::NATIVE,
+                        );
+                        let mut body = frame_body(bytes, compression_threshold);
+                        prop_assert_eq!($name::ID, body.read_var_int().unwrap());
+                        prop_assert_eq!(&packet, &$name::new(&mut body).unwrap());
+                        prop_assert_eq!(body.get_ref().len() as u64, body.position());
+                    }
+                )*
+
+                // Frames lead with an id that's likely to be real, or the fields would hardly ever
+                // be reached
+                #[test]
+                fn reading_garbage_never_panics(
+                    status in (0..=6).prop_map(|state| Status::from_i32(state).unwrap()),
+                    version in proptest::sample::select(ProtocolVersion::SUPPORTED.to_vec()),
+                    id in 0u8..0x60,
+                    body in proptest::collection::vec(any::<u8>(), 0..512),
+                ) {
+                    let mut frame = vec![id];
+                    frame.extend(body);
+                    let _ = read(
+                        &mut Cursor::new(frame.clone()),
+                        status,
+                        Direction::Serverbound,
+                        version,
+                    );
+                    let _ = read(&mut Cursor::new(frame), status, Direction::Clientbound, version);
+                }
+            }
+        }
     )
 }
 
+// Packets that share an id are told apart by the value of their first field, and match whatever
+// it is otherwise
+macro_rules! packet_discriminant {
+    () => {
+        _
+    };
+    ($value:literal) => {
+        Some($value)
+    };
+}
+
 macro_rules! packet {
     ($name:ident, $id:expr, [ $( ($fieldname:ident, $datatype:ident$(($($typearg:tt),*))* $(, $transtype:tt$(($($transarg:tt),*))*),* )),+]) => (
-        #[derive(Debug, Clone)]
+        #[derive(Debug, Clone, PartialEq)]
         pub struct $name { $(pub $fieldname: mc_to_rust_datatype!($datatype$(($($typearg),*))*)),* }
         impl $name {
-            const ID: i32 = $id;
-            pub fn new<S: MinecraftProtocolReader>(stream: &mut S) -> $name {
-                $name { $( $fieldname: read_packet_field!(stream, $datatype$(($($typearg),*))*) ),* }
+            pub const ID: i32 = $id;
+            pub fn new<S: MinecraftProtocolReader>(stream: &mut S) -> Result<$name, ProtocolError> {
+                Ok($name { $( $fieldname: read_packet_field!(stream, $datatype$(($($typearg),*))*)? ),* })
             }
             pub fn write_fields<S: MinecraftProtocolWriter>(&self, stream: &mut S) {
                 $( write_packet_field!(stream, self.$fieldname.clone(), $datatype$(($($typearg),*))*) );*
@@ -127,14 +272,22 @@ macro_rules! packet {
                 translated
             }
         }
+        #[cfg(test)]
+        impl $name {
+            pub fn arbitrary() -> impl proptest::strategy::Strategy<Value = $name> {
+                use proptest::prelude::*;
+                ( $( arbitrary_packet_field!($datatype$(($($typearg),*))*), )* )
+                    .prop_map(|( $($fieldname,)* )| $name { $($fieldname),* })
+            }
+        }
     );
     ($name:ident, $id:expr, []) => (
-        #[derive(Debug, Clone)]
+        #[derive(Debug, Clone, PartialEq)]
         pub struct $name {}
         impl $name {
-            const ID: i32 = $id;
-            pub fn new<S: MinecraftProtocolReader>(stream: &mut S) -> $name {
-                $name {}
+            pub const ID: i32 = $id;
+            pub fn new<S: MinecraftProtocolReader>(stream: &mut S) -> Result<$name, ProtocolError> {
+                Ok($name {})
             }
             pub fn write_fields<S: MinecraftProtocolWriter>(&self, stream: &mut S) {}
             pub fn translate(&self, translation_data: TranslationInfo) -> $name {
@@ -144,6 +297,12 @@ macro_rules! packet {
                 self.clone()
             }
         }
+        #[cfg(test)]
+        impl $name {
+            pub fn arbitrary() -> impl proptest::strategy::Strategy<Value = $name> {
+                proptest::strategy::Just($name {})
+            }
+        }
     )
 }
 
@@ -163,6 +322,9 @@ macro_rules! mc_to_rust_datatype {
     (String) => {
         String
     };
+    (ByteArray) => {
+        Vec<u8>
+    };
     (u128) => {
         u128
     };
@@ -196,9 +358,126 @@ macro_rules! mc_to_rust_datatype {
     (ChunkSection) => {
         ChunkSection
     };
+    (ChunkColumn) => {
+        ChunkColumn
+    };
     (BlockPosition) => {
         BlockPosition
     };
+    (PropertyArray) => {
+        Vec<ProfileProperty>
+    };
+}
+
+// Values of each field type that the codec can carry unchanged. Floats are kept finite since NaN
+// never equals itself, positions stay within the bits they're packed into, and chunk sections
+// mix ones with few enough states to get a palette of their own with ones that need global ids.
+// Columns leave most of their sections out, as real ones do above the ground
+#[cfg(test)]
+macro_rules! arbitrary_packet_field {
+    (VarInt) => {
+        any::<i32>()
+    };
+    (UShort) => {
+        any::<u16>()
+    };
+    (Short) => {
+        any::<i16>()
+    };
+    (Long) => {
+        any::<i64>()
+    };
+    (String) => {
+        ".{0,32}"
+    };
+    (ByteArray) => {
+        proptest::collection::vec(any::<u8>(), 0..64)
+    };
+    (u128) => {
+        any::<u128>()
+    };
+    (Int) => {
+        any::<i32>()
+    };
+    (Array($type:ident, $length:expr)) => {
+        proptest::collection::vec(arbitrary_packet_field!($type), $length)
+    };
+    (LengthPrefixedArray($type:ident)) => {
+        proptest::collection::vec(arbitrary_packet_field!($type), 0..16)
+    };
+    (Float) => {
+        -1.0e6f32..1.0e6
+    };
+    (Double) => {
+        -1.0e9f64..1.0e9
+    };
+    (Byte) => {
+        any::<i8>()
+    };
+    (UByte) => {
+        any::<u8>()
+    };
+    (Boolean) => {
+        any::<bool>()
+    };
+    (ChunkSection) => {
+        prop_oneof![
+            proptest::collection::vec(0..(1 << 14), 4096),
+            proptest::collection::vec(0..(1 << 14), 1..=300).prop_flat_map(|palette| {
+                proptest::collection::vec(proptest::sample::select(palette), 4096)
+            }),
+        ]
+        .prop_flat_map(|block_ids| {
+            (
+                Just(block_ids),
+                proptest::collection::vec(any::<u8>(), 2048),
+                proptest::collection::vec(any::<u8>(), 2048),
+            )
+        })
+        .prop_map(|(block_ids, block_light, sky_light)| ChunkSection {
+            block_ids,
+            block_light,
+            sky_light,
+        })
+    };
+    (ChunkColumn) => {
+        (
+            proptest::collection::vec(
+                proptest::option::weighted(0.2, arbitrary_packet_field!(ChunkSection)),
+                16,
+            ),
+            proptest::option::of(proptest::collection::vec(any::<i32>(), 256)),
+            proptest::collection::vec(
+                (".{0,16}", arbitrary_packet_field!(BlockPosition))
+                    .prop_map(|(id, location)| BlockEntity { id, location }),
+                0..4,
+            ),
+        )
+            .prop_map(|(sections, biomes, block_entities)| ChunkColumn {
+                sections,
+                biomes,
+                block_entities,
+            })
+    };
+    (BlockPosition) => {
+        (0..(1u32 << 26), 0..(1u32 << 12), 0..(1u32 << 26)).prop_map(|(x, y, z)| BlockPosition {
+            x,
+            y,
+            z,
+        })
+    };
+    (PropertyArray) => {
+        proptest::collection::vec(
+            (".{0,16}", ".{0,16}", proptest::option::of(".{0,16}")).prop_map(
+                |(name, value, signature)| ProfileProperty {
+                    name,
+                    value,
+                    signature,
+                },
+            ),
+            0..4,
+        )
+    };
 }
 
 macro_rules! read_packet_field {
@@ -217,6 +496,9 @@ macro_rules! read_packet_field {
     ($stream:ident, String) => {
         $stream.read_string()
     };
+    ($stream:ident, ByteArray) => {
+        $stream.read_byte_array()
+    };
     ($stream:ident, u128) => {
         $stream.read_u_128()
     };
@@ -226,10 +508,11 @@ macro_rules! read_packet_field {
     ($stream:ident, Array($type:ident, $length:expr)) => {
         $stream.read_int_array($length)
     };
-    ($stream:ident, LengthPrefixedArray($type:ident)) => {{
-        let length = $stream.read_var_int();
-        $stream.read_var_int_array(length as u32)
-    }};
+    ($stream:ident, LengthPrefixedArray($type:ident)) => {
+        $stream
+            .read_var_int()
+            .and_then(|length| $stream.read_var_int_array(length as u32))
+    };
     ($stream:ident, Float) => {
         $stream.read_float()
     };
@@ -248,9 +531,15 @@ macro_rules! read_packet_field {
     ($stream:ident, ChunkSection) => {
         $stream.read_chunk_section()
     };
+    ($stream:ident, ChunkColumn) => {
+        $stream.read_chunk_column()
+    };
     ($stream:ident, BlockPosition) => {
         $stream.read_position()
     };
+    ($stream:ident, PropertyArray) => {
+        $stream.read_properties()
+    };
 }
 
 macro_rules! write_packet_field {
@@ -269,6 +558,9 @@ macro_rules! write_packet_field {
     ($stream:ident, $value:expr, String) => {
         $stream.write_string($value.clone())
     };
+    ($stream:ident, $value:expr, ByteArray) => {
+        $stream.write_byte_array($value)
+    };
     ($stream:ident, $value:expr, u128) => {
         $stream.write_u_128($value)
     };
@@ -300,9 +592,15 @@ macro_rules! write_packet_field {
     ($stream:ident, $value:expr, ChunkSection) => {
         $stream.write_chunk_section($value)
     };
+    ($stream:ident, $value:expr, ChunkColumn) => {
+        $stream.write_chunk_column($value)
+    };
     ($stream:ident, $value:expr, BlockPosition) => {
         $stream.write_position($value)
     };
+    ($stream:ident, $value:expr, PropertyArray) => {
+        $stream.write_properties($value)
+    };
 }
 
 macro_rules! translate_incoming_packet_field {
@@ -344,6 +642,14 @@ macro_rules! translate_incoming_packet_field {
             z: $value.z + ($transdata.map.position.z * CHUNK_SIZE) as u32,
         }
     };
+    ($value:expr, $transdata:expr, ChunkColumn) => {{
+        let mut column = $value;
+        for block_entity in column.block_entities.iter_mut() {
+            block_entity.location =
+                translate_incoming_packet_field!(block_entity.location, $transdata, BlockPosition);
+        }
+        column
+    }};
 }
 
 macro_rules! translate_outgoing_packet_field {
@@ -360,6 +666,14 @@ macro_rules! translate_outgoing_packet_field {
             z: $value.z - ($transdata.map.position.z * CHUNK_SIZE) as u32,
         }
     };
+    ($value:expr, $transdata:expr, ChunkColumn) => {{
+        let mut column = $value;
+        for block_entity in column.block_entities.iter_mut() {
+            block_entity.location =
+                translate_outgoing_packet_field!(block_entity.location, $transdata, BlockPosition);
+        }
+        column
+    }};
     ($value:expr, $transdata:expr) => {
         $value
     };
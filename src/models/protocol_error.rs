@@ -0,0 +1,87 @@
+// Everything that can go wrong while reading a packet off a connection. None of these are fatal
+// to the node: the connection the bad packet came from is disconnected and everyone else carries
+// on as before
+
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    Io(io::Error),
+    VarIntTooBig,
+    InvalidLength(i32),
+    PacketTooLarge(usize),
+    InvalidString,
+    InvalidBoolean(u8),
+    UnsupportedChunkSection(String),
+    UnsupportedChunkColumn(String),
+    InvalidNbt(String),
+    BadCompression(String),
+    TrailingBytes {
+        state: i32,
+        id: i32,
+        remaining: usize,
+    },
+    UnexpectedPacket {
+        state: i32,
+        packet: String,
+    },
+    InvalidState(i32),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::Io(e) => write!(f, "{}", e),
+            ProtocolError::VarIntTooBig => write!(f, "VarInt is too big"),
+            ProtocolError::InvalidLength(length) => write!(f, "invalid length {}", length),
+            ProtocolError::PacketTooLarge(length) => {
+                write!(f, "packet of {} bytes is too large", length)
+            }
+            ProtocolError::InvalidString => write!(f, "string is not valid UTF-8"),
+            ProtocolError::InvalidBoolean(value) => write!(f, "invalid boolean {}", value),
+            ProtocolError::UnsupportedChunkSection(reason) => {
+                write!(f, "unsupported chunk section: {}", reason)
+            }
+            ProtocolError::UnsupportedChunkColumn(reason) => {
+                write!(f, "unsupported chunk column: {}", reason)
+            }
+            ProtocolError::InvalidNbt(reason) => write!(f, "invalid NBT: {}", reason),
+            ProtocolError::BadCompression(reason) => write!(f, "bad compressed packet: {}", reason),
+            ProtocolError::TrailingBytes {
+                state,
+                id,
+                remaining,
+            } => write!(
+                f,
+                "{} unread bytes left in packet with id {:#04x} in state {}",
+                remaining, id, state
+            ),
+            ProtocolError::UnexpectedPacket { state, packet } => {
+                write!(f, "unexpected packet {} in state {}", packet, state)
+            }
+            ProtocolError::InvalidState(state) => write!(f, "invalid connection state {}", state),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<io::Error> for ProtocolError {
+    fn from(e: io::Error) -> ProtocolError {
+        ProtocolError::Io(e)
+    }
+}
+
+impl ProtocolError {
+    // The connection went away rather than sending us something we couldn't read
+    pub fn is_closed(&self) -> bool {
+        match self {
+            ProtocolError::Io(e) => matches!(
+                e.kind(),
+                io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset
+            ),
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,698 @@
+// The client versions we can talk to. Packets are defined as they are in 1.13.2, which is also
+// what peers speak to each other, and each version maps them to its own ids and, for the few that
+// changed shape, its own layout
+
+use super::minecraft_protocol::{
+    pack_blocks, MinecraftProtocolReader, MinecraftProtocolWriter, PalettedBlocks,
+};
+use super::minecraft_types::{BlockPosition, ChunkColumn, ChunkSection, LIGHT_ARRAY_LENGTH};
+use super::nbt::Tag;
+use super::packet::{
+    BlockChange, ChatMessage, ChunkData, ClientboundChatMessage, ClientboundKeepAlive,
+    ClientboundPlayerPositionAndLook, DestroyEntities, Direction, EntityHeadLook,
+    EntityLookAndMove, EntityTeleport, JoinGame, KeepAlive, Packet, PlayDisconnect,
+    PlayerBlockPlacement, PlayerDigging, PlayerInfo, PlayerLook, PlayerPosition,
+    PlayerPositionAndLook, SpawnPlayer, Status,
+};
+use super::protocol_error::ProtocolError;
+
+use std::collections::BTreeMap;
+use std::io::{Cursor, Read, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProtocolVersion {
+    V1_12_2,
+    V1_13_2,
+    V1_14_4,
+}
+
+// Every packet read in play, as (direction, 1.12.2 id, 1.13.2 id, 1.14.4 id). Ids before play
+// are the same in all of them
+const PLAY_IDS: [(Direction, i32, i32, i32); 20] = [
+    (Direction::Serverbound, 0x02, ChatMessage::ID, 0x03),
+    (Direction::Serverbound, 0x0B, KeepAlive::ID, 0x0F),
+    (Direction::Serverbound, 0x0D, PlayerPosition::ID, 0x11),
+    (
+        Direction::Serverbound,
+        0x0E,
+        PlayerPositionAndLook::ID,
+        0x12,
+    ),
+    (Direction::Serverbound, 0x0F, PlayerLook::ID, 0x13),
+    (Direction::Serverbound, 0x14, PlayerDigging::ID, 0x1A),
+    (Direction::Serverbound, 0x1F, PlayerBlockPlacement::ID, 0x2C),
+    (Direction::Clientbound, 0x05, SpawnPlayer::ID, 0x05),
+    (Direction::Clientbound, 0x0B, BlockChange::ID, 0x0B),
+    (
+        Direction::Clientbound,
+        0x0F,
+        ClientboundChatMessage::ID,
+        0x0E,
+    ),
+    (Direction::Clientbound, 0x1A, PlayDisconnect::ID, 0x1A),
+    (Direction::Clientbound, 0x1F, ClientboundKeepAlive::ID, 0x20),
+    (Direction::Clientbound, 0x20, ChunkData::ID, 0x21),
+    (Direction::Clientbound, 0x23, JoinGame::ID, 0x25),
+    (Direction::Clientbound, 0x27, EntityLookAndMove::ID, 0x29),
+    (Direction::Clientbound, 0x2E, PlayerInfo::ID, 0x33),
+    (
+        Direction::Clientbound,
+        0x2F,
+        ClientboundPlayerPositionAndLook::ID,
+        0x35,
+    ),
+    (Direction::Clientbound, 0x32, DestroyEntities::ID, 0x37),
+    (Direction::Clientbound, 0x36, EntityHeadLook::ID, 0x3B),
+    (Direction::Clientbound, 0x4C, EntityTeleport::ID, 0x56),
+];
+
+// 1.12.2 predates block states having ids of their own, and identifies them by block id and
+// metadata instead. These are the states our world is built from, anything else shows up as stone
+const LEGACY_BLOCKS: [(i32, i32); 6] = [
+    (0, 0),              // air
+    (1, 1 << 4),         // stone
+    (97, 17 << 4 | 3),   // stripped jungle log, which 1.12.2 doesn't have
+    (103, 162 << 4 | 1), // stripped dark oak log, likewise
+    (108, 17 << 4 | 12), // oak wood
+    (180, 18 << 4 | 2),  // birch leaves
+];
+const LEGACY_STONE: i32 = 1 << 4;
+
+// The global palettes hold more states in later versions
+const LEGACY_BITS_PER_BLOCK: u8 = 13;
+const BITS_PER_BLOCK: u8 = 14;
+
+// 1.14 clients are told how far out chunks are sent, which login does 20 chunks in each direction
+const VIEW_DISTANCE: i32 = 20;
+
+// 1.14 moved light out of chunks and into a packet of its own, which 1.13.2 has no id for
+const UPDATE_LIGHT_ID: i32 = 0x24;
+
+// Heights go from 0 to 256, so take 9 bits each
+const HEIGHTMAP_BITS: u8 = 9;
+
+impl ProtocolVersion {
+    pub const SUPPORTED: [ProtocolVersion; 3] = [
+        ProtocolVersion::V1_12_2,
+        ProtocolVersion::V1_13_2,
+        ProtocolVersion::V1_14_4,
+    ];
+    pub const NATIVE: ProtocolVersion = ProtocolVersion::V1_13_2;
+    pub const OLDEST: ProtocolVersion = ProtocolVersion::V1_12_2;
+    pub const NEWEST: ProtocolVersion = ProtocolVersion::V1_14_4;
+
+    pub fn from_number(number: i32) -> Option<ProtocolVersion> {
+        ProtocolVersion::SUPPORTED
+            .iter()
+            .copied()
+            .find(|version| version.number() == number)
+    }
+
+    // Clients on versions we don't speak are turned away at login, and nothing they can send
+    // before then differs between versions
+    pub fn negotiate(number: i32) -> ProtocolVersion {
+        ProtocolVersion::from_number(number).unwrap_or(ProtocolVersion::NATIVE)
+    }
+
+    pub fn number(self) -> i32 {
+        match self {
+            ProtocolVersion::V1_12_2 => 340,
+            ProtocolVersion::V1_13_2 => 404,
+            ProtocolVersion::V1_14_4 => 498,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ProtocolVersion::V1_12_2 => "1.12.2",
+            ProtocolVersion::V1_13_2 => "1.13.2",
+            ProtocolVersion::V1_14_4 => "1.14.4",
+        }
+    }
+
+    // What the status ping shows as our version
+    pub fn range() -> String {
+        format!(
+            "{}-{}",
+            ProtocolVersion::OLDEST.name(),
+            ProtocolVersion::NEWEST.name()
+        )
+    }
+
+    // Versions in between the oldest and newest aren't supported, so clients are told exactly
+    // which ones are, e.g. "1.12.2, 1.13.2 or 1.14.4"
+    pub fn names() -> String {
+        let names: Vec<&str> = ProtocolVersion::SUPPORTED
+            .iter()
+            .map(|version| version.name())
+            .collect();
+        let (last, rest) = names.split_last().unwrap();
+        format!("{} or {}", rest.join(", "), last)
+    }
+
+    // The id a packet is sent with, given the states it is registered for
+    pub fn id(self, registrations: &[(Direction, Status)], native_id: i32) -> i32 {
+        match registrations
+            .iter()
+            .find(|(_, status)| *status == Status::Play)
+        {
+            Some((direction, _)) => self.play_id(*direction, native_id),
+            None => native_id,
+        }
+    }
+
+    pub fn play_id(self, direction: Direction, native_id: i32) -> i32 {
+        PLAY_IDS
+            .iter()
+            .find(|row| row.0 == direction && row.2 == native_id)
+            .map_or(native_id, |row| self.column(row))
+    }
+
+    // Play packets we don't know of in this version could share an id with one we do in 1.13.2,
+    // so they are left unread
+    pub fn native_play_id(self, direction: Direction, id: i32) -> Option<i32> {
+        if self == ProtocolVersion::NATIVE {
+            return Some(id);
+        }
+        PLAY_IDS
+            .iter()
+            .find(|row| row.0 == direction && self.column(row) == id)
+            .map(|row| row.2)
+    }
+
+    fn column(self, row: &(Direction, i32, i32, i32)) -> i32 {
+        match self {
+            ProtocolVersion::V1_12_2 => row.1,
+            ProtocolVersion::V1_13_2 => row.2,
+            ProtocolVersion::V1_14_4 => row.3,
+        }
+    }
+
+    // Reads packets laid out differently than in 1.13.2, leaving the rest to be read as usual
+    pub fn read_fields(
+        self,
+        direction: Direction,
+        native_id: i32,
+        stream: &mut Cursor<Vec<u8>>,
+    ) -> Result<Option<Packet>, ProtocolError> {
+        Ok(Some(match (self, direction, native_id) {
+            // Only ever sent to clients, and the bot client skips them. They're passed over so
+            // that captures can still be read
+            (ProtocolVersion::V1_12_2, Direction::Clientbound, ChunkData::ID)
+            | (ProtocolVersion::V1_14_4, Direction::Clientbound, ChunkData::ID) => {
+                stream.read_to_end(&mut Vec::new())?;
+                Packet::Unknown
+            }
+            (ProtocolVersion::V1_12_2, Direction::Clientbound, BlockChange::ID) => {
+                Packet::BlockChange(BlockChange {
+                    location: stream.read_position()?,
+                    block_id: block_state(stream.read_var_int()?),
+                })
+            }
+            (ProtocolVersion::V1_14_4, Direction::Clientbound, BlockChange::ID) => {
+                Packet::BlockChange(BlockChange {
+                    location: read_position(stream)?,
+                    block_id: stream.read_var_int()?,
+                })
+            }
+            (ProtocolVersion::V1_14_4, Direction::Clientbound, JoinGame::ID) => {
+                let entity_id = stream.read_int()?;
+                let gamemode = stream.read_u_byte()?;
+                let dimension = stream.read_int()?;
+                let max_players = stream.read_u_byte()?;
+                let level_type = stream.read_string()?;
+                let _view_distance = stream.read_var_int()?;
+                Packet::JoinGame(JoinGame {
+                    entity_id,
+                    gamemode,
+                    dimension,
+                    // Difficulty is sent in a packet of its own from 1.14 on
+                    difficulty: 0,
+                    max_players,
+                    level_type,
+                    reduced_debug_info: stream.read_boolean()?,
+                })
+            }
+            (ProtocolVersion::V1_14_4, Direction::Serverbound, PlayerDigging::ID) => {
+                Packet::PlayerDigging(PlayerDigging {
+                    status: stream.read_var_int()?,
+                    location: read_position(stream)?,
+                    face: stream.read_byte()?,
+                })
+            }
+            (ProtocolVersion::V1_14_4, Direction::Serverbound, PlayerBlockPlacement::ID) => {
+                let hand = stream.read_var_int()?;
+                let location = read_position(stream)?;
+                let face = stream.read_var_int()?;
+                let placement = PlayerBlockPlacement {
+                    location,
+                    face,
+                    hand,
+                    cursor_x: stream.read_float()?,
+                    cursor_y: stream.read_float()?,
+                    cursor_z: stream.read_float()?,
+                };
+                let _inside_block = stream.read_boolean()?;
+                Packet::PlayerBlockPlacement(placement)
+            }
+            _ => return Ok(None),
+        }))
+    }
+
+    // Packets this version needs that 1.13.2 has no equivalent of, written as whole frames to be
+    // sent ahead of the packet they go with
+    pub fn preceding_frames(self, packet: &Packet) -> Vec<Vec<u8>> {
+        match (self, packet) {
+            (ProtocolVersion::V1_14_4, Packet::ChunkData(chunk)) => vec![update_light(chunk)],
+            _ => Vec::new(),
+        }
+    }
+
+    // Writes packets laid out differently than in 1.13.2, returning whether it did
+    pub fn write_fields(self, packet: &Packet, stream: &mut Cursor<Vec<u8>>) -> bool {
+        match (self, packet) {
+            (ProtocolVersion::V1_12_2, Packet::ChunkData(chunk)) => {
+                write_chunk(chunk, stream, None, |data| {
+                    let column = &chunk.column;
+                    for section in column.sections.iter().flatten() {
+                        write_legacy_chunk_section(data, section);
+                    }
+                    for biome in column.biomes.iter().flatten() {
+                        data.write_u_byte(*biome as u8);
+                    }
+                });
+            }
+            (ProtocolVersion::V1_14_4, Packet::ChunkData(chunk)) => {
+                let heightmaps = heightmaps(&chunk.column);
+                write_chunk(chunk, stream, Some(heightmaps), |data| {
+                    let column = &chunk.column;
+                    for section in column.sections.iter().flatten() {
+                        write_chunk_section(data, section);
+                    }
+                    if let Some(biomes) = &column.biomes {
+                        data.write_int_array(biomes.clone());
+                    }
+                });
+            }
+            (ProtocolVersion::V1_12_2, Packet::BlockChange(block_change)) => {
+                stream.write_position(block_change.location);
+                stream.write_var_int(legacy_block(block_change.block_id));
+            }
+            (ProtocolVersion::V1_14_4, Packet::BlockChange(block_change)) => {
+                write_position(stream, block_change.location);
+                stream.write_var_int(block_change.block_id);
+            }
+            (ProtocolVersion::V1_14_4, Packet::JoinGame(join_game)) => {
+                stream.write_int(join_game.entity_id);
+                stream.write_u_byte(join_game.gamemode);
+                stream.write_int(join_game.dimension);
+                stream.write_u_byte(join_game.max_players);
+                stream.write_string(join_game.level_type.clone());
+                stream.write_var_int(VIEW_DISTANCE);
+                stream.write_boolean(join_game.reduced_debug_info);
+            }
+            (ProtocolVersion::V1_14_4, Packet::PlayerDigging(player_digging)) => {
+                stream.write_var_int(player_digging.status);
+                write_position(stream, player_digging.location);
+                stream.write_byte(player_digging.face);
+            }
+            (ProtocolVersion::V1_14_4, Packet::PlayerBlockPlacement(placement)) => {
+                stream.write_var_int(placement.hand);
+                write_position(stream, placement.location);
+                stream.write_var_int(placement.face);
+                stream.write_float(placement.cursor_x);
+                stream.write_float(placement.cursor_y);
+                stream.write_float(placement.cursor_z);
+                stream.write_boolean(false);
+            }
+            _ => return false,
+        }
+        true
+    }
+}
+
+fn legacy_block(block_state: i32) -> i32 {
+    LEGACY_BLOCKS
+        .iter()
+        .find(|(state, _)| *state == block_state)
+        .map_or(LEGACY_STONE, |(_, legacy)| *legacy)
+}
+
+fn block_state(legacy_block: i32) -> i32 {
+    LEGACY_BLOCKS
+        .iter()
+        .find(|(_, legacy)| *legacy == legacy_block)
+        .map_or(1, |(state, _)| *state)
+}
+
+// Sections and biomes go in a length prefixed blob, which is laid out differently in each version
+fn write_chunk<F: FnOnce(&mut Vec<u8>)>(
+    chunk: &ChunkData,
+    stream: &mut Cursor<Vec<u8>>,
+    heightmaps: Option<Tag>,
+    data: F,
+) {
+    let mut blob = Vec::new();
+    data(&mut blob);
+    stream.write_int(chunk.chunk_x);
+    stream.write_int(chunk.chunk_z);
+    stream.write_boolean(chunk.column.biomes.is_some());
+    stream.write_var_int(chunk.column.primary_bit_mask());
+    if let Some(heightmaps) = heightmaps {
+        stream.write_nbt(Some(heightmaps));
+    }
+    stream.write_var_int(blob.len() as i32);
+    stream.write_all(&blob).unwrap();
+    stream.write_var_int(chunk.column.block_entities.len() as i32);
+    for block_entity in chunk.column.block_entities.iter() {
+        stream.write_block_entity(block_entity.clone());
+    }
+}
+
+// Clients only need the heightmap that decides where rain falls, which for each column is one above
+// the highest block in it, or 0 when there are none. Anything but air is taken to stop rain, which
+// holds for everything our worlds are built from
+fn heightmaps(column: &ChunkColumn) -> Tag {
+    let mut heights = vec![0; 256];
+    for (y, section) in column.sections.iter().enumerate() {
+        for (index, block_id) in section
+            .iter()
+            .flat_map(|section| section.block_ids.iter().enumerate())
+        {
+            if *block_id != 0 {
+                heights[index % 256] = (y * 16 + index / 256 + 1) as i32;
+            }
+        }
+    }
+    let motion_blocking = pack_blocks(&heights, HEIGHTMAP_BITS)
+        .into_iter()
+        .map(|long| long as i64)
+        .collect();
+    let mut heightmaps = BTreeMap::new();
+    heightmaps.insert(
+        String::from("MOTION_BLOCKING"),
+        Tag::LongArray(motion_blocking),
+    );
+    Tag::Compound(heightmaps)
+}
+
+// The light for every section the chunk has. The masks start from the section below the world, so
+// sections are a bit further up than in the chunk's. Those left out are lit from above by clients
+fn update_light(chunk: &ChunkData) -> Vec<u8> {
+    let mut frame = Vec::new();
+    frame.write_var_int(UPDATE_LIGHT_ID);
+    frame.write_var_int(chunk.chunk_x);
+    frame.write_var_int(chunk.chunk_z);
+    let mask = chunk.column.primary_bit_mask() << 1;
+    frame.write_var_int(mask);
+    frame.write_var_int(mask);
+    // Nothing is marked as having no light at all
+    frame.write_var_int(0);
+    frame.write_var_int(0);
+    let sections = || chunk.column.sections.iter().flatten();
+    for section in sections() {
+        frame.write_var_int(LIGHT_ARRAY_LENGTH as i32);
+        frame.write_all(&section.sky_light).unwrap();
+    }
+    for section in sections() {
+        frame.write_var_int(LIGHT_ARRAY_LENGTH as i32);
+        frame.write_all(&section.block_light).unwrap();
+    }
+    frame
+}
+
+// 1.12.2 sections always have a palette, which is left empty to use the global one
+fn write_legacy_chunk_section(stream: &mut Vec<u8>, section: &ChunkSection) {
+    let block_ids: Vec<i32> = section
+        .block_ids
+        .iter()
+        .map(|id| legacy_block(*id))
+        .collect();
+    let blocks = PalettedBlocks::new(&block_ids, LEGACY_BITS_PER_BLOCK);
+    stream.write_u_byte(blocks.bits_per_block);
+    let palette = blocks.palette.unwrap_or_default();
+    stream.write_var_int(palette.len() as i32);
+    stream.write_var_int_array(palette);
+    write_data_array(stream, blocks.data);
+    stream.write_all(&section.block_light).unwrap();
+    stream.write_all(&section.sky_light).unwrap();
+}
+
+// 1.14.4 sections count their blocks and leave light to a packet of its own
+fn write_chunk_section(stream: &mut Vec<u8>, section: &ChunkSection) {
+    let block_count = section.block_ids.iter().filter(|id| **id != 0).count();
+    stream.write_short(block_count as i16);
+    let blocks = PalettedBlocks::new(&section.block_ids, BITS_PER_BLOCK);
+    stream.write_u_byte(blocks.bits_per_block);
+    if let Some(palette) = blocks.palette {
+        stream.write_var_int(palette.len() as i32);
+        stream.write_var_int_array(palette);
+    }
+    write_data_array(stream, blocks.data);
+}
+
+fn write_data_array(stream: &mut Vec<u8>, data: Vec<u64>) {
+    stream.write_var_int(data.len() as i32);
+    data.into_iter()
+        .for_each(|long| stream.write_unsigned_long(long));
+}
+
+// 1.14 moved y to the bottom of the long
+fn write_position(stream: &mut Cursor<Vec<u8>>, position: BlockPosition) {
+    let x = (u64::from(position.x) & 0x03FF_FFFF) << 38;
+    let z = (u64::from(position.z) & 0x03FF_FFFF) << 12;
+    let y = u64::from(position.y) & 0xFFF;
+    stream.write_unsigned_long(x | z | y);
+}
+
+fn read_position(stream: &mut Cursor<Vec<u8>>) -> Result<BlockPosition, ProtocolError> {
+    let encoded_position = stream.read_unsigned_long()?;
+    Ok(BlockPosition {
+        x: (encoded_position >> 38) as u32,
+        y: (encoded_position & 0xFFF) as u32,
+        z: ((encoded_position >> 12) & 0x03FF_FFFF) as u32,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::minecraft_protocol::unpack_blocks;
+    use super::super::packet::{read, write};
+    use super::*;
+
+    fn round_trip(packet: Packet, direction: Direction, version: ProtocolVersion) -> Packet {
+        let mut bytes = Vec::new();
+        write(&mut bytes, packet, None, version);
+        read(
+            &mut Cursor::new(bytes[1..].to_vec()),
+            Status::Play,
+            direction,
+            version,
+        )
+        .unwrap()
+    }
+
+    fn four_states() -> Vec<i32> {
+        (0..4096).map(|i| [0, 1, 108, 180][i % 4]).collect()
+    }
+
+    fn global_states() -> Vec<i32> {
+        (0..4096).map(|i| i % 300).collect()
+    }
+
+    fn chunk(block_ids: Vec<i32>) -> ChunkData {
+        ChunkData {
+            chunk_x: 1,
+            chunk_z: -1,
+            column: ChunkColumn::new(vec![Some(ChunkSection::new(block_ids))]),
+        }
+    }
+
+    #[test]
+    fn play_ids_are_unique_in_every_version() {
+        for version in ProtocolVersion::SUPPORTED.iter() {
+            for (index, row) in PLAY_IDS.iter().enumerate() {
+                assert!(
+                    PLAY_IDS[index + 1..]
+                        .iter()
+                        .all(|other| other.0 != row.0
+                            || version.column(other) != version.column(row)),
+                    "{:?} uses {:#x} twice",
+                    version,
+                    version.column(row)
+                );
+                assert_eq!(
+                    Some(row.2),
+                    version.native_play_id(row.0, version.column(row))
+                );
+            }
+        }
+        // Lock difficulty, which would otherwise be read as a position update
+        assert_eq!(
+            None,
+            ProtocolVersion::V1_14_4.native_play_id(Direction::Serverbound, PlayerPosition::ID)
+        );
+    }
+
+    #[test]
+    fn packets_changed_in_other_versions_come_back_as_they_were() {
+        let join_game = Packet::JoinGame(JoinGame {
+            entity_id: 3,
+            gamemode: 1,
+            dimension: 0,
+            difficulty: 0,
+            max_players: 50,
+            level_type: String::from("default"),
+            reduced_debug_info: false,
+        });
+        let placement = Packet::PlayerBlockPlacement(PlayerBlockPlacement {
+            location: BlockPosition { x: 4, y: 16, z: 9 },
+            face: 1,
+            hand: 0,
+            cursor_x: 0.5,
+            cursor_y: 1.0,
+            cursor_z: 0.5,
+        });
+        let digging = Packet::PlayerDigging(PlayerDigging {
+            status: 2,
+            location: BlockPosition { x: 4, y: 16, z: 9 },
+            face: 1,
+        });
+        let block_change = Packet::BlockChange(BlockChange {
+            location: BlockPosition { x: 4, y: 17, z: 9 },
+            block_id: 1,
+        });
+        for version in ProtocolVersion::SUPPORTED.iter().copied() {
+            assert_eq!(
+                join_game,
+                round_trip(join_game.clone(), Direction::Clientbound, version)
+            );
+            assert_eq!(
+                placement,
+                round_trip(placement.clone(), Direction::Serverbound, version)
+            );
+            assert_eq!(
+                digging,
+                round_trip(digging.clone(), Direction::Serverbound, version)
+            );
+            assert_eq!(
+                block_change,
+                round_trip(block_change.clone(), Direction::Clientbound, version)
+            );
+        }
+    }
+
+    #[test]
+    fn block_positions_pack_y_last_from_1_14() {
+        let mut bytes = Cursor::new(Vec::new());
+        write_position(&mut bytes, BlockPosition { x: 1, y: 2, z: 3 });
+        assert_eq!(
+            1 << 38 | 3 << 12 | 2,
+            u64::from_be_bytes({
+                let mut long = [0; 8];
+                long.copy_from_slice(bytes.get_ref());
+                long
+            })
+        );
+    }
+
+    #[test]
+    fn sections_are_encoded_as_they_are_natively() {
+        for section in [
+            ChunkSection::new(four_states()),
+            ChunkSection::new(global_states()),
+        ]
+        .iter()
+        {
+            let mut native = Vec::new();
+            native.write_chunk_section(section.clone());
+            let mut section_1_14 = Vec::new();
+            write_chunk_section(&mut section_1_14, section);
+            // 1.14 leads with a block count, and the native section ends in light
+            assert_eq!(&native[..native.len() - 4096], &section_1_14[2..]);
+        }
+    }
+
+    #[test]
+    fn chunks_are_laid_out_for_each_version() {
+        let data_size = |version: ProtocolVersion, chunk: ChunkData| {
+            let mut bytes = Cursor::new(Vec::new());
+            assert!(version.write_fields(&Packet::ChunkData(chunk), &mut bytes));
+            let mut bytes = Cursor::new(bytes.into_inner());
+            bytes.set_position(4 + 4 + 1 + 1);
+            if version == ProtocolVersion::V1_14_4 {
+                bytes.read_nbt().unwrap();
+            }
+            bytes.read_var_int().unwrap()
+        };
+        // Bits per block, four palette entries, 256 longs, light and a byte per biome
+        assert_eq!(
+            1 + 1 + 6 + 2 + 256 * 8 + 4096 + 256,
+            data_size(ProtocolVersion::V1_12_2, chunk(four_states()))
+        );
+        // A block count, bits per block, four palette entries, 256 longs and an int per biome
+        assert_eq!(
+            2 + 1 + 1 + 5 + 2 + 256 * 8 + 1024,
+            data_size(ProtocolVersion::V1_14_4, chunk(four_states()))
+        );
+        // The same without a palette, and 896 longs
+        assert_eq!(
+            2 + 1 + 2 + 896 * 8 + 1024,
+            data_size(ProtocolVersion::V1_14_4, chunk(global_states()))
+        );
+
+        assert!(!ProtocolVersion::NATIVE.write_fields(
+            &Packet::ChunkData(chunk(four_states())),
+            &mut Cursor::new(Vec::new())
+        ));
+    }
+
+    #[test]
+    fn chunks_1_14_are_lit_and_have_heightmaps() {
+        let mut section = ChunkSection::new(four_states());
+        section.sky_light = vec![0x12; LIGHT_ARRAY_LENGTH];
+        let chunk = chunk(section.block_ids.clone());
+        let chunk = ChunkData {
+            column: ChunkColumn::new(vec![None, None, Some(section)]),
+            ..chunk
+        };
+
+        // Light goes ahead of the chunk, with the third section lit as the fourth
+        let frames = ProtocolVersion::V1_14_4.preceding_frames(&Packet::ChunkData(chunk.clone()));
+        assert_eq!(1, frames.len());
+        let mut light = Cursor::new(frames[0].clone());
+        assert_eq!(UPDATE_LIGHT_ID, light.read_var_int().unwrap());
+        assert_eq!(1, light.read_var_int().unwrap());
+        assert_eq!(-1, light.read_var_int().unwrap());
+        assert_eq!(1 << 3, light.read_var_int().unwrap());
+        assert_eq!(1 << 3, light.read_var_int().unwrap());
+        assert_eq!(0, light.read_var_int().unwrap());
+        assert_eq!(0, light.read_var_int().unwrap());
+        assert_eq!(LIGHT_ARRAY_LENGTH as i32, light.read_var_int().unwrap());
+        assert_eq!(0x12, light.read_u_byte().unwrap());
+        assert!(ProtocolVersion::NATIVE
+            .preceding_frames(&Packet::ChunkData(chunk.clone()))
+            .is_empty());
+
+        // Every fourth column of blocks is air all the way up
+        let mut bytes = Cursor::new(Vec::new());
+        ProtocolVersion::V1_14_4.write_fields(&Packet::ChunkData(chunk), &mut bytes);
+        bytes.set_position(4 + 4 + 1 + 1);
+        let heightmaps = bytes.read_nbt().unwrap().unwrap();
+        let heights = match heightmaps.get("MOTION_BLOCKING") {
+            Some(Tag::LongArray(longs)) => {
+                let longs: Vec<u64> = longs.iter().map(|long| *long as u64).collect();
+                unpack_blocks(&longs, HEIGHTMAP_BITS, 256)
+            }
+            tag => panic!("Unexpected heightmap {:?}", tag),
+        };
+        assert_eq!(&[0, 48, 48, 48, 0], &heights[..5]);
+    }
+
+    #[test]
+    fn legacy_clients_see_unknown_blocks_as_stone() {
+        assert_eq!(17 << 4 | 12, legacy_block(108));
+        assert_eq!(LEGACY_STONE, legacy_block(4000));
+        assert_eq!(108, block_state(17 << 4 | 12));
+    }
+}
@@ -0,0 +1,167 @@
+// In online mode the client tells the session server it is joining us before answering our
+// encryption request, and we ask the session server whether it did. The check sits behind the
+// SessionVerifier trait so nodes can be run against a local list of players instead of Mojang
+
+use super::minecraft_types::ProfileProperty;
+
+use md5::{Digest, Md5};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::time::Duration;
+use uuid::{Builder, Uuid, Variant, Version};
+
+const MOJANG_HAS_JOINED_URL: &str = "https://sessionserver.mojang.com/session/minecraft/hasJoined";
+
+// Clients give up on logging in after 30 seconds, so there's no point waiting on Mojang any longer
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(20);
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct GameProfile {
+    pub id: Uuid,
+    pub name: String,
+    #[serde(default)]
+    pub properties: Vec<ProfileProperty>,
+}
+
+// Offline mode players get the same UUID a vanilla server would give them, a version 3 UUID of
+// "OfflinePlayer:<name>", so they keep their identity across restarts and across nodes
+pub fn offline_uuid(username: &str) -> Uuid {
+    let digest = Md5::digest(format!("OfflinePlayer:{}", username).as_bytes());
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&digest);
+    Builder::from_bytes(bytes)
+        .set_variant(Variant::RFC4122)
+        .set_version(Version::Md5)
+        .build()
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    NotJoined(String),
+    Unavailable(String),
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SessionError::NotJoined(username) => {
+                write!(f, "{} has not joined through the session server", username)
+            }
+            SessionError::Unavailable(error) => {
+                write!(f, "session server unavailable: {}", error)
+            }
+        }
+    }
+}
+
+pub trait SessionVerifier: Send + Sync {
+    fn has_joined(&self, username: &str, server_hash: &str) -> Result<GameProfile, SessionError>;
+}
+
+pub struct MojangSessionVerifier {
+    url: String,
+    agent: ureq::Agent,
+}
+
+impl Default for MojangSessionVerifier {
+    fn default() -> MojangSessionVerifier {
+        MojangSessionVerifier::new()
+    }
+}
+
+impl MojangSessionVerifier {
+    pub fn new() -> MojangSessionVerifier {
+        MojangSessionVerifier {
+            url: String::from(MOJANG_HAS_JOINED_URL),
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(CONNECT_TIMEOUT)
+                .timeout(REQUEST_TIMEOUT)
+                .build(),
+        }
+    }
+}
+
+impl SessionVerifier for MojangSessionVerifier {
+    fn has_joined(&self, username: &str, server_hash: &str) -> Result<GameProfile, SessionError> {
+        let response = self
+            .agent
+            .get(&self.url)
+            .query("username", username)
+            .query("serverId", server_hash)
+            .call()
+            .map_err(|e| SessionError::Unavailable(e.to_string()))?;
+        // The session server answers with an empty 204 when the player never joined
+        if response.status() != 200 {
+            return Err(SessionError::NotJoined(username.to_string()));
+        }
+        response
+            .into_json()
+            .map_err(|e| SessionError::Unavailable(e.to_string()))
+    }
+}
+
+// Stand-in for the session server, backed by a JSON list of the profiles allowed to join:
+//    [{ "id": "069a79f4-44e9-4726-a5be-fca90e38aaf5", "name": "Notch" }]
+// There is no session to check the hash against, so any client using an allowed name gets in
+pub struct LocalSessionVerifier {
+    profiles: HashMap<String, GameProfile>,
+}
+
+impl LocalSessionVerifier {
+    pub fn new(profiles: Vec<GameProfile>) -> LocalSessionVerifier {
+        LocalSessionVerifier {
+            profiles: profiles
+                .into_iter()
+                .map(|profile| (profile.name.clone(), profile))
+                .collect(),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<LocalSessionVerifier, String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let profiles = serde_json::from_str(&contents).map_err(|e| e.to_string())?;
+        Ok(LocalSessionVerifier::new(profiles))
+    }
+}
+
+impl SessionVerifier for LocalSessionVerifier {
+    fn has_joined(&self, username: &str, _server_hash: &str) -> Result<GameProfile, SessionError> {
+        self.profiles
+            .get(username)
+            .cloned()
+            .ok_or_else(|| SessionError::NotJoined(username.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_verifier_only_knows_listed_players() {
+        let profiles: Vec<GameProfile> = serde_json::from_str(
+            r#"[{ "id": "069a79f444e94726a5befca90e38aaf5", "name": "Notch" }]"#,
+        )
+        .unwrap();
+        let verifier = LocalSessionVerifier::new(profiles);
+
+        let profile = verifier.has_joined("Notch", "hash").unwrap();
+        assert_eq!(
+            "069a79f4-44e9-4726-a5be-fca90e38aaf5",
+            profile.id.to_hyphenated().to_string()
+        );
+        assert!(verifier.has_joined("jeb_", "hash").is_err());
+    }
+
+    #[test]
+    fn offline_uuids_match_vanilla() {
+        assert_eq!(
+            "b50ad385-829d-3141-a216-7e7d7539ba7f",
+            offline_uuid("Notch").to_hyphenated().to_string()
+        );
+        assert_eq!(offline_uuid("Steve"), offline_uuid("Steve"));
+    }
+}
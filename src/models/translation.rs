@@ -6,6 +6,8 @@ pub enum TranslationUpdates {
     EntityIdBlock(i32),
     XOrigin(i32),
     ZOrigin(i32),
+    Compression(i32),
+    ProtocolVersion(i32),
     NoChange,
 }
 
@@ -13,6 +15,14 @@ pub enum TranslationUpdates {
 pub struct TranslationInfo {
     pub state: i32,
     pub map: Map,
+    pub compression_threshold: Option<i32>,
+    pub protocol_version: i32,
+}
+
+impl Default for TranslationInfo {
+    fn default() -> TranslationInfo {
+        TranslationInfo::new()
+    }
 }
 
 impl TranslationInfo {
@@ -20,6 +30,8 @@ impl TranslationInfo {
         TranslationInfo {
             state: 0,
             map: Map::new(Position { x: 0, z: 0 }, 0),
+            compression_threshold: None,
+            protocol_version: 0,
         }
     }
 
@@ -37,6 +49,12 @@ impl TranslationInfo {
             TranslationUpdates::ZOrigin(z) => {
                 self.map.position.z = *z;
             }
+            TranslationUpdates::Compression(threshold) => {
+                self.compression_threshold = Some(*threshold);
+            }
+            TranslationUpdates::ProtocolVersion(protocol_version) => {
+                self.protocol_version = *protocol_version;
+            }
             TranslationUpdates::NoChange => {}
         }
     }
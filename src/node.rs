@@ -0,0 +1,226 @@
+// A running node: its services, the network serving its players and peers, and the channel that
+// asks it to shut down. The patchwork binary runs a single node, while the integration tests run
+// several side by side in the same process
+
+use super::config::Config;
+use super::interfaces;
+use super::interfaces::messenger::Messenger;
+use super::interfaces::patchwork::PatchworkState;
+use super::models::capture::Capture;
+use super::models::map::Peer;
+use super::models::minecraft_types::ChatComponent;
+use super::models::session::{LocalSessionVerifier, MojangSessionVerifier, SessionVerifier};
+use super::server;
+use super::server::Network;
+use super::services;
+use super::services::instance::{OnPanic, ServiceInstance};
+
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::task::JoinHandle;
+
+// How long players and peers get to receive their last packets once the node is shutting down
+const SHUTDOWN_FLUSH_LIMIT: Duration = Duration::from_secs(5);
+
+pub struct Node {
+    runtime: Runtime,
+    network: Network,
+    listener: JoinHandle<()>,
+    port: u16,
+    shutdown_sender: Sender<()>,
+    shutdown_requests: Receiver<()>,
+    player_state: ServiceInstance<interfaces::player::Operations>,
+    block_state: ServiceInstance<interfaces::block::Operations>,
+    patchwork_state: ServiceInstance<interfaces::patchwork::Operations>,
+    messenger: ServiceInstance<interfaces::messenger::Operations>,
+    inbound_packet_processor: ServiceInstance<interfaces::packet_processor::Operations>,
+    connection_service: ServiceInstance<interfaces::connection::Operations>,
+    login_service: ServiceInstance<interfaces::login::Operations>,
+    keep_alive: ServiceInstance<interfaces::keep_alive::Operations>,
+}
+
+impl Node {
+    // The node listens before any service starts, so a port that's taken is reported before
+    // anything else happens. Port 0 picks any free port, see port()
+    pub fn start(config: Config) -> Result<Node, String> {
+        let session_verifier =
+            session_verifier(&config).map_err(|e| format!("Could not load session file: {}", e))?;
+        let listener = server::bind(&config).map_err(|e| {
+            format!(
+                "Could not listen on {}:{}: {}",
+                config.bind_address, config.port, e
+            )
+        })?;
+        let port = listener
+            .local_addr()
+            .map_err(|e| format!("Could not listen: {}", e))?
+            .port();
+        let capture = match &config.capture_file {
+            Some(path) => Some(
+                Capture::create(path)
+                    .map_err(|e| format!("Could not create capture file {:?}: {}", path, e))?,
+            ),
+            None => None,
+        };
+        let runtime =
+            server::runtime().map_err(|e| format!("Could not start the network: {}", e))?;
+        let network = Network::new(runtime.handle().clone());
+
+        let (shutdown_sender, shutdown_requests) = channel();
+
+        // Services that only hold on to work in progress are restarted when they panic. Losing the
+        // state of any of the others would leave the node out of step with its players and peers
+        define_services!(
+        shutdown: shutdown_sender,
+        (
+            module: services::player::start,
+            name: player_state,
+            dependencies: [messenger, block_state, patchwork_state, login_service],
+            on_panic: Shutdown,
+            arguments: [config]
+        ),
+        (
+            module: services::block::start,
+            name: block_state,
+            dependencies: [messenger],
+            on_panic: Shutdown
+        ),
+        (
+            module: services::patchwork::start,
+            name: patchwork_state,
+            dependencies: [messenger, inbound_packet_processor, player_state, block_state],
+            on_panic: Shutdown,
+            arguments: [config, network]
+        ),
+        (
+            module: services::messenger::start,
+            name: messenger,
+            dependencies: [],
+            on_panic: Shutdown,
+            arguments: [config, capture]
+        ),
+        (
+            module: services::packet_processor::start_inbound,
+            name: inbound_packet_processor,
+            dependencies: [messenger, player_state, block_state, patchwork_state, login_service, connection_service, keep_alive],
+            on_panic: Shutdown,
+            arguments: [capture]
+        ),
+        (
+            module: services::connection::start,
+            name: connection_service,
+            dependencies: [messenger, player_state, patchwork_state, inbound_packet_processor, login_service, keep_alive],
+            on_panic: Restart
+        ),
+        (
+            module: services::login::start,
+            name: login_service,
+            dependencies: [messenger, player_state, inbound_packet_processor, keep_alive],
+            on_panic: Restart,
+            arguments: [config, session_verifier]
+        ),
+        (
+            module: services::keep_alive::start,
+            name: keep_alive,
+            dependencies: [messenger, player_state, connection_service],
+            on_panic: Shutdown
+        )
+            );
+
+        trace!("Services Started");
+
+        let listener = network.listen(
+            listener,
+            inbound_packet_processor.sender(),
+            connection_service.sender(),
+            messenger.sender(),
+        );
+
+        let node = Node {
+            runtime,
+            network,
+            listener,
+            port,
+            shutdown_sender,
+            shutdown_requests,
+            player_state,
+            block_state,
+            patchwork_state,
+            messenger,
+            inbound_packet_processor,
+            connection_service,
+            login_service,
+            keep_alive,
+        };
+        if config.peers.is_empty() {
+            info!("No peers configured, running standalone");
+        }
+        for peer in config.peers {
+            node.add_peer(peer);
+        }
+        Ok(node)
+    }
+
+    // Gives the node a map for the peer, which is connected to in the background
+    pub fn add_peer(&self, peer: Peer) {
+        info!("Adding peer {}:{}", peer.address, peer.port);
+        self.patchwork_state.sender().new_map(peer);
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    // Anything sent on this asks the node to shut down, see wait_for_shutdown_request
+    pub fn shutdown_sender(&self) -> Sender<()> {
+        self.shutdown_sender.clone()
+    }
+
+    pub fn player_state(&self) -> Sender<interfaces::player::Operations> {
+        self.player_state.sender()
+    }
+
+    pub fn patchwork_state(&self) -> Sender<interfaces::patchwork::Operations> {
+        self.patchwork_state.sender()
+    }
+
+    pub fn wait_for_shutdown_request(&self) {
+        let _ = self.shutdown_requests.recv();
+    }
+
+    pub fn stop(mut self) {
+        info!("Shutting down");
+        self.network.stop_listening();
+        let _ = self.network.block_on(self.listener);
+        self.messenger
+            .sender()
+            .disconnect_all(ChatComponent::text("Server closed"));
+
+        // Services are stopped from the edges inwards, so anything a stopping service sends on its
+        // way out is still handled. The messenger goes last to flush whatever is left to write
+        self.connection_service.stop();
+        self.login_service.stop();
+        self.keep_alive.stop();
+        self.patchwork_state.stop();
+        self.inbound_packet_processor.stop();
+        self.player_state.stop();
+        self.block_state.stop();
+        self.messenger.stop();
+        self.network.flush(SHUTDOWN_FLUSH_LIMIT);
+        self.runtime.shutdown_background();
+        info!("Stopped");
+    }
+}
+
+// Offline mode has no session verifier, and lets players in under whatever name they give
+fn session_verifier(config: &Config) -> Result<Option<Arc<dyn SessionVerifier>>, String> {
+    if !config.online_mode {
+        return Ok(None);
+    }
+    Ok(Some(match &config.session_file {
+        Some(path) => Arc::new(LocalSessionVerifier::load(path)?),
+        None => Arc::new(MojangSessionVerifier::new()),
+    }))
+}
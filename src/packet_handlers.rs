@@ -10,10 +10,15 @@ pub mod initiation_protocols;
 pub mod packet_router;
 pub mod peer_subscription;
 
-use super::constants;
+use super::config;
 use super::models::map::Peer;
 use super::models::minecraft_types;
 use super::models::packet;
+use super::models::protocol_error;
+use super::models::protocol_version;
 use super::models::translation;
 
 use super::interfaces;
+
+#[cfg(test)]
+use super::test_support;
@@ -12,15 +12,16 @@ pub fn route_packet<PA: PatchworkState>(p: Packet, conn_id: Uuid, patchwork_stat
                 conn_id
             );
             let mut token_stream = chat_message.message.as_str().split(' ');
-            match token_stream.next() {
-                Some("/connect") => {
-                    connect(&mut token_stream, patchwork_state).ok();
-                }
-                _ => {}
+            if let Some("/connect") = token_stream.next() {
+                connect(&mut token_stream, patchwork_state).ok();
             }
         }
         _ => {
-            panic!("Chat Message Router received unexpected packet {:?}", p);
+            warn!(
+                "Chat Message Router ignoring unexpected packet {:?} from {:?}",
+                p.debug_print_type(),
+                conn_id
+            );
         }
     }
 }
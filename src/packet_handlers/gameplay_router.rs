@@ -59,7 +59,130 @@ pub fn route_packet<P: PlayerState, PA: PatchworkState, B: BlockState>(
         }
         Packet::Unknown => (),
         _ => {
-            panic!("Gameplay router received unexpected packet {:?}", p);
+            warn!(
+                "Gameplay router ignoring unexpected packet {:?} from {:?}",
+                p.debug_print_type(),
+                conn_id
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::interfaces::{block, patchwork, player};
+    use super::super::minecraft_types::BlockPosition;
+    use super::super::packet::{ChatMessage, PlayerDigging, PlayerLook, PlayerPosition};
+    use super::*;
+
+    struct Services {
+        player_state: player::Recorder,
+        patchwork_state: patchwork::Recorder,
+        block_state: block::Recorder,
+    }
+
+    fn route(packet: Packet, conn_id: Uuid) -> Services {
+        let services = Services {
+            player_state: player::Recorder::new(),
+            patchwork_state: patchwork::Recorder::new(),
+            block_state: block::Recorder::new(),
+        };
+        route_packet(
+            packet,
+            conn_id,
+            services.player_state.clone(),
+            services.patchwork_state.clone(),
+            services.block_state.clone(),
+        );
+        services
+    }
+
+    #[test]
+    fn movement_goes_to_player_state() {
+        let conn_id = Uuid::new_v4();
+        let services = route(
+            Packet::PlayerPosition(PlayerPosition {
+                x: 1.0,
+                feet_y: 2.0,
+                z: 3.0,
+                on_ground: true,
+            }),
+            conn_id,
+        );
+        match services.player_state.take().as_slice() {
+            [player::Operations::MoveAndLook(msg)] => {
+                assert_eq!(conn_id, msg.conn_id);
+                let position = msg.new_position.unwrap();
+                assert_eq!((1.0, 2.0, 3.0), (position.x, position.y, position.z));
+                assert!(msg.new_angle.is_none());
+            }
+            other => panic!("expected a single move, got {:?}", other),
         }
+        assert!(services.block_state.take().is_empty());
+    }
+
+    #[test]
+    fn looking_around_leaves_the_position_alone() {
+        let services = route(
+            Packet::PlayerLook(PlayerLook {
+                yaw: 90.0,
+                pitch: 45.0,
+                on_ground: true,
+            }),
+            Uuid::new_v4(),
+        );
+        match services.player_state.take().as_slice() {
+            [player::Operations::MoveAndLook(msg)] => {
+                assert!(msg.new_position.is_none());
+                let angle = msg.new_angle.unwrap();
+                assert_eq!((90.0, 45.0), (angle.yaw, angle.pitch));
+            }
+            other => panic!("expected a single look, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn digging_goes_to_block_state() {
+        let services = route(
+            Packet::PlayerDigging(PlayerDigging {
+                status: 2,
+                location: BlockPosition { x: 1, y: 2, z: 3 },
+                face: 1,
+            }),
+            Uuid::new_v4(),
+        );
+        assert!(matches!(
+            services.block_state.take().as_slice(),
+            [block::Operations::BreakBlock(_)]
+        ));
+        assert!(services.player_state.take().is_empty());
+    }
+
+    #[test]
+    fn connect_command_adds_a_peer_map() {
+        let services = route(
+            Packet::ChatMessage(ChatMessage {
+                message: String::from("/connect 10.0.0.2 25566"),
+            }),
+            Uuid::new_v4(),
+        );
+        match services.patchwork_state.take().as_slice() {
+            [patchwork::Operations::New(msg)] => {
+                assert_eq!("10.0.0.2", msg.peer.address);
+                assert_eq!(25566, msg.peer.port);
+            }
+            other => panic!("expected a new map, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn other_chat_is_ignored() {
+        let services = route(
+            Packet::ChatMessage(ChatMessage {
+                message: String::from("/connect nowhere"),
+            }),
+            Uuid::new_v4(),
+        );
+        assert!(services.patchwork_state.take().is_empty());
     }
 }
@@ -10,8 +10,13 @@ pub mod client_ping;
 pub mod handshake;
 pub mod login;
 
-use super::constants;
+use super::config;
 use super::interfaces;
 use super::minecraft_types;
 use super::packet;
+use super::protocol_error;
+use super::protocol_version;
 use super::translation;
+
+#[cfg(test)]
+use super::test_support;
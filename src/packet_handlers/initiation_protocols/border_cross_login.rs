@@ -12,10 +12,12 @@ pub fn border_cross_login<P: PlayerState>(
         Packet::BorderCrossLogin(packet) => {
             let player = Player {
                 conn_id,
-                uuid: Uuid::new_v4(),
+                uuid: Uuid::from_u128(packet.uuid),
                 name: packet.username,
+                properties: packet.properties,
                 //Hardcoded to assume that 950-1000 is the range used for this peer's anchors
                 entity_id: 950 + packet.entity_id,
+                ping: 0,
                 position: Position {
                     x: packet.x,
                     y: packet.feet_y,
@@ -1,31 +1,26 @@
-use super::constants::{SERVER_DESCRIPTION, SERVER_PROTOCOL, SERVER_VERSION};
+use super::config::Config;
 use super::interfaces::messenger::Messenger;
-use super::interfaces::player::PlayerState;
-use super::minecraft_types::{Description, Version};
+use super::interfaces::player::{Player, PlayerState};
+use super::minecraft_types::{
+    Description, PingPlayersInfo, PingSamplePlayer, StatusResponse, Version,
+};
 use super::packet;
 use super::packet::Packet;
+use super::protocol_version::ProtocolVersion;
 use super::translation::TranslationUpdates;
 use uuid::Uuid;
 
-// Called when client pings the server
+// Called when client pings the server. The player service answers status requests itself, since
+// it has the players to hand and waiting on it here would hold up every connection's packets
 pub fn handle_client_ping_packet<M: Messenger, P: PlayerState>(
     p: Packet,
     conn_id: Uuid,
+    protocol_version: i32,
     messenger: M,
     player_state: P,
 ) -> TranslationUpdates {
     match p {
-        Packet::StatusRequest(_) => {
-            let version = Version {
-                name: SERVER_VERSION.to_string(),
-                protocol: SERVER_PROTOCOL,
-            };
-            let description = Description {
-                text: SERVER_DESCRIPTION.to_string(),
-            };
-
-            player_state.status_response(conn_id, version, description);
-        }
+        Packet::StatusRequest(_) => player_state.report_status(conn_id, protocol_version),
         Packet::Ping(ping) => {
             let pong = packet::Pong {
                 payload: ping.payload,
@@ -36,3 +31,117 @@ pub fn handle_client_ping_packet<M: Messenger, P: PlayerState>(
     }
     TranslationUpdates::NoChange
 }
+
+// Clients on a version we speak are shown their own, so that they don't list us as incompatible
+pub fn status_response(players: Vec<&Player>, protocol_version: i32, config: &Config) -> Packet {
+    let status_response = StatusResponse {
+        version: Version {
+            name: ProtocolVersion::range(),
+            protocol: ProtocolVersion::negotiate(protocol_version).number() as u16,
+        },
+        players: PingPlayersInfo {
+            max: config.max_players,
+            online: players.len() as u16,
+            sample: players
+                .into_iter()
+                .map(|player| PingSamplePlayer {
+                    name: player.name.clone(),
+                    id: player.uuid.to_hyphenated().to_string(),
+                })
+                .collect(),
+        },
+        description: Description {
+            text: config.motd.clone(),
+        },
+    };
+    Packet::StatusResponse(packet::StatusResponse {
+        json_response: serde_json::to_string(&status_response).unwrap(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::interfaces::{messenger, player};
+    use super::super::packet::{Ping, StatusRequest};
+    use super::super::test_support;
+    use super::*;
+
+    fn status(packet: Packet) -> serde_json::Value {
+        match packet {
+            Packet::StatusResponse(response) => {
+                serde_json::from_str(&response.json_response).unwrap()
+            }
+            other => panic!("expected a status response, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn status_requests_are_answered_by_the_player_service() {
+        let messenger = messenger::Recorder::new();
+        let player_state = player::Recorder::new();
+        let conn_id = Uuid::new_v4();
+        handle_client_ping_packet(
+            Packet::StatusRequest(StatusRequest {}),
+            conn_id,
+            404,
+            messenger.clone(),
+            player_state.clone(),
+        );
+        assert!(messenger.take().is_empty());
+        match player_state.take().as_slice() {
+            [player::Operations::ReportStatus(msg)] => {
+                assert_eq!(conn_id, msg.conn_id);
+                assert_eq!(404, msg.protocol_version);
+            }
+            other => panic!("expected a status report, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn status_lists_online_players() {
+        let alex = test_support::player("Alex");
+        let status = status(status_response(vec![&alex], 404, &test_support::config()));
+        assert_eq!(1, status["players"]["online"]);
+        assert_eq!("Alex", status["players"]["sample"][0]["name"]);
+        assert_eq!(
+            alex.uuid.to_hyphenated().to_string(),
+            status["players"]["sample"][0]["id"]
+        );
+        assert_eq!(404, status["version"]["protocol"]);
+    }
+
+    #[test]
+    fn status_shows_the_supported_versions() {
+        let version = |protocol_version| {
+            status(status_response(
+                Vec::new(),
+                protocol_version,
+                &test_support::config(),
+            ))["version"]
+                .clone()
+        };
+        assert_eq!("1.12.2-1.14.4", version(340)["name"]);
+        assert_eq!(340, version(340)["protocol"]);
+        assert_eq!(498, version(498)["protocol"]);
+        assert_eq!(404, version(578)["protocol"]);
+    }
+
+    #[test]
+    fn pings_are_ponged() {
+        let messenger = messenger::Recorder::new();
+        handle_client_ping_packet(
+            Packet::Ping(Ping { payload: 42 }),
+            Uuid::new_v4(),
+            404,
+            messenger.clone(),
+            player::Recorder::new(),
+        );
+        match messenger.take().pop() {
+            Some(messenger::Operations::Send(msg)) => match msg.packet {
+                Packet::Pong(pong) => assert_eq!(42, pong.payload),
+                other => panic!("expected a pong, got {:?}", other),
+            },
+            other => panic!("expected a packet, got {:?}", other),
+        }
+    }
+}
@@ -1,10 +1,21 @@
 use super::packet::Packet;
+use super::protocol_error::ProtocolError;
 use super::translation::TranslationUpdates;
 
-// Called upon handshake
-pub fn handle_handshake_packet(p: Packet) -> TranslationUpdates {
-    TranslationUpdates::State(match p.clone() {
-        Packet::Handshake(handshake) => handshake.next_state,
-        _ => panic!("Invalid packet {:?}", p),
-    })
+// Called upon handshake. Clients may only ask for the status or login states, peers for one of the
+// peer states. The protocol version is kept so login can turn away clients we can't talk to
+pub fn handle_handshake_packet(p: Packet) -> Result<Vec<TranslationUpdates>, ProtocolError> {
+    match p {
+        Packet::Handshake(handshake) => match handshake.next_state {
+            1 | 2 | 4..=6 => Ok(vec![
+                TranslationUpdates::ProtocolVersion(handshake.protocol_version),
+                TranslationUpdates::State(handshake.next_state),
+            ]),
+            next_state => Err(ProtocolError::InvalidState(next_state)),
+        },
+        _ => Err(ProtocolError::UnexpectedPacket {
+            state: 0,
+            packet: String::from(p.debug_print_type()),
+        }),
+    }
 }
@@ -1,30 +1,70 @@
 use super::interfaces::block::BlockState;
+use super::interfaces::login::LoginService;
 use super::interfaces::messenger::{Messenger, SubscriberType};
 use super::interfaces::patchwork::PatchworkState;
-use super::interfaces::player::{Angle, Player, PlayerState, Position};
+use super::interfaces::player::{Player, PlayerState};
 use super::interfaces::report::report;
-use super::minecraft_types::ChunkSection;
-use super::packet;
+use super::minecraft_types::{ChatComponent, ChunkColumn, ChunkSection};
 use super::packet::{ChunkData, Packet};
+use super::protocol_error::ProtocolError;
+use super::protocol_version::ProtocolVersion;
 use super::translation::TranslationUpdates;
 use rand::prelude::*;
 use uuid::Uuid;
 
-pub fn handle_login_packet<M: Messenger + Clone, P: PlayerState + Clone>(
+// The login service takes it from here, and moves the connection into the play state once the
+// player has been let in. Clients on a protocol version we don't speak are turned away before that
+pub fn handle_login_packet<M: Messenger, L: LoginService>(
     p: Packet,
     conn_id: Uuid,
+    protocol_version: i32,
     messenger: M,
-    player_state: P,
-) -> TranslationUpdates {
+    login_service: L,
+) -> Result<TranslationUpdates, ProtocolError> {
     match p {
         Packet::LoginStart(login_start) => {
-            confirm_login(conn_id, messenger, login_start, player_state);
-            TranslationUpdates::State(3)
+            if ProtocolVersion::from_number(protocol_version).is_some() {
+                login_service.start(conn_id, login_start.username);
+            } else {
+                messenger.disconnect(conn_id, outdated(protocol_version));
+            }
+        }
+        Packet::EncryptionResponse(encryption_response) => {
+            login_service.encryption_response(
+                conn_id,
+                encryption_response.shared_secret,
+                encryption_response.verify_token,
+            );
         }
         _ => {
-            panic!("Login failed");
+            return Err(ProtocolError::UnexpectedPacket {
+                state: 2,
+                packet: String::from(p.debug_print_type()),
+            });
         }
     }
+    Ok(TranslationUpdates::NoChange)
+}
+
+// The same reasons a vanilla server gives, unless the client is on a version in between the ones
+// we speak
+fn outdated(protocol_version: i32) -> ChatComponent {
+    if protocol_version < ProtocolVersion::OLDEST.number() {
+        ChatComponent::text(&format!(
+            "Outdated client! Please use {}",
+            ProtocolVersion::names()
+        ))
+    } else if protocol_version > ProtocolVersion::NEWEST.number() {
+        ChatComponent::text(&format!(
+            "Outdated server! I'm still on {}",
+            ProtocolVersion::NEWEST.name()
+        ))
+    } else {
+        ChatComponent::text(&format!(
+            "Unsupported client! Please use {}",
+            ProtocolVersion::names()
+        ))
+    }
 }
 
 pub fn initialize_world<M: Messenger + Clone, B: BlockState, PA: PatchworkState, P: PlayerState>(
@@ -77,60 +117,137 @@ fn send_placeholder_chunk<M: Messenger>(x: i32, z: i32, conn_id: Uuid, messenger
             }
         }
     }
+    let mut sections = vec![None; 4];
+    sections[3] = Some(ChunkSection::new(block_ids));
     messenger.send_packet(
         conn_id,
         Packet::ChunkData(ChunkData {
             chunk_x: x,
             chunk_z: z,
-            full_chunk: true,
-            primary_bit_mask: 2_i32.pow(3),
-            size: 12291,
-            data: ChunkSection {
-                bits_per_block: 14,
-                data_array_length: 896,
-                block_ids,
-                block_light: Vec::new(),
-                sky_light: Vec::new(),
-            },
-            biomes: vec![127; 256],
-            number_of_block_entities: 0,
+            column: ChunkColumn::new(sections),
         }),
     );
 }
 
-fn confirm_login<M: Messenger + Clone, P: PlayerState + Clone>(
-    conn_id: Uuid,
-    messenger: M,
-    login_start: packet::LoginStart,
-    player_state: P,
-) {
-    let player = Player {
-        conn_id,
-        uuid: Uuid::new_v4(),
-        name: login_start.username,
-        entity_id: 0, // replaced by player state
-        position: Position {
-            x: 10.0,
-            y: 80.0,
-            z: 10.0,
-        },
-        angle: Angle {
-            pitch: 0.0,
-            yaw: 0.0,
-        },
-    };
+#[cfg(test)]
+mod tests {
+    use super::super::interfaces::{block, login, messenger, patchwork, player};
+    use super::super::packet::LoginStart;
+    use super::super::test_support;
+    use super::*;
 
-    //protocol
-    login_success(conn_id, messenger.clone(), player.clone());
-    player_state.new_player(conn_id, player);
-    player_state.login(conn_id);
-    messenger.subscribe(conn_id, SubscriberType::All);
-}
+    fn login_start(protocol_version: i32) -> (messenger::Recorder, login::Recorder) {
+        let messenger = messenger::Recorder::new();
+        let login_service = login::Recorder::new();
+        handle_login_packet(
+            Packet::LoginStart(LoginStart {
+                username: String::from("Alex"),
+            }),
+            Uuid::new_v4(),
+            protocol_version,
+            messenger.clone(),
+            login_service.clone(),
+        )
+        .unwrap();
+        (messenger, login_service)
+    }
+
+    #[test]
+    fn clients_on_our_versions_are_logged_in() {
+        for version in ProtocolVersion::SUPPORTED.iter() {
+            let (messenger, login_service) = login_start(version.number());
+            assert!(messenger.take().is_empty());
+            match login_service.take().as_slice() {
+                [login::Operations::Start(msg)] => assert_eq!("Alex", msg.username),
+                other => panic!("expected a login, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn clients_on_other_versions_are_turned_away() {
+        let reason = |protocol_version| {
+            let (messenger, login_service) = login_start(protocol_version);
+            assert!(login_service.take().is_empty());
+            match messenger.take().as_slice() {
+                [messenger::Operations::Disconnect(msg)] => msg.reason.text.clone(),
+                other => panic!("expected a disconnect, got {:?}", other),
+            }
+        };
+        assert_eq!(
+            "Outdated client! Please use 1.12.2, 1.13.2 or 1.14.4",
+            reason(335)
+        );
+        assert_eq!(
+            "Unsupported client! Please use 1.12.2, 1.13.2 or 1.14.4",
+            reason(401)
+        );
+        assert_eq!("Outdated server! I'm still on 1.14.4", reason(578));
+    }
+
+    #[test]
+    fn unexpected_packets_are_refused() {
+        let result = handle_login_packet(
+            Packet::StatusRequest(super::super::packet::StatusRequest {}),
+            Uuid::new_v4(),
+            ProtocolVersion::NATIVE.number(),
+            messenger::Recorder::new(),
+            login::Recorder::new(),
+        );
+        assert!(matches!(
+            result,
+            Err(ProtocolError::UnexpectedPacket { state: 2, .. })
+        ));
+    }
 
-fn login_success<M: Messenger>(conn_id: Uuid, messenger: M, player: Player) {
-    let login_success = packet::LoginSuccess {
-        uuid: player.uuid.to_hyphenated().to_string(),
-        username: player.name,
-    };
-    messenger.send_packet(conn_id, Packet::LoginSuccess(login_success));
+    #[test]
+    fn new_players_are_sent_the_world() {
+        let player = test_support::player("Alex");
+        let messenger = messenger::Recorder::new();
+        let player_state = player::Recorder::new();
+        let block_state = block::Recorder::new();
+        let patchwork_state = patchwork::Recorder::new();
+        initialize_world(
+            player.clone(),
+            messenger.clone(),
+            player_state.clone(),
+            block_state.clone(),
+            patchwork_state.clone(),
+        );
+        let packets: Vec<Packet> = messenger
+            .take()
+            .into_iter()
+            .map(|operation| match operation {
+                messenger::Operations::Send(msg) => {
+                    assert_eq!(player.conn_id, msg.conn_id);
+                    msg.packet
+                }
+                other => panic!("expected a packet for the player, got {:?}", other),
+            })
+            .collect();
+        assert!(matches!(packets.first(), Some(Packet::JoinGame(_))));
+        assert_eq!(
+            1600,
+            packets
+                .iter()
+                .filter(|packet| matches!(packet, Packet::ChunkData(_)))
+                .count()
+        );
+        assert!(matches!(
+            packets.last(),
+            Some(Packet::ClientboundPlayerPositionAndLook(_))
+        ));
+        assert!(matches!(
+            player_state.take().as_slice(),
+            [player::Operations::Report(_)]
+        ));
+        assert!(matches!(
+            block_state.take().as_slice(),
+            [block::Operations::Report(_)]
+        ));
+        assert!(matches!(
+            patchwork_state.take().as_slice(),
+            [patchwork::Operations::Report(_)]
+        ));
+    }
 }
@@ -1,46 +1,86 @@
 use super::interfaces::block::BlockState;
+use super::interfaces::keep_alive::KeepAliveService;
+use super::interfaces::login::LoginService;
 use super::interfaces::messenger::Messenger;
 use super::interfaces::patchwork::PatchworkState;
 use super::interfaces::player::PlayerState;
 
 use super::initiation_protocols::{border_cross_login, client_ping, handshake, login};
-use super::packet::Packet;
+use super::packet::{Packet, Status};
 use super::peer_subscription;
-use super::translation::TranslationUpdates;
+use super::protocol_error::ProtocolError;
+use super::translation::{TranslationInfo, TranslationUpdates};
 use uuid::Uuid;
 
-// Routes the packet to the corresponding service according to the connection state
+// Routes the packet to the corresponding service according to the connection state, returning
+// any changes that should be made to how the rest of the connection is read, or why the connection
+// should be dropped
+#[allow(clippy::too_many_arguments)]
 pub fn route_packet<
     M: Messenger + Clone,
     P: PlayerState + Clone,
     PA: PatchworkState + Clone,
     B: BlockState + Clone,
+    L: LoginService,
+    K: KeepAliveService,
 >(
     packet: Packet,
-    state: i32,
+    translation_data: &TranslationInfo,
     conn_id: Uuid,
     messenger: M,
     player_state: P,
     block_state: B,
     patchwork_state: PA,
-) -> TranslationUpdates {
-    let st = Status::from_i32(state);
-    match st {
-        Status::Handshake => handshake::handle_handshake_packet(packet),
-        Status::Login => login::handle_login_packet(packet, conn_id, messenger, player_state),
-        Status::ClientPing => {
-            client_ping::handle_client_ping_packet(packet, conn_id, messenger, player_state)
-        }
+    login_service: L,
+    keep_alive: K,
+) -> Result<Vec<TranslationUpdates>, ProtocolError> {
+    let st = Status::from_i32(translation_data.state)?;
+    if let (
+        Status::BorderCrossLogin | Status::InPeerSub | Status::OutPeerSub,
+        Packet::SetCompression(set_compression),
+    ) = (&st, &packet)
+    {
+        return Ok(peer_subscription::handle_set_compression(
+            conn_id,
+            set_compression,
+            messenger,
+        ));
+    }
+    Ok(match st {
+        Status::Handshake => handshake::handle_handshake_packet(packet)?,
+        Status::Login => vec![login::handle_login_packet(
+            packet,
+            conn_id,
+            translation_data.protocol_version,
+            messenger,
+            login_service,
+        )?],
+        Status::ClientPing => vec![client_ping::handle_client_ping_packet(
+            packet,
+            conn_id,
+            translation_data.protocol_version,
+            messenger,
+            player_state,
+        )],
+        // Keep alives are answered to whichever node the player logged in through, so they must
+        // not be forwarded to the peer the player is anchored to
         Status::Play => {
-            patchwork_state.route_player_packet(packet, conn_id);
-            TranslationUpdates::NoChange
-        }
-        Status::BorderCrossLogin => {
-            border_cross_login::border_cross_login(packet, conn_id, player_state)
+            match packet {
+                Packet::KeepAlive(keep_alive_packet) => {
+                    keep_alive.response(conn_id, keep_alive_packet.id)
+                }
+                packet => patchwork_state.route_player_packet(packet, conn_id),
+            }
+            vec![TranslationUpdates::NoChange]
         }
+        Status::BorderCrossLogin => vec![border_cross_login::border_cross_login(
+            packet,
+            conn_id,
+            player_state,
+        )],
         Status::InPeerSub => {
             peer_subscription::handle_peer_packet(packet, messenger, player_state);
-            TranslationUpdates::NoChange
+            vec![TranslationUpdates::NoChange]
         }
         Status::OutPeerSub => {
             peer_subscription::handle_subscriber_packet(
@@ -49,32 +89,7 @@ pub fn route_packet<
                 player_state,
                 block_state,
             );
-            TranslationUpdates::NoChange
-        }
-    }
-}
-
-enum Status {
-    Handshake,
-    ClientPing,
-    Login,
-    Play,
-    BorderCrossLogin,
-    InPeerSub,
-    OutPeerSub,
-}
-
-impl Status {
-    fn from_i32(status: i32) -> Status {
-        match status {
-            0 => Status::Handshake,
-            1 => Status::ClientPing,
-            2 => Status::Login,
-            3 => Status::Play,
-            4 => Status::BorderCrossLogin,
-            5 => Status::InPeerSub,
-            6 => Status::OutPeerSub,
-            _ => panic!("Bad state"),
+            vec![TranslationUpdates::NoChange]
         }
-    }
+    })
 }
@@ -1,5 +1,6 @@
 use super::interfaces::messenger::{Messenger, SubscriberType};
-use super::packet::Packet;
+use super::packet::{DestroyEntities, Packet, SetCompression};
+use super::translation::TranslationUpdates;
 use uuid::Uuid;
 
 use super::interfaces::block::BlockState;
@@ -16,13 +17,19 @@ pub fn handle_peer_packet<M: Messenger, P: PlayerState>(
                 messenger.broadcast(Packet::SpawnPlayer(packet), None, SubscriberType::Local);
             }
         }
+        // Peers can send any number of ids, only some of which are theirs
         Packet::DestroyEntities(packet) => {
-            assert!(
-                packet.entity_ids.len() == 1,
-                "Cannot handle entity destroy packets from peers with multiple ids"
-            );
-            if packet.entity_ids[0] >= 1000 {
-                messenger.broadcast(Packet::DestroyEntities(packet), None, SubscriberType::Local);
+            let entity_ids: Vec<i32> = packet
+                .entity_ids
+                .into_iter()
+                .filter(|entity_id| *entity_id >= 1000)
+                .collect();
+            if !entity_ids.is_empty() {
+                messenger.broadcast(
+                    Packet::DestroyEntities(DestroyEntities { entity_ids }),
+                    None,
+                    SubscriberType::Local,
+                );
             }
         }
         //We really don't want to have to do this for every type of packet that has an entity id
@@ -33,6 +40,8 @@ pub fn handle_peer_packet<M: Messenger, P: PlayerState>(
             let entity_id = packet.entity_id;
             player_state.broadcast_anchored_event(entity_id, Packet::EntityLookAndMove(packet));
         }
+        // Whatever we couldn't read isn't passed on to our players
+        Packet::Unknown => {}
         _ => {
             messenger.broadcast(packet, None, SubscriberType::Local);
         }
@@ -54,3 +63,143 @@ pub fn handle_subscriber_packet<M: Messenger, P: PlayerState, B: BlockState>(
     player_state.report(conn_id);
     block_state.report(conn_id);
 }
+
+pub fn handle_set_compression<M: Messenger>(
+    conn_id: Uuid,
+    set_compression: &SetCompression,
+    messenger: M,
+) -> Vec<TranslationUpdates> {
+    //Peers compress everything they send after their handshake, so we answer in kind
+
+    trace!(
+        "Peer {:?} enabled compression with threshold {:?}",
+        conn_id,
+        set_compression.threshold
+    );
+
+    messenger.enable_compression(conn_id, set_compression.threshold);
+    vec![TranslationUpdates::Compression(set_compression.threshold)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::interfaces::{block, messenger, player};
+    use super::super::packet::{EntityLookAndMove, SpawnPlayer};
+    use super::*;
+
+    fn spawn_player(entity_id: i32) -> Packet {
+        Packet::SpawnPlayer(SpawnPlayer {
+            entity_id,
+            uuid: 0,
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+            yaw: 0,
+            pitch: 0,
+            entity_metadata_terminator: 0xff,
+        })
+    }
+
+    // Peers echo back entities from our own entity id block, which our players already know of
+    #[test]
+    fn only_peer_entities_are_spawned() {
+        let messenger = messenger::Recorder::new();
+        let player_state = player::Recorder::new();
+        handle_peer_packet(spawn_player(5), messenger.clone(), player_state.clone());
+        assert!(messenger.take().is_empty());
+
+        handle_peer_packet(spawn_player(1005), messenger.clone(), player_state);
+        match messenger.take().as_slice() {
+            [messenger::Operations::Broadcast(msg)] => {
+                assert!(matches!(msg.packet, Packet::SpawnPlayer(_)));
+                assert!(matches!(msg.subscriber_type, SubscriberType::Local));
+            }
+            other => panic!("expected a broadcast, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn only_peer_entities_are_destroyed() {
+        let messenger = messenger::Recorder::new();
+        let player_state = player::Recorder::new();
+        let destroy = |entity_ids| {
+            handle_peer_packet(
+                Packet::DestroyEntities(DestroyEntities { entity_ids }),
+                messenger.clone(),
+                player_state.clone(),
+            )
+        };
+        destroy(Vec::new());
+        destroy(vec![5]);
+        assert!(messenger.take().is_empty());
+
+        destroy(vec![5, 1005, 1006]);
+        match messenger.take().as_slice() {
+            [messenger::Operations::Broadcast(msg)] => match &msg.packet {
+                Packet::DestroyEntities(packet) => assert_eq!(vec![1005, 1006], packet.entity_ids),
+                other => panic!("expected DestroyEntities, got {:?}", other),
+            },
+            other => panic!("expected a broadcast, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unknown_packets_from_peers_go_nowhere() {
+        let messenger = messenger::Recorder::new();
+        handle_peer_packet(Packet::Unknown, messenger.clone(), player::Recorder::new());
+        assert!(messenger.take().is_empty());
+    }
+
+    #[test]
+    fn peer_movement_is_checked_for_anchored_players() {
+        let messenger = messenger::Recorder::new();
+        let player_state = player::Recorder::new();
+        handle_peer_packet(
+            Packet::EntityLookAndMove(EntityLookAndMove {
+                entity_id: 1005,
+                delta_x: 1,
+                delta_y: 0,
+                delta_z: 0,
+                yaw: 0,
+                pitch: 0,
+                on_ground: true,
+            }),
+            messenger.clone(),
+            player_state.clone(),
+        );
+        assert!(messenger.take().is_empty());
+        match player_state.take().as_slice() {
+            [player::Operations::BroadcastAnchoredEvent(msg)] => assert_eq!(1005, msg.entity_id),
+            other => panic!("expected an anchored event, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn subscribers_are_reported_our_state() {
+        let conn_id = Uuid::new_v4();
+        let messenger = messenger::Recorder::new();
+        let player_state = player::Recorder::new();
+        let block_state = block::Recorder::new();
+        handle_subscriber_packet(
+            conn_id,
+            messenger.clone(),
+            player_state.clone(),
+            block_state.clone(),
+        );
+        match messenger.take().as_slice() {
+            [messenger::Operations::Subscribe(msg)] => {
+                assert_eq!(conn_id, msg.conn_id);
+                assert!(matches!(msg.typ, SubscriberType::Remote));
+            }
+            other => panic!("expected a subscription, got {:?}", other),
+        }
+        assert!(matches!(
+            player_state.take().as_slice(),
+            [player::Operations::Report(_)]
+        ));
+        assert!(matches!(
+            block_state.take().as_slice(),
+            [block::Operations::Report(_)]
+        ));
+    }
+}
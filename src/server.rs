@@ -1,109 +1,314 @@
+use super::config::Config;
 use super::interfaces::connection::ConnectionService;
 use super::interfaces::messenger::Messenger;
 use super::interfaces::packet_processor::PacketProcessor;
 
-use super::models::minecraft_protocol::MinecraftProtocolReader;
+use super::models::encryption::{DecryptingReader, DecryptionSlot};
+use super::models::minecraft_protocol::frame_length;
+use super::models::outbound::{Outbound, OutboundReceiver};
+use super::models::protocol_error::ProtocolError;
 
-use std::env;
-use std::io::ErrorKind::{ConnectionReset, UnexpectedEof};
-use std::io::{Cursor, Error, Read};
-use std::net::{TcpListener, TcpStream};
-use std::thread;
-use std::thread::sleep;
-use std::time;
+use std::io::{Cursor, Error};
+use std::net;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::{Builder, Handle, Runtime};
+use tokio::sync::Notify;
+use tokio::task::{AbortHandle, JoinHandle};
+use tokio::time::{sleep, timeout};
 
 use uuid::Uuid;
 
-pub fn listen<
-    M: 'static + Messenger + Clone + Send,
-    PP: 'static + PacketProcessor + Clone + Send,
-    CS: 'static + ConnectionService + Clone + Send,
->(
-    inbound_packet_processor: PP,
-    connection_service: CS,
-    messenger: M,
-) {
-    let connection_string = format!("127.0.0.1:{}", env::var("PORT").unwrap());
-    let listener = TcpListener::bind(connection_string.clone()).unwrap();
-
-    trace!("Listening on {:?}", connection_string);
-
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        let inbound_packet_processor_clone = inbound_packet_processor.clone();
-        let messenger_clone = messenger.clone();
-        let closure_connection_service = connection_service.clone();
-        let conn_id = Uuid::new_v4();
-        thread::spawn(move || {
-            handle_connection(
-                stream,
-                inbound_packet_processor_clone,
-                messenger_clone,
-                conn_id,
-                || closure_connection_service.close(conn_id),
-            );
+// Every socket on the node, clients and peers alike, is served from this many threads
+const NETWORK_THREADS: usize = 4;
+
+const MAX_CONNECT_BACKOFF: u64 = 10;
+
+pub fn runtime() -> Result<Runtime, Error> {
+    Builder::new_multi_thread()
+        .worker_threads(NETWORK_THREADS)
+        .thread_name("network")
+        .enable_all()
+        .build()
+}
+
+pub fn bind(config: &Config) -> Result<net::TcpListener, Error> {
+    let listener = net::TcpListener::bind((config.bind_address.as_str(), config.port))?;
+    listener.set_nonblocking(true)?;
+    info!("Listening on {:?}", listener.local_addr()?);
+    Ok(listener)
+}
+
+// Owns the sockets of every connection. What is read off a connection goes to the packet
+// processor, and the messenger writes to connections through their Outbound queues
+#[derive(Clone)]
+pub struct Network {
+    runtime: Handle,
+    stop_listening: Arc<Notify>,
+    open_writers: Arc<AtomicUsize>,
+    writer_finished: Arc<Notify>,
+}
+
+impl Network {
+    pub fn new(runtime: Handle) -> Network {
+        Network {
+            runtime,
+            stop_listening: Arc::new(Notify::new()),
+            open_writers: Arc::new(AtomicUsize::new(0)),
+            writer_finished: Arc::new(Notify::new()),
+        }
+    }
+
+    // Accepts connections until stop_listening is called
+    pub fn listen<
+        M: 'static + Messenger + Clone + Send,
+        PP: 'static + PacketProcessor + Clone + Send,
+        CS: 'static + ConnectionService + Clone + Send,
+    >(
+        &self,
+        listener: net::TcpListener,
+        inbound_packet_processor: PP,
+        connection_service: CS,
+        messenger: M,
+    ) -> JoinHandle<()> {
+        let network = self.clone();
+        self.runtime.spawn(async move {
+            let listener = match TcpListener::from_std(listener) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    error!("Could not listen for connections: {}", e);
+                    return;
+                }
+            };
+            loop {
+                let stream = tokio::select! {
+                    accepted = listener.accept() => accepted,
+                    _ = network.stop_listening.notified() => break,
+                };
+                match stream {
+                    Ok((stream, _)) => {
+                        let conn_id = Uuid::new_v4();
+                        let connection_service = connection_service.clone();
+                        network.open(
+                            stream,
+                            conn_id,
+                            &messenger,
+                            inbound_packet_processor.clone(),
+                            move || connection_service.close(conn_id),
+                        );
+                    }
+                    Err(e) => warn!("Failed to accept connection: {:?}", e),
+                }
+            }
+        })
+    }
+
+    pub fn stop_listening(&self) {
+        self.stop_listening.notify_one();
+    }
+
+    // Waits until the block finishes, from a thread that isn't one of the network's
+    pub fn block_on<F: std::future::Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
+
+    // Registers a connected socket with the messenger and starts feeding the frames read off it to
+    // the packet processor. on_closure runs once the connection is gone, from either end
+    pub fn open<
+        M: Messenger,
+        PP: 'static + PacketProcessor + Send,
+        F: 'static + FnOnce() + Send,
+    >(
+        &self,
+        stream: TcpStream,
+        conn_id: Uuid,
+        messenger: &M,
+        inbound_packet_processor: PP,
+        on_closure: F,
+    ) {
+        let _ = stream.set_nodelay(true);
+        let (read_half, write_half) = stream.into_split();
+        let decryption = DecryptionSlot::default();
+        let closed = Arc::new(Notify::new());
+        let (outbound, outbound_receiver) = Outbound::channel();
+        messenger.new_connection(conn_id, outbound, decryption.clone());
+        self.spawn_writer(write_half, outbound_receiver, closed.clone());
+        self.runtime.spawn(handle_connection(
+            BufReader::new(DecryptingReader::new(read_half, decryption)),
+            inbound_packet_processor,
+            conn_id,
+            closed,
+            on_closure,
+        ));
+    }
+
+    // For connections we only ever write to
+    pub fn open_outbound<M: Messenger>(&self, stream: TcpStream, conn_id: Uuid, messenger: &M) {
+        let _ = stream.set_nodelay(true);
+        let (_, write_half) = stream.into_split();
+        let (outbound, outbound_receiver) = Outbound::channel();
+        messenger.new_connection(conn_id, outbound, DecryptionSlot::default());
+        self.spawn_writer(write_half, outbound_receiver, Arc::new(Notify::new()));
+    }
+
+    //Just doing a simple linear backoff for now, probably want something a little more
+    //sophisticated eventually. Retrying goes on until it connects or is aborted with the handle
+    pub fn connect_with_backoff<F: 'static + FnOnce(TcpStream) + Send>(
+        &self,
+        address: String,
+        port: u16,
+        on_connection: F,
+    ) -> AbortHandle {
+        let connecting = self.runtime.spawn(async move {
+            let mut backoff = 1;
+            loop {
+                match TcpStream::connect((address.as_str(), port)).await {
+                    Ok(stream) => {
+                        trace!("Connection Established");
+                        on_connection(stream);
+                        break;
+                    }
+                    Err(_) => {
+                        backoff = MAX_CONNECT_BACKOFF.min(backoff + 1);
+                        trace!("Failed to connect- retrying in {:?}s", backoff);
+                        sleep(Duration::from_secs(backoff)).await;
+                    }
+                }
+            }
         });
+        connecting.abort_handle()
+    }
+
+    // Gives connections that were closed during shutdown a chance to write what was queued for them
+    pub fn flush(&self, limit: Duration) {
+        let flushed = async {
+            while self.open_writers.load(Ordering::SeqCst) > 0 {
+                self.writer_finished.notified().await;
+            }
+        };
+        if self
+            .block_on(async { timeout(limit, flushed).await })
+            .is_err()
+        {
+            warn!("Gave up waiting for connections to flush");
+        }
+    }
+
+    fn spawn_writer(
+        &self,
+        socket: OwnedWriteHalf,
+        outbound: OutboundReceiver,
+        closed: Arc<Notify>,
+    ) {
+        let open_writers = self.open_writers.clone();
+        let writer_finished = self.writer_finished.clone();
+        open_writers.fetch_add(1, Ordering::SeqCst);
+        self.runtime.spawn(async move {
+            write_outbound(socket, outbound).await;
+            // Wakes up the task reading from this socket so it can clean up after itself
+            closed.notify_one();
+            open_writers.fetch_sub(1, Ordering::SeqCst);
+            writer_finished.notify_one();
+        });
+    }
+}
+
+async fn write_outbound(mut socket: OwnedWriteHalf, mut outbound: OutboundReceiver) {
+    while let Some(bytes) = outbound.recv().await {
+        if let Err(e) = socket.write_all(&bytes).await {
+            warn!("Failed to write packet: {:?}", e);
+            return;
+        }
     }
+    let _ = socket.shutdown().await;
 }
 
-pub fn handle_connection<M: Messenger, PP: PacketProcessor, F: Fn()>(
-    mut stream: TcpStream,
+// Reads packets off a connection that has already been registered with the messenger
+async fn handle_connection<R: AsyncRead + Unpin, PP: PacketProcessor, F: FnOnce()>(
+    mut stream: R,
     inbound_packet_processor: PP,
-    messenger: M,
     conn_id: Uuid,
+    closed: Arc<Notify>,
     on_closure: F,
 ) {
-    let stream_clone = stream.try_clone().unwrap();
-    messenger.new_connection(conn_id, stream_clone);
     loop {
-        match stream.try_read_var_int() {
-            Ok(length) => {
-                let vec: Vec<u8> = (&stream)
-                    .bytes()
-                    .take(length as usize)
-                    .map(|r: Result<u8, _>| {
-                        r.expect("packet was smaller than length field indicated!")
-                    })
-                    .collect();
-                let cursor = Cursor::new(vec);
-                inbound_packet_processor.inbound(conn_id, cursor);
+        let frame = tokio::select! {
+            frame = read_frame(&mut stream) => frame,
+            _ = closed.notified() => break,
+        };
+        match frame {
+            Ok(frame) => {
+                inbound_packet_processor.inbound(conn_id, Cursor::new(frame));
             }
             Err(e) => {
-                match e.kind() {
-                    UnexpectedEof => on_closure(),
-                    ConnectionReset => on_closure(),
-                    _ => {
-                        panic!("conn closed due to {:?}", e);
-                    }
-                };
+                if !e.is_closed() {
+                    warn!("Closing connection {:?}: {}", conn_id, e);
+                }
                 break;
             }
         }
     }
+    on_closure();
 }
 
-//Just doing a simple linear backoff for now, probably want something a little more sophisticated
-//eventually
-pub fn wait_for_connection<F: FnOnce(TcpStream)>(
-    peer_address: String,
-    peer_port: u16,
-    on_connection: F,
-) {
-    let backoff = 1;
-    loop {
-        if let Ok(connection) = new_connection(peer_address.clone(), peer_port) {
-            trace!("Connection Established");
-            on_connection(connection);
-            break;
-        } else {
-            let backoff = if backoff < 10 { backoff + 1 } else { backoff };
-            trace!("Failed to connect- retrying in {:?}s", backoff);
-            sleep(time::Duration::from_secs(backoff));
+async fn read_frame<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Vec<u8>, ProtocolError> {
+    let length = frame_length(read_frame_length(stream).await?)?;
+    let mut frame = vec![0; length];
+    stream.read_exact(&mut frame).await?;
+    Ok(frame)
+}
+
+async fn read_frame_length<R: AsyncRead + Unpin>(stream: &mut R) -> Result<i32, ProtocolError> {
+    let mut length = 0;
+    for position in 0..5 {
+        let byte = stream.read_u8().await?;
+        length |= ((byte & 0x7F) as i32) << (7 * position);
+        if byte & 0x80 == 0 {
+            return Ok(length);
         }
     }
+    Err(ProtocolError::VarIntTooBig)
 }
 
-pub fn new_connection(peer_address: String, peer_port: u16) -> Result<TcpStream, Error> {
-    let peer_info = format!("{}:{}", peer_address, peer_port.to_string());
-    TcpStream::connect(peer_info)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{channel, TryRecvError};
+
+    #[test]
+    fn aborted_connections_are_never_made() {
+        let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+        let network = Network::new(runtime.handle().clone());
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (connected, connections) = channel();
+        network
+            .connect_with_backoff(String::from("127.0.0.1"), port, move |_| {
+                connected.send(()).unwrap()
+            })
+            .abort();
+        runtime.block_on(async { sleep(Duration::from_millis(100)).await });
+        assert_eq!(Err(TryRecvError::Disconnected), connections.try_recv());
+    }
+
+    #[tokio::test]
+    async fn frames_are_split_on_their_length() {
+        let mut stream: &[u8] = &[2, 0xAA, 0xBB, 1, 0xCC];
+        assert_eq!(vec![0xAA, 0xBB], read_frame(&mut stream).await.unwrap());
+        assert_eq!(vec![0xCC], read_frame(&mut stream).await.unwrap());
+        assert!(read_frame(&mut stream).await.unwrap_err().is_closed());
+    }
+
+    #[tokio::test]
+    async fn oversized_frames_are_rejected() {
+        let mut stream: &[u8] = &[0xFF, 0xFF, 0xFF, 0x01];
+        match read_frame(&mut stream).await {
+            Err(ProtocolError::PacketTooLarge(_)) => {}
+            other => panic!("expected PacketTooLarge, got {:?}", other),
+        }
+    }
 }
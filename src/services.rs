@@ -10,18 +10,30 @@ pub mod messenger;
 pub mod block;
 pub mod connection;
 pub mod keep_alive;
+pub mod login;
 pub mod packet_processor;
 pub mod patchwork;
 pub mod player;
 
-use super::constants;
+use super::config;
 
+use super::models::capture;
+use super::models::compression;
+use super::models::encryption;
+use super::models::lighting;
 use super::models::map;
 use super::models::minecraft_types;
+use super::models::outbound;
 use super::models::packet;
+use super::models::protocol_error;
+use super::models::protocol_version;
+use super::models::session;
 use super::models::translation;
 
 use super::interfaces;
 
 use super::packet_handlers;
 use super::server;
+
+#[cfg(test)]
+use super::test_support;
@@ -1,10 +1,13 @@
+use super::instance::Inbox;
 use super::interfaces::block::Block;
 use super::interfaces::block::Operations;
 use super::interfaces::messenger::{Messenger, SubscriberType};
-use super::minecraft_types::{BlockPosition, ChunkSection};
+use super::lighting::{light_column, ColumnLight};
+use super::minecraft_types::{BlockPosition, ChunkColumn, ChunkSection};
 use super::packet::{BlockChange, ChunkData, Packet};
 
-use std::sync::mpsc::{Receiver, Sender};
+use std::ops::ControlFlow;
+use std::sync::mpsc::Sender;
 use uuid::Uuid;
 
 // We don't really have any meaningful block state yet- it cannot be changed or be particularly
@@ -27,34 +30,59 @@ fn fill_dummy_block_ids(ids: &mut Vec<i32>) {
 }
 
 pub fn start<M: Messenger + Clone>(
-    receiver: Receiver<Operations>,
+    receiver: &Inbox<Operations>,
     _sender: Sender<Operations>,
     messenger: M,
 ) {
     let mut block = Block::new();
     while let Ok(msg) = receiver.recv() {
-        //Just send a hardcoded simple chunk pillar
-        handle_message(msg, &mut block, messenger.clone());
+        if handle_message(msg, &mut block, messenger.clone()).is_break() {
+            break;
+        }
     }
 }
 
-fn handle_message<M: Messenger + Clone>(msg: Operations, block: &mut Block, messenger: M) {
+fn handle_message<M: Messenger + Clone>(
+    msg: Operations,
+    block: &mut Block,
+    messenger: M,
+) -> ControlFlow<()> {
     match msg {
+        Operations::Stop(_) => return ControlFlow::Break(()),
         Operations::Report(msg) => {
             trace!("Reporting block state to {:?}", msg.conn_id);
 
             refresh_chunk(
                 msg.conn_id,
-                &mut block.block_ids[0][0][2],
+                &block.block_ids[0][0],
+                &block.light[0][0],
                 messenger.clone(),
             );
         }
         Operations::BlockPlacement(msg) => {
-            trace!("Block placed: #{:?}", msg);
-            let placement_position = get_position_of_placement(
-                msg.block_placement.location,
-                Face::from_varint(msg.block_placement.face),
+            trace!(
+                "Block placed by {:?}: {:?}",
+                msg.conn_id,
+                msg.block_placement
             );
+            let face = match Face::from_varint(msg.block_placement.face) {
+                Some(face) => face,
+                None => {
+                    warn!("Ignoring block placement on invalid face {:?}", msg);
+                    return ControlFlow::Continue(());
+                }
+            };
+            let placement_position =
+                match get_position_of_placement(msg.block_placement.location, face) {
+                    Some(position) => position,
+                    None => {
+                        warn!(
+                            "Ignoring block placement off the edge of the world {:?}",
+                            msg
+                        );
+                        return ControlFlow::Continue(());
+                    }
+                };
             block.place_block(placement_position);
             messenger.broadcast(
                 Packet::BlockChange(BlockChange {
@@ -66,7 +94,11 @@ fn handle_message<M: Messenger + Clone>(msg: Operations, block: &mut Block, mess
             );
         }
         Operations::BreakBlock(msg) => {
-            trace!("break block {:?}", msg);
+            trace!(
+                "Block broken by {:?}: {:?}",
+                msg.conn_id,
+                msg.player_digging
+            );
             block.break_block(msg.player_digging.location);
             messenger.broadcast(
                 Packet::BlockChange(BlockChange {
@@ -78,6 +110,13 @@ fn handle_message<M: Messenger + Clone>(msg: Operations, block: &mut Block, mess
             );
         }
     }
+    ControlFlow::Continue(())
+}
+
+impl Default for Block {
+    fn default() -> Block {
+        Block::new()
+    }
 }
 
 impl Block {
@@ -98,12 +137,10 @@ impl Block {
         for _i in 3..16 {
             starting_pillar.push(air_chunk.clone())
         }
+        let light = vec![vec![light_column(&starting_pillar)]];
         starting_pillar_row.push(starting_pillar);
         block_ids.push(starting_pillar_row);
-        Block {
-            conn_id: Uuid::new_v4(),
-            block_ids,
-        }
+        Block { block_ids, light }
     }
     pub fn place_block(&mut self, position: BlockPosition) {
         //println!("position: {:?} place at {:?} {:?} {:?} {:?}", position,
@@ -131,6 +168,7 @@ impl Block {
             })
         {
             *block = 1;
+            self.relight(position);
         }
     }
     pub fn break_block(&mut self, position: BlockPosition) {
@@ -148,8 +186,16 @@ impl Block {
             })
         {
             *block = 0;
+            self.relight(position);
         }
     }
+
+    // Changing a block can change the light anywhere in its column, so the whole column is lit again.
+    // Clients work this out for themselves on block changes, but chunks sent later need it
+    fn relight(&mut self, position: BlockPosition) {
+        let (row, pillar) = (get_pillar_row_index(position), get_pillar_index(position));
+        self.light[row][pillar] = light_column(&self.block_ids[row][pillar]);
+    }
 }
 
 fn get_pillar_row_index(pos: BlockPosition) -> usize {
@@ -179,50 +225,89 @@ enum Face {
 }
 
 impl Face {
-    pub fn from_varint(x: i32) -> Face {
+    pub fn from_varint(x: i32) -> Option<Face> {
         match x {
-            0 => Face::Bottom,
-            1 => Face::Top,
-            2 => Face::North,
-            3 => Face::South,
-            4 => Face::West,
-            5 => Face::East,
-            _ => panic!("Received invalid face"),
+            0 => Some(Face::Bottom),
+            1 => Some(Face::Top),
+            2 => Some(Face::North),
+            3 => Some(Face::South),
+            4 => Some(Face::West),
+            5 => Some(Face::East),
+            _ => None,
         }
     }
 }
 
-fn get_position_of_placement(position: BlockPosition, face: Face) -> BlockPosition {
+// Clients can place against any face of any block, including ones at the very edge of the world
+fn get_position_of_placement(position: BlockPosition, face: Face) -> Option<BlockPosition> {
     let mut adjusted_position = position;
     match face {
-        Face::Bottom => adjusted_position.y -= 1,
-        Face::Top => adjusted_position.y += 1,
-        Face::North => adjusted_position.z -= 1,
-        Face::South => adjusted_position.z += 1,
-        Face::West => adjusted_position.x -= 1,
-        Face::East => adjusted_position.x += 1,
+        Face::Bottom => adjusted_position.y = position.y.checked_sub(1)?,
+        Face::Top => adjusted_position.y = position.y.checked_add(1)?,
+        Face::North => adjusted_position.z = position.z.checked_sub(1)?,
+        Face::South => adjusted_position.z = position.z.checked_add(1)?,
+        Face::West => adjusted_position.x = position.x.checked_sub(1)?,
+        Face::East => adjusted_position.x = position.x.checked_add(1)?,
     }
-    adjusted_position
+    Some(adjusted_position)
 }
 
-fn refresh_chunk<M: Messenger + Clone>(conn_id: Uuid, block_ids: &mut Vec<i32>, messenger: M) {
+// The whole pillar is sent, leaving out any sections that are all air
+fn refresh_chunk<M: Messenger + Clone>(
+    conn_id: Uuid,
+    pillar: &[Vec<i32>],
+    light: &ColumnLight,
+    messenger: M,
+) {
+    let sections = pillar
+        .iter()
+        .zip(light.block_light.iter().zip(light.sky_light.iter()))
+        .map(|(block_ids, (block_light, sky_light))| {
+            Some(ChunkSection {
+                block_ids: block_ids.clone(),
+                block_light: block_light.clone(),
+                sky_light: sky_light.clone(),
+            })
+        })
+        .collect();
     messenger.send_packet(
         conn_id,
         Packet::ChunkData(ChunkData {
             chunk_x: 0,
             chunk_z: 0,
-            full_chunk: true,
-            primary_bit_mask: 0b0000_0100,
-            size: 12291, //I just calculated the length of this hardcoded chunk section
-            data: ChunkSection {
-                bits_per_block: 14,
-                data_array_length: 896,
-                block_ids: block_ids.to_vec(),
-                block_light: Vec::new(),
-                sky_light: Vec::new(),
-            },
-            biomes: vec![127; 256],
-            number_of_block_entities: 0,
+            column: ChunkColumn::new(sections),
         }),
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::interfaces::{block, messenger};
+    use super::super::packet::PlayerBlockPlacement;
+    use super::*;
+
+    #[test]
+    fn placements_off_the_edge_of_the_world_are_ignored() {
+        let messenger = messenger::Recorder::new();
+        let mut block = Block::new();
+        for face in 0..6 {
+            let _ = handle_message(
+                Operations::BlockPlacement(block::BlockPlacement {
+                    conn_id: Uuid::new_v4(),
+                    block_placement: PlayerBlockPlacement {
+                        location: BlockPosition { x: 0, y: 0, z: 0 },
+                        face,
+                        hand: 0,
+                        cursor_x: 0.0,
+                        cursor_y: 0.0,
+                        cursor_z: 0.0,
+                    },
+                }),
+                &mut block,
+                messenger.clone(),
+            );
+        }
+        // Only the top, south and east faces have room next to them
+        assert_eq!(3, messenger.take().len());
+    }
+}
@@ -1,30 +1,71 @@
+use super::instance::Inbox;
 use super::interfaces::connection::Operations;
+use super::interfaces::keep_alive::KeepAliveService;
+use super::interfaces::login::LoginService;
 use super::interfaces::messenger::Messenger;
 use super::interfaces::packet_processor::PacketProcessor;
 use super::interfaces::patchwork::PatchworkState;
 use super::interfaces::player::PlayerState;
 
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+use uuid::Uuid;
 
+// Closing the connection shouldn't wait on a busy player service just to say who left
+const PLAYER_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[allow(clippy::too_many_arguments)]
 pub fn start<
     M: Messenger + Clone,
     P: PlayerState + Clone,
     PA: PatchworkState + Clone,
     PP: 'static + PacketProcessor + Clone + Send,
+    L: LoginService,
+    K: KeepAliveService,
 >(
-    receiver: Receiver<Operations>,
+    receiver: &Inbox<Operations>,
     _sender: Sender<Operations>,
     messenger: M,
     player_state: P,
-    _patchwork_state: PA,
+    patchwork_state: PA,
     _packet_processor: PP,
+    login_service: L,
+    keep_alive: K,
 ) {
     while let Ok(msg) = receiver.recv() {
         match msg {
+            Operations::Stop(_) => break,
+            Operations::Disconnect(msg) => {
+                messenger.disconnect(msg.conn_id, msg.reason);
+                log_departure(msg.conn_id, &player_state);
+                player_state.delete_player(msg.conn_id);
+                patchwork_state.drop_player(msg.conn_id);
+                login_service.abandon(msg.conn_id);
+                keep_alive.untrack(msg.conn_id);
+            }
             Operations::Close(msg) => {
                 messenger.close(msg.conn_id);
+                log_departure(msg.conn_id, &player_state);
                 player_state.delete_player(msg.conn_id);
+                patchwork_state.drop_player(msg.conn_id);
+                login_service.abandon(msg.conn_id);
+                keep_alive.untrack(msg.conn_id);
             }
         }
     }
 }
+
+// Most connections never get as far as being a player, and those are left out
+fn log_departure<P: PlayerState>(conn_id: Uuid, player_state: &P) {
+    match player_state
+        .get_player(conn_id)
+        .wait_timeout(PLAYER_TIMEOUT)
+    {
+        Ok(Some(player)) => info!("{} left the game", player.name),
+        Ok(None) => {}
+        Err(e) => info!(
+            "Could not tell who left from connection {:?}: {}",
+            conn_id, e
+        ),
+    }
+}
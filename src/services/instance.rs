@@ -1,17 +1,39 @@
+use super::interfaces::Operation;
+
+use std::any::Any;
+use std::cell::Cell;
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::mpsc::channel;
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::{Receiver, RecvError, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// A service that keeps panicking isn't going to get better by being restarted
+const MAX_RESTARTS: usize = 5;
+const RESTART_WINDOW: Duration = Duration::from_secs(60);
+
+// What happens when a service panics. Restarting gives the service fresh state but keeps its
+// channel, so everyone holding a sender to it carries on as before. Services whose state the rest
+// of the node can't do without shut the node down instead
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OnPanic {
+    Restart,
+    Shutdown,
+}
 
 pub struct ServiceInstance<O> {
-    pub receiver: Option<Receiver<O>>,
+    inbox: Option<Inbox<O>>,
     sender: Sender<O>,
+    thread: Option<JoinHandle<()>>,
 }
 
-impl<O> ServiceInstance<O> {
+impl<O: 'static + Operation + Send> ServiceInstance<O> {
     pub fn new() -> ServiceInstance<O> {
         let (sender, receiver) = channel();
         ServiceInstance {
-            receiver: Some(receiver),
+            inbox: Some(Inbox::new(receiver)),
             sender,
+            thread: None,
         }
     }
 
@@ -19,28 +41,216 @@ impl<O> ServiceInstance<O> {
         self.sender.clone()
     }
 
-    pub fn receiver(&mut self) -> Receiver<O> {
-        match self.receiver.take() {
-            Some(receiver) => receiver,
+    // Runs the service event loop in its own thread, starting it over whenever it panics for as
+    // long as the policy allows. Escalating is done by asking the node to shut down
+    pub fn supervise<F: 'static + Fn(&Inbox<O>) + Send>(
+        &mut self,
+        name: &'static str,
+        on_panic: OnPanic,
+        shutdown: Sender<()>,
+        service: F,
+    ) {
+        let inbox = match self.inbox.take() {
+            Some(inbox) => inbox,
             _ => {
                 panic!("failed to extract receiver from service- is it already on?");
             }
+        };
+        let thread = thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                let mut restarts = Vec::<Instant>::new();
+                while let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| service(&inbox))) {
+                    error!(
+                        "Service {} panicked handling {}: {}",
+                        name,
+                        inbox.last().unwrap_or("nothing"),
+                        panic_message(&payload)
+                    );
+                    restarts.retain(|restarted_at| restarted_at.elapsed() < RESTART_WINDOW);
+                    if on_panic == OnPanic::Shutdown || restarts.len() >= MAX_RESTARTS {
+                        error!("Shutting down after losing service {}", name);
+                        let _ = shutdown.send(());
+                        // Everyone else still holds a sender and unwraps their sends, so the
+                        // channel is kept open and drained until the node gets round to stopping
+                        // us rather than having every other service panic in turn
+                        inbox.drain_until_stopped();
+                        return;
+                    }
+                    warn!("Restarting service {}", name);
+                    restarts.push(Instant::now());
+                }
+            })
+            .expect("failed to spawn service thread");
+        self.thread = Some(thread);
+    }
+
+    // Waits for the service to work through everything already sent to it before returning
+    pub fn stop(&mut self) {
+        let _ = self.sender.send(O::stop());
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("A service panicked while shutting down");
+            }
         }
     }
 }
 
+// The receiving end of a service's channel. It outlives any one run of the service, and remembers
+// the last operation it handed out so a panic can be traced back to it
+pub struct Inbox<O> {
+    receiver: Receiver<O>,
+    last: Cell<Option<&'static str>>,
+}
+
+impl<O: Operation> Inbox<O> {
+    fn new(receiver: Receiver<O>) -> Inbox<O> {
+        Inbox {
+            receiver,
+            last: Cell::new(None),
+        }
+    }
+
+    pub fn recv(&self) -> Result<O, RecvError> {
+        let msg = self.receiver.recv()?;
+        self.last.set(Some(msg.name()));
+        Ok(msg)
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<O, RecvTimeoutError> {
+        let msg = self.receiver.recv_timeout(timeout)?;
+        self.last.set(Some(msg.name()));
+        Ok(msg)
+    }
+
+    fn last(&self) -> Option<&'static str> {
+        self.last.get()
+    }
+
+    fn drain_until_stopped(&self) {
+        let stop = O::stop().name();
+        while let Ok(msg) = self.recv() {
+            if msg.name() == stop {
+                break;
+            }
+            warn!("Dropping {} sent to a lost service", msg.name());
+        }
+    }
+}
+
+fn panic_message(payload: &Box<dyn Any + Send>) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
 // 1. Create the service instance struct (which creates a channel for you)
-// 2. Run the service event loop method with a clone of the sender of all services it depends on
+// 2. Run the service event loop method under supervision with a clone of the sender of all
+//    services it depends on, followed by a clone of any additional arguments (such as the node
+//    config) it was given. Every restart gets fresh clones
 macro_rules! define_services {
-    ($( (module: $service:path, name: $service_instance:ident, dependencies: [$($dependency:ident),*])),*) => (
+    (shutdown: $shutdown:ident, $( (module: $service:path, name: $service_instance:ident, dependencies: [$($dependency:ident),*], on_panic: $on_panic:ident $(, arguments: [$($argument:ident),*])?)),*) => (
         $(let mut $service_instance = ServiceInstance::new();)*
         $(
             paste::expr! {
                 $(let [<$dependency _clone>] = $dependency.sender(););*
+                $($(let [<$argument _clone>] = $argument.clone(););*)?
                 let sender = $service_instance.sender();
-                let receiver = $service_instance.receiver();
-                thread::spawn(move || $service(receiver, sender, $({[<$dependency _clone>]}),*));
+                $service_instance.supervise(
+                    stringify!($service_instance),
+                    OnPanic::$on_panic,
+                    $shutdown.clone(),
+                    move |inbox| $service(inbox, sender.clone() $(, [<$dependency _clone>].clone())* $($(, [<$argument _clone>].clone())*)?),
+                );
             }
         )*
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    enum Operations {
+        Panic,
+        Echo(Sender<i32>),
+        Stop,
+    }
+
+    impl Operation for Operations {
+        fn stop() -> Operations {
+            Operations::Stop
+        }
+
+        fn name(&self) -> &'static str {
+            match self {
+                Operations::Panic => "Panic",
+                Operations::Echo(_) => "Echo",
+                Operations::Stop => "Stop",
+            }
+        }
+    }
+
+    fn service(inbox: &Inbox<Operations>) {
+        while let Ok(msg) = inbox.recv() {
+            match msg {
+                Operations::Panic => panic!("asked to"),
+                Operations::Echo(reply) => reply.send(1).unwrap(),
+                Operations::Stop => break,
+            }
+        }
+    }
+
+    #[test]
+    fn restarted_services_keep_their_channel() {
+        let (shutdown, shutdown_requests) = channel();
+        let mut instance = ServiceInstance::new();
+        instance.supervise("test", OnPanic::Restart, shutdown, service);
+        let (reply, echoed) = channel();
+        instance.sender().send(Operations::Panic).unwrap();
+        instance.sender().send(Operations::Echo(reply)).unwrap();
+        assert_eq!(Ok(1), echoed.recv());
+        instance.stop();
+        assert!(shutdown_requests.try_recv().is_err());
+    }
+
+    #[test]
+    fn services_that_cannot_restart_shut_the_node_down() {
+        let (shutdown, shutdown_requests) = channel();
+        let mut instance = ServiceInstance::new();
+        instance.supervise("test", OnPanic::Shutdown, shutdown, service);
+        instance.sender().send(Operations::Panic).unwrap();
+        assert_eq!(Ok(()), shutdown_requests.recv());
+        instance.stop();
+    }
+
+    #[test]
+    fn lost_services_keep_taking_messages_until_stopped() {
+        let (shutdown, shutdown_requests) = channel();
+        let mut instance = ServiceInstance::new();
+        instance.supervise("test", OnPanic::Shutdown, shutdown, service);
+        instance.sender().send(Operations::Panic).unwrap();
+        assert_eq!(Ok(()), shutdown_requests.recv());
+        // Late messages from other services are dropped rather than failing to send
+        let (reply, echoed) = channel();
+        assert!(instance.sender().send(Operations::Echo(reply)).is_ok());
+        instance.stop();
+        assert!(echoed.recv().is_err());
+    }
+
+    #[test]
+    fn services_that_keep_panicking_shut_the_node_down() {
+        let (shutdown, shutdown_requests) = channel();
+        let mut instance = ServiceInstance::new();
+        instance.supervise("test", OnPanic::Restart, shutdown, service);
+        for _ in 0..=MAX_RESTARTS {
+            instance.sender().send(Operations::Panic).unwrap();
+        }
+        assert_eq!(Ok(()), shutdown_requests.recv());
+        instance.stop();
+    }
+}
@@ -1,21 +1,122 @@
-use super::interfaces::messenger::{Messenger, SubscriberType};
-use super::packet::{KeepAlive, Packet};
-use std::sync::mpsc::{Receiver, Sender};
-use std::thread::sleep;
-use std::time;
+use super::instance::Inbox;
+use super::interfaces::connection::ConnectionService;
+use super::interfaces::keep_alive::Operations;
+use super::interfaces::messenger::Messenger;
+use super::interfaces::player::PlayerState;
+use super::minecraft_types::ChatComponent;
+use super::packet::{ClientboundKeepAlive, Packet};
+use std::collections::HashMap;
+use std::sync::mpsc::{RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
 
-const KEEP_ALIVE_PERIOD: u64 = 15;
-const KEEP_ALIVE_VALUE: i64 = 16;
+const KEEP_ALIVE_PERIOD: Duration = Duration::from_secs(15);
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(30);
+const CHECK_PERIOD: Duration = Duration::from_secs(1);
+
+struct KeepAlive {
+    sent_at: Instant,
+    pending_id: Option<i64>,
+    latency: i32,
+}
+
+// Every player in the play state is sent a keep alive with a fresh random id every 15 seconds.
+// The time it takes them to echo it back is their latency, and players who leave one unanswered
+// for 30 seconds are disconnected
+pub fn start<M: Messenger, P: PlayerState, CS: ConnectionService>(
+    receiver: &Inbox<Operations>,
+    _sender: Sender<Operations>,
+    messenger: M,
+    player_state: P,
+    connection_service: CS,
+) {
+    let mut keep_alives = HashMap::<Uuid, KeepAlive>::new();
 
-pub fn start<M: Messenger>(_: Receiver<i32>, _: Sender<i32>, messenger: M) {
     loop {
-        sleep(time::Duration::from_secs(KEEP_ALIVE_PERIOD));
-        messenger.broadcast(
-            Packet::KeepAlive(KeepAlive {
-                id: KEEP_ALIVE_VALUE,
-            }),
-            None,
-            SubscriberType::Local,
-        );
+        match receiver.recv_timeout(CHECK_PERIOD) {
+            Ok(Operations::Track(msg)) => {
+                trace!("Tracking keep alives for conn_id {:?}", msg.conn_id);
+                keep_alives.insert(msg.conn_id, send_keep_alive(msg.conn_id, &messenger, 0));
+            }
+            Ok(Operations::Response(msg)) => {
+                let keep_alive = match keep_alives.get_mut(&msg.conn_id) {
+                    Some(keep_alive) => keep_alive,
+                    None => continue,
+                };
+                if keep_alive.pending_id != Some(msg.id) {
+                    warn!(
+                        "Ignoring keep alive {} from {:?}, expected {:?}",
+                        msg.id, msg.conn_id, keep_alive.pending_id
+                    );
+                    continue;
+                }
+                keep_alive.pending_id = None;
+                keep_alive.latency =
+                    smooth_latency(keep_alive.latency, keep_alive.sent_at.elapsed());
+                trace!(
+                    "Latency for conn_id {:?} is now {}ms",
+                    msg.conn_id,
+                    keep_alive.latency
+                );
+                player_state.update_latency(msg.conn_id, keep_alive.latency);
+            }
+            Ok(Operations::Untrack(msg)) => {
+                keep_alives.remove(&msg.conn_id);
+            }
+            Ok(Operations::Stop(_)) | Err(RecvTimeoutError::Disconnected) => return,
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+
+        let timed_out: Vec<Uuid> = keep_alives
+            .iter()
+            .filter(|(_, keep_alive)| {
+                keep_alive.pending_id.is_some()
+                    && keep_alive.sent_at.elapsed() >= KEEP_ALIVE_TIMEOUT
+            })
+            .map(|(conn_id, _)| *conn_id)
+            .collect();
+        for conn_id in timed_out {
+            warn!("Keep alive timed out for {:?}", conn_id);
+            keep_alives.remove(&conn_id);
+            connection_service.disconnect(conn_id, ChatComponent::text("Timed out"));
+        }
+
+        for (conn_id, keep_alive) in keep_alives.iter_mut() {
+            if keep_alive.pending_id.is_none() && keep_alive.sent_at.elapsed() >= KEEP_ALIVE_PERIOD
+            {
+                *keep_alive = send_keep_alive(*conn_id, &messenger, keep_alive.latency);
+            }
+        }
+    }
+}
+
+fn send_keep_alive<M: Messenger>(conn_id: Uuid, messenger: &M, latency: i32) -> KeepAlive {
+    let id = rand::random::<i64>();
+    messenger.send_packet(
+        conn_id,
+        Packet::ClientboundKeepAlive(ClientboundKeepAlive { id }),
+    );
+    KeepAlive {
+        sent_at: Instant::now(),
+        pending_id: Some(id),
+        latency,
+    }
+}
+
+// Weighted the same way as vanilla, so a single slow response doesn't make the ping jump around
+fn smooth_latency(latency: i32, round_trip: Duration) -> i32 {
+    let round_trip = round_trip.as_millis().min(i32::MAX as u128) as i32;
+    (latency * 3 + round_trip) / 4
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latency_is_smoothed() {
+        assert_eq!(100, smooth_latency(100, Duration::from_millis(100)));
+        assert_eq!(25, smooth_latency(0, Duration::from_millis(100)));
+        assert_eq!(175, smooth_latency(200, Duration::from_millis(100)));
     }
 }
@@ -0,0 +1,265 @@
+use super::config::Config;
+use super::interfaces::keep_alive::KeepAliveService;
+use super::interfaces::login::{LoginService, Operations};
+use super::interfaces::messenger::{Messenger, SubscriberType};
+use super::interfaces::packet_processor::PacketProcessor;
+use super::interfaces::player::{Angle, Player, PlayerState, Position};
+
+use super::encryption::{server_hash, ServerKey};
+use super::instance::Inbox;
+use super::minecraft_types::ChatComponent;
+use super::packet;
+use super::packet::Packet;
+use super::session::{offline_uuid, GameProfile, SessionVerifier};
+use super::translation::TranslationUpdates;
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+use uuid::Uuid;
+
+// Vanilla servers have sent an empty server id ever since 1.7
+const SERVER_ID: &str = "";
+
+struct PendingLogin {
+    username: String,
+    verify_token: Vec<u8>,
+}
+
+struct OnlineMode {
+    server_key: ServerKey,
+    session_verifier: Arc<dyn SessionVerifier>,
+}
+
+// Takes a connection from Login Start to Login Success. In offline mode that happens straight
+// away, in online mode only once the client has answered our encryption request and the session
+// verifier has confirmed who they are. Asking the session verifier can mean waiting on Mojang, so
+// that's done on a thread of its own and the answer comes back as a message
+#[allow(clippy::too_many_arguments)]
+pub fn start<M: Messenger + Clone, P: PlayerState, PP: PacketProcessor, K: KeepAliveService>(
+    receiver: &Inbox<Operations>,
+    sender: Sender<Operations>,
+    messenger: M,
+    player_state: P,
+    inbound_packet_processor: PP,
+    keep_alive: K,
+    config: Config,
+    session_verifier: Option<Arc<dyn SessionVerifier>>,
+) {
+    let online_mode = session_verifier.map(|session_verifier| OnlineMode {
+        server_key: ServerKey::generate().expect("Failed to generate the server key"),
+        session_verifier,
+    });
+    let mut pending_logins = HashMap::<Uuid, PendingLogin>::new();
+    let mut verifying = HashSet::<Uuid>::new();
+
+    while let Ok(msg) = receiver.recv() {
+        match msg {
+            Operations::Stop(_) => break,
+            Operations::Start(msg) => match &online_mode {
+                Some(online_mode) => {
+                    trace!("Requesting encryption from conn_id {:?}", msg.conn_id);
+                    let verify_token = rand::random::<[u8; 4]>().to_vec();
+                    messenger.send_packet(
+                        msg.conn_id,
+                        Packet::EncryptionRequest(packet::EncryptionRequest {
+                            server_id: String::from(SERVER_ID),
+                            public_key: online_mode.server_key.public_key_der.clone(),
+                            verify_token: verify_token.clone(),
+                        }),
+                    );
+                    pending_logins.insert(
+                        msg.conn_id,
+                        PendingLogin {
+                            username: msg.username,
+                            verify_token,
+                        },
+                    );
+                }
+                None => {
+                    let profile = GameProfile {
+                        id: offline_uuid(&msg.username),
+                        name: msg.username,
+                        properties: Vec::new(),
+                    };
+                    admit(msg.conn_id, profile, &player_state);
+                }
+            },
+            Operations::EncryptionResponse(msg) => {
+                let conn_id = msg.conn_id;
+                let (online_mode, pending_login) =
+                    match (&online_mode, pending_logins.remove(&msg.conn_id)) {
+                        (Some(online_mode), Some(pending_login)) => (online_mode, pending_login),
+                        _ => {
+                            warn!("Unexpected encryption response from {:?}", msg.conn_id);
+                            messenger.disconnect(
+                                msg.conn_id,
+                                ChatComponent::text("Unexpected encryption response"),
+                            );
+                            continue;
+                        }
+                    };
+                match decrypt(
+                    online_mode,
+                    &pending_login,
+                    msg.shared_secret,
+                    msg.verify_token,
+                    |shared_secret| messenger.enable_encryption(conn_id, shared_secret),
+                ) {
+                    Ok(hash) => {
+                        verifying.insert(conn_id);
+                        verify(
+                            conn_id,
+                            pending_login.username,
+                            hash,
+                            online_mode.session_verifier.clone(),
+                            sender.clone(),
+                        );
+                    }
+                    Err(reason) => reject(conn_id, &reason, &messenger),
+                }
+            }
+            // Players who left while being verified are forgotten about
+            Operations::Verified(msg) => {
+                if verifying.remove(&msg.conn_id) {
+                    match msg.profile {
+                        Ok(profile) => admit(msg.conn_id, profile, &player_state),
+                        Err(reason) => reject(msg.conn_id, &reason, &messenger),
+                    }
+                }
+            }
+            Operations::Admitted(msg) => {
+                info!("{} joined the game", msg.player.name);
+                complete_login(
+                    msg.conn_id,
+                    msg.player,
+                    messenger.clone(),
+                    &player_state,
+                    &inbound_packet_processor,
+                    &config,
+                );
+                keep_alive.track(msg.conn_id);
+            }
+            Operations::Abandon(msg) => {
+                pending_logins.remove(&msg.conn_id);
+                verifying.remove(&msg.conn_id);
+            }
+        }
+    }
+}
+
+// Everything after a successful encryption response is encrypted, so encryption is enabled as
+// soon as we know the shared secret and before we ask the session verifier about the player. What
+// we ask it with is the server hash
+fn decrypt<F: FnOnce(Vec<u8>)>(
+    online_mode: &OnlineMode,
+    pending_login: &PendingLogin,
+    encrypted_shared_secret: Vec<u8>,
+    encrypted_verify_token: Vec<u8>,
+    enable_encryption: F,
+) -> Result<String, String> {
+    let verify_token = online_mode
+        .server_key
+        .decrypt(&encrypted_verify_token)
+        .map_err(|e| format!("could not decrypt verify token: {}", e))?;
+    if verify_token != pending_login.verify_token {
+        return Err(String::from("verify token did not match"));
+    }
+    let shared_secret = online_mode
+        .server_key
+        .decrypt_shared_secret(&encrypted_shared_secret)
+        .map_err(|e| format!("could not decrypt shared secret: {}", e))?;
+    let hash = server_hash(
+        SERVER_ID,
+        &shared_secret,
+        &online_mode.server_key.public_key_der,
+    );
+    enable_encryption(shared_secret);
+    Ok(hash)
+}
+
+fn verify(
+    conn_id: Uuid,
+    username: String,
+    hash: String,
+    session_verifier: Arc<dyn SessionVerifier>,
+    login_service: Sender<Operations>,
+) {
+    thread::spawn(move || {
+        let profile = session_verifier
+            .has_joined(&username, &hash)
+            .map_err(|e| e.to_string());
+        login_service.verified(conn_id, profile);
+    });
+}
+
+fn reject<M: Messenger>(conn_id: Uuid, reason: &str, messenger: &M) {
+    warn!("Rejecting login from {:?}: {}", conn_id, reason);
+    messenger.disconnect(conn_id, ChatComponent::text(reason));
+}
+
+// Player state decides whether there is room for the player, and tells us once they're admitted
+fn admit<P: PlayerState>(conn_id: Uuid, profile: GameProfile, player_state: &P) {
+    let player = Player {
+        conn_id,
+        uuid: profile.id,
+        name: profile.name,
+        properties: profile.properties,
+        entity_id: 0, // replaced by player state
+        ping: 0,
+        position: Position {
+            x: 10.0,
+            y: 80.0,
+            z: 10.0,
+        },
+        angle: Angle {
+            pitch: 0.0,
+            yaw: 0.0,
+        },
+    };
+    player_state.admit(conn_id, player);
+}
+
+// The packet processor is told about the new state before anything is sent, so the first packets
+// the client sends in the play state can't be read with the login state
+fn complete_login<M: Messenger + Clone, P: PlayerState, PP: PacketProcessor>(
+    conn_id: Uuid,
+    player: Player,
+    messenger: M,
+    player_state: &P,
+    inbound_packet_processor: &PP,
+    config: &Config,
+) {
+    let mut translation_updates = Vec::new();
+    if let Some(threshold) = config.compression_threshold {
+        translation_updates.push(TranslationUpdates::Compression(threshold));
+    }
+    translation_updates.push(TranslationUpdates::State(3));
+    inbound_packet_processor.set_translation_data(conn_id, translation_updates);
+
+    if let Some(threshold) = config.compression_threshold {
+        enable_compression(conn_id, messenger.clone(), threshold);
+    }
+
+    //protocol
+    login_success(conn_id, messenger.clone(), player);
+    player_state.login(conn_id);
+    messenger.subscribe(conn_id, SubscriberType::All);
+}
+
+// Set Compression has to be the last uncompressed packet we send on this connection
+fn enable_compression<M: Messenger>(conn_id: Uuid, messenger: M, threshold: i32) {
+    messenger.send_packet(
+        conn_id,
+        Packet::SetCompression(packet::SetCompression { threshold }),
+    );
+    messenger.enable_compression(conn_id, threshold);
+}
+
+fn login_success<M: Messenger>(conn_id: Uuid, messenger: M, player: Player) {
+    let login_success = packet::LoginSuccess {
+        uuid: player.uuid.to_hyphenated().to_string(),
+        username: player.name,
+    };
+    messenger.send_packet(conn_id, Packet::LoginSuccess(login_success));
+}
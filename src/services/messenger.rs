@@ -1,35 +1,60 @@
 use super::super::interfaces::messenger::{Operations, SubscriberType};
-use super::packet::{translate_outgoing, write, Packet};
+use super::capture::Capture;
+use super::config::Config;
+use super::encryption::{DecryptionSlot, Encryptor};
+use super::instance::Inbox;
+use super::minecraft_types::ChatComponent;
+use super::outbound::Outbound;
+use super::packet::{translate_outgoing, write, LoginDisconnect, Packet, PlayDisconnect};
+use super::protocol_version::ProtocolVersion;
 use super::translation::TranslationInfo;
 
 use std::collections::{HashMap, HashSet};
-use std::net::TcpStream;
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
-pub fn start(receiver: Receiver<Operations>, _sender: Sender<Operations>) {
-    let mut connection_map = HashMap::<Uuid, TcpStream>::new();
+// Sending a player the world can fill their queue for a moment, so a connection is only given up
+// on once it has been at the disconnect threshold for this long
+const BACKLOG_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+pub fn start(
+    receiver: &Inbox<Operations>,
+    _sender: Sender<Operations>,
+    config: Config,
+    capture: Option<Capture>,
+) {
+    let limits = OutboundLimits::from(&config);
+    let mut connection_map = HashMap::<Uuid, Connection>::new();
     let mut subscriber_list = SubscriberList::new();
     let mut translation_data = HashMap::<Uuid, TranslationInfo>::new();
 
     while let Ok(msg) = receiver.recv() {
         match msg {
+            Operations::Stop(_) => break,
             Operations::Send(msg) => {
                 trace!(
                     "Sending packet {:?} to conn_id {:?}",
                     msg.packet.debug_print_type(),
                     msg.conn_id
                 );
-                if let Some(socket) = connection_map.get(&msg.conn_id) {
-                    let mut socket_clone = socket.try_clone().unwrap();
+                if let Some(connection) = connection_map.get_mut(&msg.conn_id) {
                     let translated_packet = match translation_data.get(&msg.conn_id) {
                         Some(translation_data) => {
                             translate_outgoing(msg.packet, translation_data.clone())
                         }
                         None => msg.packet,
                     };
-                    write(&mut socket_clone, translated_packet);
-                    trace!("Send successful");
+                    match connection.write(translated_packet, &limits) {
+                        Ok(()) => trace!("Send successful"),
+                        Err(backlog) => evict(
+                            msg.conn_id,
+                            backlog,
+                            &mut connection_map,
+                            &mut translation_data,
+                            &mut subscriber_list,
+                        ),
+                    }
                 } else {
                     trace!("Connection ID not found");
                 }
@@ -41,15 +66,29 @@ pub fn start(receiver: Receiver<Operations>, _sender: Sender<Operations>) {
                     msg.subscriber_type,
                 );
                 let receipients: HashSet<Uuid> = subscriber_list.receipients(msg.subscriber_type);
-                if let Some(source) = msg.source_conn_id {
+                let backlogged = if let Some(source) = msg.source_conn_id {
                     let filtered_receipients: HashSet<Uuid> = receipients
                         .iter()
                         .filter(|conn_id| **conn_id != source)
                         .copied()
                         .collect();
-                    broadcast(msg.packet, filtered_receipients, &connection_map)
+                    broadcast(
+                        msg.packet,
+                        filtered_receipients,
+                        &mut connection_map,
+                        &limits,
+                    )
                 } else {
-                    broadcast(msg.packet, receipients, &connection_map)
+                    broadcast(msg.packet, receipients, &mut connection_map, &limits)
+                };
+                for (conn_id, backlog) in backlogged {
+                    evict(
+                        conn_id,
+                        backlog,
+                        &mut connection_map,
+                        &mut translation_data,
+                        &mut subscriber_list,
+                    );
                 }
             }
             Operations::Subscribe(msg) => {
@@ -71,19 +110,76 @@ pub fn start(receiver: Receiver<Operations>, _sender: Sender<Operations>) {
                     }
                 }
             }
+            Operations::UpdateState(msg) => {
+                if let Some(connection) = connection_map.get_mut(&msg.conn_id) {
+                    connection.state = msg.state;
+                }
+            }
+            Operations::UpdateProtocolVersion(msg) => {
+                if let Some(connection) = connection_map.get_mut(&msg.conn_id) {
+                    connection.version = msg.version;
+                }
+            }
+            Operations::Disconnect(msg) => {
+                trace!(
+                    "Disconnecting conn_id {:?} with reason {:?}",
+                    msg.conn_id,
+                    msg.reason
+                );
+                disconnect(
+                    msg.conn_id,
+                    &msg.reason,
+                    &mut connection_map,
+                    &mut translation_data,
+                    &mut subscriber_list,
+                );
+            }
+            // Peers see their connections to us close, which is how they learn to drop our map
+            Operations::DisconnectAll(msg) => {
+                trace!("Disconnecting everyone with reason {:?}", msg.reason);
+                let conn_ids: Vec<Uuid> = connection_map.keys().copied().collect();
+                for conn_id in conn_ids {
+                    disconnect(
+                        conn_id,
+                        &msg.reason,
+                        &mut connection_map,
+                        &mut translation_data,
+                        &mut subscriber_list,
+                    );
+                }
+            }
             Operations::Close(msg) => {
                 trace!("Closing connection {:?}", msg.conn_id);
-                connection_map.remove(&msg.conn_id);
-                translation_data.remove(&msg.conn_id);
-                subscriber_list.remove(&msg.conn_id);
+                close(
+                    msg.conn_id,
+                    &mut connection_map,
+                    &mut translation_data,
+                    &mut subscriber_list,
+                );
             }
             Operations::New(msg) => {
+                trace!("New Connection with conn_id {:?}", msg.conn_id);
+                connection_map.insert(
+                    msg.conn_id,
+                    Connection::new(msg.conn_id, msg.outbound, msg.decryption, capture.clone()),
+                );
+            }
+            Operations::EnableCompression(msg) => {
                 trace!(
-                    "New Connection with conn_id {:?} on socket {:?}",
+                    "Enabling compression for conn_id {:?} with threshold {:?}",
                     msg.conn_id,
-                    msg.socket
+                    msg.threshold
                 );
-                connection_map.insert(msg.conn_id, msg.socket);
+                if let Some(connection) = connection_map.get_mut(&msg.conn_id) {
+                    connection.compression_threshold = Some(msg.threshold);
+                }
+            }
+            Operations::EnableEncryption(msg) => {
+                trace!("Enabling encryption for conn_id {:?}", msg.conn_id);
+                if let Some(connection) = connection_map.get_mut(&msg.conn_id) {
+                    connection.encryptor = Some(Encryptor::new(&msg.shared_secret));
+                    connection.decryption.enable(&msg.shared_secret);
+                }
             }
             Operations::UpdateTranslation(msg) => {
                 trace!(
@@ -96,6 +192,8 @@ pub fn start(receiver: Receiver<Operations>, _sender: Sender<Operations>) {
                     TranslationInfo {
                         state: 0,
                         map: msg.map,
+                        compression_threshold: None,
+                        protocol_version: 0,
                     },
                 );
             }
@@ -103,18 +201,196 @@ pub fn start(receiver: Receiver<Operations>, _sender: Sender<Operations>) {
     }
 }
 
-fn broadcast<'a, I: IntoIterator<Item = Uuid>>(
+// How far behind a connection can fall, in bytes queued but not yet picked up by the network.
+// Past the drop threshold packets that the next one makes up for are skipped. Nothing more is
+// queued past the disconnect threshold, and a connection that stays there for the grace period is
+// given up on
+struct OutboundLimits {
+    drop_threshold: usize,
+    disconnect_threshold: usize,
+    grace_period: Duration,
+}
+
+impl From<&Config> for OutboundLimits {
+    fn from(config: &Config) -> OutboundLimits {
+        OutboundLimits {
+            drop_threshold: config.outbound_drop_threshold,
+            disconnect_threshold: config.outbound_disconnect_threshold,
+            grace_period: BACKLOG_GRACE_PERIOD,
+        }
+    }
+}
+
+// The bytes a connection had queued when it was found to be too far behind
+#[derive(Debug)]
+struct Backlogged(usize);
+
+// Movement is sent continuously, so a client that misses some only sees the entity skip ahead
+fn is_droppable(packet: &Packet) -> bool {
+    matches!(
+        packet,
+        Packet::EntityLookAndMove(_)
+            | Packet::EntityTeleport(_)
+            | Packet::EntityHeadLook(_)
+            | Packet::PlayerPosition(_)
+            | Packet::PlayerLook(_)
+            | Packet::PlayerPositionAndLook(_)
+    )
+}
+
+// Only the login and play states have a way of telling the client why
+fn disconnect(
+    conn_id: Uuid,
+    reason: &ChatComponent,
+    connection_map: &mut HashMap<Uuid, Connection>,
+    translation_data: &mut HashMap<Uuid, TranslationInfo>,
+    subscriber_list: &mut SubscriberList,
+) {
+    if let Some(connection) = connection_map.get_mut(&conn_id) {
+        // The connection is closed either way, so this skips the outbound limits
+        match connection.state {
+            2 => connection.write_unbounded(Packet::LoginDisconnect(LoginDisconnect {
+                reason: reason.to_json(),
+            })),
+            3 => connection.write_unbounded(Packet::PlayDisconnect(PlayDisconnect {
+                reason: reason.to_json(),
+            })),
+            _ => {}
+        }
+    }
+    close(conn_id, connection_map, translation_data, subscriber_list);
+}
+
+fn close(
+    conn_id: Uuid,
+    connection_map: &mut HashMap<Uuid, Connection>,
+    translation_data: &mut HashMap<Uuid, TranslationInfo>,
+    subscriber_list: &mut SubscriberList,
+) {
+    if let Some(connection) = connection_map.remove(&conn_id) {
+        connection.outbound.close();
+    }
+    translation_data.remove(&conn_id);
+    subscriber_list.remove(&conn_id);
+}
+
+// Whatever is still queued for the connection is thrown away. Closing the socket is what lets the
+// connection service clean up after it, as with any other connection that goes away
+fn evict(
+    conn_id: Uuid,
+    backlog: Backlogged,
+    connection_map: &mut HashMap<Uuid, Connection>,
+    translation_data: &mut HashMap<Uuid, TranslationInfo>,
+    subscriber_list: &mut SubscriberList,
+) {
+    warn!(
+        "Disconnecting {:?}, it fell {} bytes behind",
+        conn_id, backlog.0
+    );
+    if let Some(connection) = connection_map.remove(&conn_id) {
+        connection.outbound.evict();
+    }
+    translation_data.remove(&conn_id);
+    subscriber_list.remove(&conn_id);
+}
+
+// Returns the recipients that fell too far behind, which the caller evicts once it's done
+fn broadcast<I: IntoIterator<Item = Uuid>>(
     packet: Packet,
     conn_ids: I,
-    connection_map: &'a HashMap<Uuid, TcpStream>,
-) {
-    conn_ids.into_iter().for_each(|conn_id| {
-        if let Some(socket) = connection_map.get(&conn_id) {
-            let mut socket_clone = socket.try_clone().unwrap();
-            let packet_clone = packet.clone();
-            write(&mut socket_clone, packet_clone);
+    connection_map: &mut HashMap<Uuid, Connection>,
+    limits: &OutboundLimits,
+) -> Vec<(Uuid, Backlogged)> {
+    conn_ids
+        .into_iter()
+        .filter_map(|conn_id| {
+            let connection = connection_map.get_mut(&conn_id)?;
+            connection
+                .write(packet.clone(), limits)
+                .err()
+                .map(|backlog| (conn_id, backlog))
+        })
+        .collect()
+}
+
+// The decryption slot is shared with the thread reading from the socket, so that both halves of
+// the connection start using the cipher at the same time
+struct Connection {
+    conn_id: Uuid,
+    outbound: Outbound,
+    state: i32,
+    version: ProtocolVersion,
+    compression_threshold: Option<i32>,
+    encryptor: Option<Encryptor>,
+    decryption: DecryptionSlot,
+    capture: Option<Capture>,
+    backed_up_since: Option<Instant>,
+}
+
+impl Connection {
+    fn new(
+        conn_id: Uuid,
+        outbound: Outbound,
+        decryption: DecryptionSlot,
+        capture: Option<Capture>,
+    ) -> Connection {
+        Connection {
+            conn_id,
+            outbound,
+            state: 0,
+            version: ProtocolVersion::NATIVE,
+            compression_threshold: None,
+            encryptor: None,
+            decryption,
+            capture,
+            backed_up_since: None,
+        }
+    }
+
+    fn write(&mut self, packet: Packet, limits: &OutboundLimits) -> Result<(), Backlogged> {
+        let queued = self.outbound.queued();
+        if queued < limits.disconnect_threshold {
+            self.backed_up_since = None;
+        } else {
+            let backed_up_since = *self.backed_up_since.get_or_insert_with(Instant::now);
+            if backed_up_since.elapsed() >= limits.grace_period {
+                return Err(Backlogged(queued));
+            }
+            trace!(
+                "Refusing {} for a connection {} bytes behind",
+                packet.debug_print_type(),
+                queued
+            );
+            return Ok(());
+        }
+        if queued >= limits.drop_threshold && is_droppable(&packet) {
+            trace!(
+                "Dropping {} for a backed up connection",
+                packet.debug_print_type()
+            );
+            return Ok(());
+        }
+        self.write_unbounded(packet);
+        Ok(())
+    }
+
+    fn write_unbounded(&mut self, packet: Packet) {
+        let mut bytes = Vec::new();
+        write(&mut bytes, packet, self.compression_threshold, self.version);
+        if let Some(capture) = &self.capture {
+            capture.outbound(
+                self.conn_id,
+                self.state,
+                self.version,
+                self.compression_threshold.is_some(),
+                &bytes,
+            );
+        }
+        if let Some(encryptor) = self.encryptor.as_mut() {
+            encryptor.encrypt(&mut bytes);
         }
-    });
+        self.outbound.send(bytes);
+    }
 }
 
 struct SubscriberList {
@@ -155,3 +431,96 @@ impl SubscriberList {
         self.remote_subscribers.remove(uuid);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::packet::{ClientboundChatMessage, EntityTeleport};
+    use super::*;
+    use std::thread::sleep;
+
+    const LIMITS: OutboundLimits = OutboundLimits {
+        drop_threshold: 100,
+        disconnect_threshold: 200,
+        grace_period: Duration::from_millis(50),
+    };
+
+    fn chat() -> Packet {
+        Packet::ClientboundChatMessage(ClientboundChatMessage {
+            message: "x".repeat(40),
+            position: 0,
+        })
+    }
+
+    fn movement() -> Packet {
+        Packet::EntityTeleport(EntityTeleport {
+            entity_id: 0,
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+            yaw: 0,
+            pitch: 0,
+            on_ground: true,
+        })
+    }
+
+    #[test]
+    fn movement_is_shed_first() {
+        let (outbound, _receiver) = Outbound::channel();
+        let mut connection = Connection::new(
+            Uuid::new_v4(),
+            outbound.clone(),
+            DecryptionSlot::default(),
+            None,
+        );
+        while outbound.queued() < LIMITS.drop_threshold {
+            connection.write(chat(), &LIMITS).unwrap();
+        }
+        let queued = outbound.queued();
+        connection.write(movement(), &LIMITS).unwrap();
+        assert_eq!(queued, outbound.queued());
+        connection.write(chat(), &LIMITS).unwrap();
+        assert!(outbound.queued() > queued);
+    }
+
+    #[tokio::test]
+    async fn short_bursts_are_tolerated() {
+        let (outbound, mut receiver) = Outbound::channel();
+        let mut connection = Connection::new(
+            Uuid::new_v4(),
+            outbound.clone(),
+            DecryptionSlot::default(),
+            None,
+        );
+        for _ in 0..20 {
+            connection.write(chat(), &LIMITS).unwrap();
+        }
+        // The queue stops growing at the disconnect threshold rather than taking everything
+        let queued = outbound.queued();
+        assert!(queued >= LIMITS.disconnect_threshold);
+        assert!(queued < LIMITS.disconnect_threshold + 50);
+
+        while outbound.queued() > 0 {
+            receiver.recv().await;
+        }
+        sleep(LIMITS.grace_period);
+        assert!(connection.write(chat(), &LIMITS).is_ok());
+        assert!(connection.backed_up_since.is_none());
+    }
+
+    #[test]
+    fn connections_that_stay_backed_up_are_evicted() {
+        let (outbound, _receiver) = Outbound::channel();
+        let mut connection = Connection::new(
+            Uuid::new_v4(),
+            outbound.clone(),
+            DecryptionSlot::default(),
+            None,
+        );
+        while outbound.queued() < LIMITS.disconnect_threshold {
+            connection.write(chat(), &LIMITS).unwrap();
+        }
+        assert!(connection.write(chat(), &LIMITS).is_ok());
+        sleep(LIMITS.grace_period);
+        assert!(connection.write(chat(), &LIMITS).is_err());
+    }
+}
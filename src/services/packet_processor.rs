@@ -1,62 +1,112 @@
 use super::interfaces::block::BlockState;
+use super::interfaces::connection::ConnectionService;
+use super::interfaces::keep_alive::KeepAliveService;
+use super::interfaces::login::LoginService;
 use super::interfaces::messenger::Messenger;
 use super::interfaces::packet_processor::Operations;
 use super::interfaces::patchwork::PatchworkState;
 use super::interfaces::player::PlayerState;
 
-use super::packet::{read, translate};
+use super::capture::Capture;
+use super::compression::decompress;
+use super::instance::Inbox;
+use super::minecraft_types::ChatComponent;
+use super::packet::{read, translate, Packet, Status};
 use super::packet_handlers::packet_router;
+use super::protocol_error::ProtocolError;
+use super::protocol_version::ProtocolVersion;
 use super::translation::{TranslationInfo, TranslationUpdates};
 use std::collections::HashMap;
+use std::io::Cursor;
 
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::Sender;
 use uuid::Uuid;
 
+#[allow(clippy::too_many_arguments)]
 pub fn start_inbound<
     M: Messenger + Clone,
     P: PlayerState + Clone,
     PA: PatchworkState + Clone,
     B: BlockState + Clone,
+    L: LoginService + Clone,
+    CS: ConnectionService,
+    K: KeepAliveService + Clone,
 >(
-    receiver: Receiver<Operations>,
+    receiver: &Inbox<Operations>,
     _sender: Sender<Operations>,
     messenger: M,
     player_state: P,
     block_state: B,
     patchwork_state: PA,
+    login_service: L,
+    connection_service: CS,
+    keep_alive: K,
+    capture: Option<Capture>,
 ) {
     let mut translation_data = HashMap::<Uuid, TranslationInfo>::new();
 
     while let Ok(msg) = receiver.recv() {
         match msg {
+            Operations::Stop(_) => break,
             Operations::Inbound(msg) => {
-                trace!("Received packet from conn_id {:?}", msg.conn_id);
-                let translation_data = translation_data
-                    .entry(msg.conn_id)
-                    .or_insert_with(TranslationInfo::new);
+                let conn_id = msg.conn_id;
+                trace!("Received packet from conn_id {:?}", conn_id);
+                let translation_data = translation_data.entry(msg.conn_id).or_default();
+                // Frames are captured here rather than as they come off the socket, since this is
+                // where the state they were sent in is known
+                if let Some(capture) = &capture {
+                    capture.inbound(
+                        conn_id,
+                        translation_data.state,
+                        ProtocolVersion::negotiate(translation_data.protocol_version),
+                        translation_data.compression_threshold.is_some(),
+                        msg.cursor.get_ref(),
+                    );
+                }
 
-                let packet = read(&mut msg.cursor.clone(), translation_data.state);
-                let packet = translate(packet, translation_data.clone());
-                let translation_update = packet_router::route_packet(
-                    packet,
-                    translation_data.state,
-                    msg.conn_id,
-                    messenger.clone(),
-                    player_state.clone(),
-                    block_state.clone(),
-                    patchwork_state.clone(),
-                );
-                match translation_update {
-                    TranslationUpdates::NoChange => {}
-                    _ => {
-                        trace!(
-                            "Incoming translation update {:?} for conn_id {:?}",
-                            translation_update,
-                            msg.conn_id
+                let translation_updates =
+                    read_inbound(msg.cursor, translation_data).and_then(|packet| {
+                        packet_router::route_packet(
+                            packet,
+                            translation_data,
+                            conn_id,
+                            messenger.clone(),
+                            player_state.clone(),
+                            block_state.clone(),
+                            patchwork_state.clone(),
+                            login_service.clone(),
+                            keep_alive.clone(),
+                        )
+                    });
+                match translation_updates {
+                    Ok(translation_updates) => {
+                        translation_updates.iter().for_each(|translation_update| {
+                            match translation_update {
+                                TranslationUpdates::NoChange => {}
+                                _ => {
+                                    trace!(
+                                        "Incoming translation update {:?} for conn_id {:?}",
+                                        translation_update,
+                                        conn_id
+                                    );
+                                    apply_update(
+                                        conn_id,
+                                        translation_data,
+                                        translation_update,
+                                        &messenger,
+                                    );
+                                }
+                            }
+                        })
+                    }
+                    Err(e) => {
+                        warn!("Disconnecting {:?}: {}", conn_id, e);
+                        connection_service.disconnect(
+                            conn_id,
+                            ChatComponent::text(&format!("Bad packet: {}", e)),
                         );
                     }
                 }
-                translation_data.update(&translation_update);
             }
             Operations::SetTranslationData(msg) => {
                 trace!(
@@ -64,14 +114,43 @@ pub fn start_inbound<
                     msg.updates,
                     msg.conn_id
                 );
-                let data = translation_data
-                    .entry(msg.conn_id)
-                    .or_insert_with(TranslationInfo::new);
+                let data = translation_data.entry(msg.conn_id).or_default();
 
                 msg.updates.iter().for_each(|update| {
-                    data.update(update);
+                    apply_update(msg.conn_id, data, update, &messenger);
                 })
             }
         }
     }
 }
+
+// The messenger needs to know the state of each connection to pick the right disconnect packet,
+// and its version to write packets the way the client expects
+fn apply_update<M: Messenger>(
+    conn_id: Uuid,
+    translation_data: &mut TranslationInfo,
+    update: &TranslationUpdates,
+    messenger: &M,
+) {
+    if let TranslationUpdates::State(state) = update {
+        messenger.update_state(conn_id, *state);
+    }
+    if let TranslationUpdates::ProtocolVersion(version) = update {
+        messenger.update_protocol_version(conn_id, ProtocolVersion::negotiate(*version));
+    }
+    translation_data.update(update);
+}
+
+fn read_inbound(
+    cursor: Cursor<Vec<u8>>,
+    translation_data: &TranslationInfo,
+) -> Result<Packet, ProtocolError> {
+    let mut cursor = match translation_data.compression_threshold {
+        Some(_) => Cursor::new(decompress(cursor.into_inner())?),
+        None => cursor,
+    };
+    let status = Status::from_i32(translation_data.state)?;
+    let version = ProtocolVersion::negotiate(translation_data.protocol_version);
+    let packet = read(&mut cursor, status, status.inbound(), version)?;
+    Ok(translate(packet, translation_data.clone()))
+}
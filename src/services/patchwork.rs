@@ -1,134 +1,83 @@
+use super::config::Config;
+use super::instance::Inbox;
 use super::interfaces::block::BlockState;
 use super::interfaces::messenger::Messenger;
 use super::interfaces::packet_processor::PacketProcessor;
-use super::interfaces::patchwork::Operations;
+use super::interfaces::patchwork::{Operations, PatchworkState};
 use super::interfaces::player::{PlayerState, Position as PlayerPosition};
-use super::map::{Map, Peer, PeerConnection, Position};
+use super::map::{enable_peer_compression, Map, Peer, PeerConnection, Position};
 use super::packet;
 use super::packet::Packet;
 use super::packet_handlers::gameplay_router;
-use super::server;
+use super::protocol_version::ProtocolVersion;
+use super::server::Network;
 
 use std::collections::HashMap;
-use std::io;
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::Sender;
 
+use tokio::net::TcpStream;
+use tokio::task::AbortHandle;
 use uuid::Uuid;
 
+#[allow(clippy::too_many_arguments)]
 pub fn start<
     M: 'static + Messenger + Clone + Send,
     P: PlayerState + Clone,
     PP: 'static + PacketProcessor + Clone + Send,
     B: BlockState + Clone,
 >(
-    receiver: Receiver<Operations>,
+    receiver: &Inbox<Operations>,
     sender: Sender<Operations>,
     messenger: M,
     inbound_packet_processor: PP,
     player_state: P,
     block_state: B,
+    config: Config,
+    network: Network,
 ) {
     let mut patchwork = Patchwork::new();
 
     while let Ok(msg) = receiver.recv() {
         match msg {
+            Operations::Stop(_) => break,
             Operations::New(msg) => {
                 trace!("Adding Peer Map for peer {:?}", msg.peer);
                 patchwork.add_peer_map(
+                    network.clone(),
                     msg.peer,
                     messenger.clone(),
                     inbound_packet_processor.clone(),
                     sender.clone(),
+                    config.compression_threshold,
                 )
             }
             Operations::ConnectMap(msg) => {
                 patchwork.connect_map(msg.map_index, msg.peer_connection, messenger.clone());
             }
-            Operations::RoutePlayerPacket(msg) => {
-                let patchwork_clone = patchwork.clone();
-                let anchor = patchwork
-                    .player_anchors
-                    .entry(msg.conn_id)
-                    .or_insert(Anchor {
-                        map_index: 0,
-                        conn_id: None,
-                    });
-                if let Some(position) = extract_target_position((&msg.packet).clone()) {
-                    let target_map_index = patchwork_clone.clone().position_map_index(position);
-                    if target_map_index != anchor.map_index {
-                        messenger.send_packet(
-                            msg.conn_id,
-                            Packet::ClientboundChatMessage(packet::ClientboundChatMessage {
-                                message: String::from(
-                                    "{\"text\":\"You must walk onto that map first before interacting with it\",\"bold\": \"true\"}",
-                                ),
-                                position: 1
-                            }),
-                        );
-                        patchwork.clone().report(messenger.clone());
-                        continue;
-                    }
-                }
-                match &patchwork.maps[anchor.map_index].peer_connection {
-                    Some(_) => match msg.packet {
-                        Packet::Unknown => {}
-                        _ => {
-                            trace!(
-                                "Routing packet from conn_id {:?} through anchor",
-                                msg.conn_id
-                            );
-                            player_state.anchored_move_and_look(
-                                msg.conn_id,
-                                extract_player_position((&msg.packet).clone()),
-                                None,
-                            );
-                            messenger.send_packet(anchor.conn_id.unwrap(), msg.packet.clone());
-                        }
-                    },
-                    None => {
-                        trace!("Routing packet from conn_id {:?} locally", msg.conn_id);
-                        gameplay_router::route_packet(
-                            msg.packet.clone(),
-                            msg.conn_id,
-                            player_state.clone(),
-                            sender.clone(),
-                            block_state.clone(),
-                        );
-                    }
-                }
-                if let Some(position) = extract_map_position((&msg.packet).clone()) {
-                    let new_map_index = patchwork_clone.position_map_index(position);
-                    if new_map_index != anchor.map_index {
-                        anchor.disconnect(messenger.clone());
-                        *anchor = match &patchwork.maps[new_map_index].peer_connection {
-                            Some(peer_connection) => Anchor::connect(
-                                peer_connection.peer.clone(),
-                                msg.conn_id,
-                                new_map_index,
-                                patchwork.maps[new_map_index].position.x,
-                                messenger.clone(),
-                                player_state.clone(),
-                            )
-                            .unwrap(),
-                            None => {
-                                gameplay_router::route_packet(
-                                    msg.packet.clone(),
-                                    msg.conn_id,
-                                    player_state.clone(),
-                                    sender.clone(),
-                                    block_state.clone(),
-                                );
-                                if patchwork.maps[anchor.map_index].peer_connection.is_some() {
-                                    player_state.reintroduce(msg.conn_id);
-                                }
-                                Anchor {
-                                    conn_id: None,
-                                    map_index: new_map_index,
-                                }
-                            }
-                        }
-                    }
-                }
+            Operations::RoutePlayerPacket(msg) => patchwork.route_player_packet(
+                msg.packet,
+                msg.conn_id,
+                &network,
+                messenger.clone(),
+                player_state.clone(),
+                block_state.clone(),
+                sender.clone(),
+            ),
+            Operations::DropMap(msg) => {
+                patchwork.drop_map(msg.map_index, messenger.clone(), player_state.clone());
+            }
+            Operations::AnchorConnected(msg) => patchwork.open_anchor(
+                msg.conn_id,
+                msg.anchor_conn_id,
+                msg.stream,
+                &network,
+                messenger.clone(),
+                player_state.clone(),
+                config.compression_threshold,
+            ),
+            Operations::DropPlayer(msg) => patchwork.drop_player(msg.conn_id, messenger.clone()),
+            Operations::GetMaps(msg) => {
+                msg.reply.send(patchwork.maps.clone());
             }
             Operations::Report(_) => {
                 trace!("Reporting patchwork state");
@@ -186,38 +135,45 @@ fn extract_player_position(packet: Packet) -> Option<PlayerPosition> {
 struct Anchor {
     map_index: usize,
     conn_id: Option<Uuid>,
+    connecting: Option<AbortHandle>,
 }
 
 impl Anchor {
-    pub fn connect<M: Messenger, P: PlayerState>(
+    // Players on our own maps don't need a connection to anywhere else
+    pub fn local(map_index: usize) -> Anchor {
+        Anchor {
+            map_index,
+            conn_id: None,
+            connecting: None,
+        }
+    }
+
+    // Peers can be slow to answer, so the anchor connects without holding up the patchwork, which
+    // is told once it has. Until then anything routed through the anchor is dropped
+    pub fn connect<PA: 'static + PatchworkState + Send>(
+        network: &Network,
         peer: Peer,
         local_conn_id: Uuid,
         map_index: usize,
-        x_origin: i32,
-        messenger: M,
-        player_state: P,
-    ) -> Result<Anchor, io::Error> {
+        patchwork_state: PA,
+    ) -> Anchor {
         let conn_id = Uuid::new_v4();
-        let stream = server::new_connection(peer.address.clone(), peer.port)?;
-        messenger.new_connection(conn_id, stream.try_clone().unwrap());
-        messenger.update_translation(conn_id, Map::new(Position { x: x_origin, z: 0 }, 0));
-        messenger.send_packet(
-            conn_id,
-            Packet::Handshake(packet::Handshake {
-                protocol_version: 404,
-                server_address: String::from(""), //Neither of these fields are actually used
-                server_port: 0,
-                next_state: 4,
-            }),
-        );
-        player_state.cross_border(local_conn_id, conn_id);
-        Ok(Anchor {
+        let connecting = network.connect_with_backoff(peer.address, peer.port, move |stream| {
+            patchwork_state.anchor_connected(local_conn_id, conn_id, stream)
+        });
+        Anchor {
             map_index,
             conn_id: Some(conn_id),
-        })
+            connecting: Some(connecting),
+        }
     }
 
+    // An anchor that is still connecting is given up on, so that the player isn't crossed over to
+    // a map they've since left
     pub fn disconnect<M: Messenger>(&self, messenger: M) {
+        if let Some(connecting) = &self.connecting {
+            connecting.abort();
+        }
         if let Some(conn_id) = self.conn_id {
             messenger.close(conn_id);
         }
@@ -250,11 +206,10 @@ impl Patchwork {
             .push(Map::new(next_position, self.next_entity_id_block()));
     }
 
-    pub fn position_map_index(self, position: Position) -> usize {
+    pub fn position_map_index(&self, position: Position) -> Option<usize> {
         self.maps
-            .into_iter()
-            .position(|map| map.position == position)
-            .expect("Could not find map for position")
+            .iter()
+            .position(|map| map.position == position && !map.dropped)
     }
 
     pub fn connect_map<M: Messenger + Clone>(
@@ -267,25 +222,197 @@ impl Patchwork {
         self.maps[map_index].report(messenger);
     }
 
+    // Called when the connection to a peer's map closes, usually because the peer shut down. The
+    // map's area is no longer covered, and anyone anchored to it is brought back to the local map
+    pub fn drop_map<M: Messenger + Clone, P: PlayerState>(
+        &mut self,
+        map_index: usize,
+        messenger: M,
+        player_state: P,
+    ) {
+        let map = &mut self.maps[map_index];
+        info!("Dropping map at {:?}", map.position);
+        map.dropped = true;
+        if let Some(peer_connection) = map.peer_connection.take() {
+            messenger.close(peer_connection.conn_id);
+        }
+        for (conn_id, anchor) in self.player_anchors.iter_mut() {
+            if anchor.map_index == map_index {
+                anchor.disconnect(messenger.clone());
+                *anchor = Anchor::local(0);
+                player_state.reintroduce(*conn_id);
+            }
+        }
+    }
+
+    // Called when a player's connection closes, taking their anchor with them
+    pub fn drop_player<M: Messenger>(&mut self, conn_id: Uuid, messenger: M) {
+        if let Some(anchor) = self.player_anchors.remove(&conn_id) {
+            anchor.disconnect(messenger);
+        }
+    }
+
+    // Packets from players on a peer's map are forwarded through their anchor, the rest are handled
+    // here. Walking onto another map moves the anchor along with them
+    #[allow(clippy::too_many_arguments)]
+    pub fn route_player_packet<
+        M: 'static + Messenger + Clone + Send,
+        P: PlayerState + Clone,
+        B: BlockState + Clone,
+        PA: 'static + PatchworkState + Clone + Send,
+    >(
+        &mut self,
+        packet: Packet,
+        conn_id: Uuid,
+        network: &Network,
+        messenger: M,
+        player_state: P,
+        block_state: B,
+        patchwork_state: PA,
+    ) {
+        let patchwork_clone = self.clone();
+        let anchor = self
+            .player_anchors
+            .entry(conn_id)
+            .or_insert_with(|| Anchor::local(0));
+        if let Some(position) = extract_target_position(packet.clone()) {
+            let target_map_index = patchwork_clone.position_map_index(position);
+            if target_map_index != Some(anchor.map_index) {
+                messenger.send_packet(
+                    conn_id,
+                    Packet::ClientboundChatMessage(packet::ClientboundChatMessage {
+                        message: String::from(
+                            "{\"text\":\"You must walk onto that map first before interacting with it\",\"bold\": \"true\"}",
+                        ),
+                        position: 1
+                    }),
+                );
+                self.clone().report(messenger.clone());
+                return;
+            }
+        }
+        match &self.maps[anchor.map_index].peer_connection {
+            Some(_) => match packet {
+                Packet::Unknown => {}
+                _ => {
+                    trace!("Routing packet from conn_id {:?} through anchor", conn_id);
+                    player_state.anchored_move_and_look(
+                        conn_id,
+                        extract_player_position(packet.clone()),
+                        None,
+                    );
+                    messenger.send_packet(anchor.conn_id.unwrap(), packet.clone());
+                }
+            },
+            None => {
+                trace!("Routing packet from conn_id {:?} locally", conn_id);
+                gameplay_router::route_packet(
+                    packet.clone(),
+                    conn_id,
+                    player_state.clone(),
+                    patchwork_state.clone(),
+                    block_state.clone(),
+                );
+            }
+        }
+        // Positions that aren't covered by any map (e.g. past the edge of the patchwork) keep
+        // the player anchored to whichever map they were last on
+        if let Some(new_map_index) = extract_map_position(packet.clone())
+            .and_then(|position| patchwork_clone.position_map_index(position))
+        {
+            if new_map_index != anchor.map_index {
+                anchor.disconnect(messenger.clone());
+                *anchor = match &self.maps[new_map_index].peer_connection {
+                    Some(peer_connection) => Anchor::connect(
+                        network,
+                        peer_connection.peer.clone(),
+                        conn_id,
+                        new_map_index,
+                        patchwork_state.clone(),
+                    ),
+                    None => {
+                        gameplay_router::route_packet(
+                            packet.clone(),
+                            conn_id,
+                            player_state.clone(),
+                            patchwork_state.clone(),
+                            block_state.clone(),
+                        );
+                        if self.maps[anchor.map_index].peer_connection.is_some() {
+                            player_state.reintroduce(conn_id);
+                        }
+                        Anchor::local(new_map_index)
+                    }
+                }
+            }
+        }
+    }
+
+    // The player may have moved on while their anchor was connecting, in which case the connection
+    // is no longer wanted and is closed again by dropping it
+    #[allow(clippy::too_many_arguments)]
+    pub fn open_anchor<M: Messenger + Clone, P: PlayerState>(
+        &self,
+        conn_id: Uuid,
+        anchor_conn_id: Uuid,
+        stream: TcpStream,
+        network: &Network,
+        messenger: M,
+        player_state: P,
+        compression_threshold: Option<i32>,
+    ) {
+        let map_index = match self.player_anchors.get(&conn_id) {
+            Some(anchor) if anchor.conn_id == Some(anchor_conn_id) => anchor.map_index,
+            _ => {
+                trace!(
+                    "Dropping anchor {:?} that conn_id {:?} no longer needs",
+                    anchor_conn_id,
+                    conn_id
+                );
+                return;
+            }
+        };
+        network.open_outbound(stream, anchor_conn_id, &messenger);
+        let x_origin = self.maps[map_index].position.x;
+        messenger.update_translation(anchor_conn_id, Map::new(Position { x: x_origin, z: 0 }, 0));
+        messenger.send_packet(
+            anchor_conn_id,
+            Packet::Handshake(packet::Handshake {
+                protocol_version: ProtocolVersion::NATIVE.number(),
+                server_address: String::from(""), //Neither of these fields are actually used
+                server_port: 0,
+                next_state: 4,
+            }),
+        );
+        if let Some(threshold) = compression_threshold {
+            enable_peer_compression(anchor_conn_id, messenger.clone(), threshold);
+        }
+        player_state.cross_border(conn_id, anchor_conn_id);
+    }
+
     pub fn add_peer_map<
         M: 'static + Messenger + Send + Clone,
         PP: 'static + PacketProcessor + Send + Clone,
     >(
         &mut self,
+        network: Network,
         peer: Peer,
         messenger: M,
         inbound_packet_processor: PP,
         patchwork_state: Sender<Operations>,
+        compression_threshold: Option<i32>,
     ) {
         let next_position = self.next_position();
         let map = Map::new(next_position, self.next_entity_id_block());
         self.maps.push(map.clone());
         map.connect(
+            network,
             messenger,
             inbound_packet_processor,
             peer,
             patchwork_state,
             self.maps.len() - 1,
+            compression_threshold,
         );
     }
 
@@ -355,3 +482,287 @@ impl Direction {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::interfaces::{block, messenger, patchwork, player};
+    use super::super::minecraft_types::BlockPosition;
+    use super::packet::{PlayerDigging, PlayerPosition};
+    use super::*;
+
+    struct Services {
+        network: Network,
+        messenger: messenger::Recorder,
+        player_state: player::Recorder,
+        block_state: block::Recorder,
+        patchwork_state: patchwork::Recorder,
+    }
+
+    impl Services {
+        fn new(runtime: &tokio::runtime::Runtime) -> Services {
+            Services {
+                network: Network::new(runtime.handle().clone()),
+                messenger: messenger::Recorder::new(),
+                player_state: player::Recorder::new(),
+                block_state: block::Recorder::new(),
+                patchwork_state: patchwork::Recorder::new(),
+            }
+        }
+
+        fn route(&self, patchwork: &mut Patchwork, packet: Packet, conn_id: Uuid) {
+            patchwork.route_player_packet(
+                packet,
+                conn_id,
+                &self.network,
+                self.messenger.clone(),
+                self.player_state.clone(),
+                self.block_state.clone(),
+                self.patchwork_state.clone(),
+            );
+        }
+    }
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+    }
+
+    // A patchwork of our own map and a peer's map next to it, which the connection with the given
+    // id subscribes us to
+    fn patchwork_with_peer(peer_conn_id: Uuid) -> Patchwork {
+        let mut patchwork = Patchwork::new();
+        let position = patchwork.next_position();
+        let mut map = Map::new(position, patchwork.next_entity_id_block());
+        map.peer_connection = Some(PeerConnection {
+            peer: Peer {
+                address: String::from("10.0.0.2"),
+                port: 25566,
+            },
+            conn_id: peer_conn_id,
+        });
+        patchwork.maps.push(map);
+        patchwork
+    }
+
+    fn position(x: f64, z: f64) -> Packet {
+        Packet::PlayerPosition(PlayerPosition {
+            x,
+            feet_y: 80.0,
+            z,
+            on_ground: true,
+        })
+    }
+
+    #[test]
+    fn packets_on_the_local_map_are_handled_here() {
+        let runtime = runtime();
+        let services = Services::new(&runtime);
+        let mut patchwork = patchwork_with_peer(Uuid::new_v4());
+        let conn_id = Uuid::new_v4();
+        services.route(&mut patchwork, position(8.0, 8.0), conn_id);
+        assert!(matches!(
+            services.player_state.take().as_slice(),
+            [player::Operations::MoveAndLook(_)]
+        ));
+        assert!(services.messenger.take().is_empty());
+        assert_eq!(0, patchwork.player_anchors[&conn_id].map_index);
+    }
+
+    #[test]
+    fn blocks_on_other_maps_cannot_be_touched() {
+        let runtime = runtime();
+        let services = Services::new(&runtime);
+        let peer_conn_id = Uuid::new_v4();
+        let mut patchwork = patchwork_with_peer(peer_conn_id);
+        let conn_id = Uuid::new_v4();
+        let peer_position = patchwork.maps[1].position;
+        services.route(
+            &mut patchwork,
+            Packet::PlayerDigging(PlayerDigging {
+                status: 2,
+                location: BlockPosition {
+                    x: peer_position.x as u32 * 16 + 1,
+                    y: 80,
+                    z: peer_position.z as u32 * 16 + 1,
+                },
+                face: 1,
+            }),
+            conn_id,
+        );
+        assert!(services.block_state.take().is_empty());
+        let sent: Vec<(Uuid, Packet)> = services
+            .messenger
+            .take()
+            .into_iter()
+            .filter_map(|operation| match operation {
+                messenger::Operations::Send(msg) => Some((msg.conn_id, msg.packet)),
+                _ => None,
+            })
+            .collect();
+        assert!(matches!(
+            sent.as_slice(),
+            [
+                (warned, Packet::ClientboundChatMessage(_)),
+                (reported, Packet::Handshake(_))
+            ] if *warned == conn_id && *reported == peer_conn_id
+        ));
+    }
+
+    #[test]
+    fn anchored_players_are_routed_through_their_anchor() {
+        let runtime = runtime();
+        let services = Services::new(&runtime);
+        let mut patchwork = patchwork_with_peer(Uuid::new_v4());
+        let conn_id = Uuid::new_v4();
+        let anchor_conn_id = Uuid::new_v4();
+        patchwork.player_anchors.insert(
+            conn_id,
+            Anchor {
+                map_index: 1,
+                conn_id: Some(anchor_conn_id),
+                connecting: None,
+            },
+        );
+        let peer_position = patchwork.maps[1].position;
+        let on_peer_map = position(
+            f64::from(peer_position.x) * 16.0 + 8.0,
+            f64::from(peer_position.z) * 16.0 + 8.0,
+        );
+        services.route(&mut patchwork, on_peer_map, conn_id);
+        assert!(matches!(
+            services.player_state.take().as_slice(),
+            [player::Operations::AnchoredMoveAndLook(_)]
+        ));
+        assert!(matches!(
+            services.messenger.take().as_slice(),
+            [messenger::Operations::Send(msg)] if msg.conn_id == anchor_conn_id
+        ));
+    }
+
+    #[test]
+    fn anchors_cross_the_player_over_once_connected() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        let services = Services::new(&runtime);
+        let mut patchwork = patchwork_with_peer(Uuid::new_v4());
+        let conn_id = Uuid::new_v4();
+        let peer_position = patchwork.maps[1].position;
+        let on_peer_map = position(
+            f64::from(peer_position.x) * 16.0 + 8.0,
+            f64::from(peer_position.z) * 16.0 + 8.0,
+        );
+
+        // Nothing waits on the peer, which this runtime never gets around to connecting to
+        services.route(&mut patchwork, on_peer_map, conn_id);
+        assert!(services.messenger.take().is_empty());
+        assert!(!services
+            .player_state
+            .take()
+            .iter()
+            .any(|operation| matches!(operation, player::Operations::CrossBorder(_))));
+        let anchor_conn_id = patchwork.player_anchors[&conn_id].conn_id.unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let connect = || runtime.block_on(TcpStream::connect(address)).unwrap();
+        let open = |anchor_conn_id| {
+            patchwork.open_anchor(
+                conn_id,
+                anchor_conn_id,
+                connect(),
+                &services.network,
+                services.messenger.clone(),
+                services.player_state.clone(),
+                None,
+            )
+        };
+        open(Uuid::new_v4());
+        assert!(services.messenger.take().is_empty());
+        assert!(services.player_state.take().is_empty());
+
+        open(anchor_conn_id);
+        assert!(matches!(
+            services.messenger.take().first(),
+            Some(messenger::Operations::New(msg)) if msg.conn_id == anchor_conn_id
+        ));
+        assert!(matches!(
+            services.player_state.take().as_slice(),
+            [player::Operations::CrossBorder(msg)] if msg.remote_conn_id == anchor_conn_id
+        ));
+    }
+
+    #[test]
+    fn leaving_drops_the_anchor_and_stops_it_connecting() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let services = Services::new(&runtime);
+        let mut patchwork = patchwork_with_peer(Uuid::new_v4());
+        let conn_id = Uuid::new_v4();
+        let peer_position = patchwork.maps[1].position;
+        let on_peer_map = position(
+            f64::from(peer_position.x) * 16.0 + 8.0,
+            f64::from(peer_position.z) * 16.0 + 8.0,
+        );
+        services.route(&mut patchwork, on_peer_map, conn_id);
+        let anchor = patchwork.player_anchors[&conn_id].clone();
+        services.messenger.take();
+
+        patchwork.drop_player(conn_id, services.messenger.clone());
+        assert!(!patchwork.player_anchors.contains_key(&conn_id));
+        assert!(matches!(
+            services.messenger.take().as_slice(),
+            [messenger::Operations::Close(msg)] if Some(msg.conn_id) == anchor.conn_id
+        ));
+        runtime.block_on(async { tokio::task::yield_now().await });
+        assert!(anchor.connecting.unwrap().is_finished());
+    }
+
+    #[test]
+    fn walking_back_onto_the_local_map_drops_the_anchor() {
+        let runtime = runtime();
+        let services = Services::new(&runtime);
+        let mut patchwork = patchwork_with_peer(Uuid::new_v4());
+        let conn_id = Uuid::new_v4();
+        let anchor_conn_id = Uuid::new_v4();
+        patchwork.player_anchors.insert(
+            conn_id,
+            Anchor {
+                map_index: 1,
+                conn_id: Some(anchor_conn_id),
+                connecting: None,
+            },
+        );
+        services.route(&mut patchwork, position(8.0, 8.0), conn_id);
+        assert!(services.messenger.take().iter().any(|operation| matches!(
+            operation,
+            messenger::Operations::Close(msg) if msg.conn_id == anchor_conn_id
+        )));
+        assert!(services
+            .player_state
+            .take()
+            .iter()
+            .any(|operation| matches!(operation, player::Operations::Reintroduce(_))));
+        let anchor = &patchwork.player_anchors[&conn_id];
+        assert_eq!((0, None), (anchor.map_index, anchor.conn_id));
+    }
+
+    #[test]
+    fn maps_for_many_peers_get_distinct_positions() {
+        let mut patchwork = Patchwork::new();
+        for _ in 0..8 {
+            patchwork.create_local_map();
+        }
+        for (index, map) in patchwork.maps.iter().enumerate() {
+            assert_eq!(Some(index), patchwork.position_map_index(map.position));
+        }
+        assert_eq!(
+            None,
+            patchwork.position_map_index(Position { x: 50, z: 50 })
+        );
+    }
+}
@@ -1,79 +1,121 @@
-use super::constants::SERVER_MAX_CAPACITY;
+use super::config::Config;
+use super::instance::Inbox;
 use super::interfaces::block::BlockState;
+use super::interfaces::login::LoginService;
 use super::interfaces::messenger::{Messenger, SubscriberType};
 use super::interfaces::patchwork::PatchworkState;
 use super::interfaces::player::{Angle, Operations, Player, Position};
-use super::minecraft_types;
 use super::minecraft_types::float_to_angle;
+use super::minecraft_types::ChatComponent;
 use super::packet::{
     BorderCrossLogin, ClientboundPlayerPositionAndLook, DestroyEntities, EntityHeadLook,
-    EntityLookAndMove, EntityTeleport, JoinGame, Packet, PlayerInfo, SpawnPlayer, StatusResponse,
+    EntityLookAndMove, EntityTeleport, JoinGame, Packet, PlayerInfo, PlayerInfoUpdateLatency,
+    SpawnPlayer,
 };
-use super::packet_handlers::initiation_protocols::login;
+use super::packet_handlers::initiation_protocols::{client_ping, login};
 use std::collections::HashMap;
 
-use std::sync::mpsc::{Receiver, Sender};
+use std::ops::ControlFlow;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
-pub fn start<M: Messenger + Clone, B: BlockState + Clone, PA: PatchworkState + Clone>(
-    receiver: Receiver<Operations>,
+// Admissions come from the login service and deletions from the connection service, so a player
+// whose connection closes mid-login can be deleted before they're admitted. Connections are
+// remembered for long enough after closing that a late admission is still turned away
+const DEPARTED_MEMORY: Duration = Duration::from_secs(60);
+
+pub fn start<
+    M: Messenger + Clone,
+    B: BlockState + Clone,
+    PA: PatchworkState + Clone,
+    L: LoginService,
+>(
+    receiver: &Inbox<Operations>,
     sender: Sender<Operations>,
     messenger: M,
     block_state: B,
     patchwork_state: PA,
+    login_service: L,
+    config: Config,
 ) {
     let mut players = HashMap::<Uuid, Player>::new();
     let mut entity_conn_ids = HashMap::<i32, Uuid>::new();
     let mut entity_id = 0;
+    let mut departed = HashMap::<Uuid, Instant>::new();
 
     while let Ok(msg) = receiver.recv() {
-        handle_message(
+        let handled = handle_message(
             msg,
             &mut players,
             &mut entity_conn_ids,
             &mut entity_id,
+            &mut departed,
             messenger.clone(),
             sender.clone(),
             block_state.clone(),
             patchwork_state.clone(),
-        )
+            &login_service,
+            &config,
+        );
+        if handled.is_break() {
+            break;
+        }
     }
 }
 
-fn handle_message<M: Messenger + Clone, B: BlockState + Clone, PA: PatchworkState + Clone>(
+#[allow(clippy::too_many_arguments)]
+fn handle_message<
+    M: Messenger + Clone,
+    B: BlockState + Clone,
+    PA: PatchworkState + Clone,
+    L: LoginService,
+>(
     msg: Operations,
     players: &mut HashMap<Uuid, Player>,
     entity_conn_ids: &mut HashMap<i32, Uuid>,
     entity_id: &mut i32,
+    departed: &mut HashMap<Uuid, Instant>,
     messenger: M,
     sender: Sender<Operations>,
     block_state: B,
     patchwork_state: PA,
-) {
+    login_service: &L,
+    config: &Config,
+) -> ControlFlow<()> {
     match msg {
+        Operations::Stop(_) => return ControlFlow::Break(()),
         Operations::New(msg) => {
-            let mut player = msg.player;
-            if player.entity_id == 0 {
-                player.entity_id = *entity_id;
-                *entity_id += 1;
-            }
-            trace!(
-                "Creating new player {:?} for conn_id {:?}",
-                player,
-                msg.conn_id
-            );
-            messenger.broadcast(
-                Packet::PlayerInfo(player.player_info_packet()),
-                Some(msg.conn_id),
-                SubscriberType::All,
+            add_player(
+                msg.conn_id,
+                msg.player,
+                players,
+                entity_conn_ids,
+                entity_id,
+                &messenger,
             );
-            messenger.broadcast(
-                Packet::SpawnPlayer(player.spawn_player_packet()),
-                Some(msg.conn_id),
-                SubscriberType::All,
+        }
+        // Logins are gated on the same count the status ping reports, so a client that saw a free
+        // slot in the server list is only refused if someone else took it in the meantime
+        Operations::Admit(msg) => {
+            if departed.contains_key(&msg.conn_id) {
+                trace!("Not admitting conn_id {:?}, it has closed", msg.conn_id);
+                return ControlFlow::Continue(());
+            }
+            if players.len() >= usize::from(config.max_players) {
+                trace!("Server is full, refusing conn_id {:?}", msg.conn_id);
+                messenger.disconnect(msg.conn_id, ChatComponent::text("The server is full!"));
+                return ControlFlow::Continue(());
+            }
+            let player = add_player(
+                msg.conn_id,
+                msg.player,
+                players,
+                entity_conn_ids,
+                entity_id,
+                &messenger,
             );
-            entity_conn_ids.insert(player.entity_id, msg.conn_id);
-            players.insert(msg.conn_id, player);
+            login_service.admitted(msg.conn_id, player);
         }
         Operations::Login(msg) => {
             if let Some(player) = players.get(&msg.conn_id) {
@@ -87,6 +129,9 @@ fn handle_message<M: Messenger + Clone, B: BlockState + Clone, PA: PatchworkStat
             }
         }
         Operations::Delete(msg) => {
+            let now = Instant::now();
+            departed.retain(|_, departed_at| now.duration_since(*departed_at) < DEPARTED_MEMORY);
+            departed.insert(msg.conn_id, now);
             if let Some(player) = players.remove(&msg.conn_id) {
                 messenger.broadcast(
                     Packet::DestroyEntities(DestroyEntities {
@@ -97,6 +142,17 @@ fn handle_message<M: Messenger + Clone, B: BlockState + Clone, PA: PatchworkStat
                 );
             }
         }
+        // Peers keep their own latencies for players anchored to them, so this stays local
+        Operations::UpdateLatency(msg) => {
+            if let Some(player) = players.get_mut(&msg.conn_id) {
+                player.ping = msg.latency;
+                messenger.broadcast(
+                    Packet::PlayerInfoUpdateLatency(player.player_info_update_latency_packet()),
+                    None,
+                    SubscriberType::Local,
+                );
+            }
+        }
         Operations::MoveAndLook(msg) => {
             trace!(
                 "Player Move/Look new_position: {:?} new_angle: {:?} for conn_id {:?}",
@@ -152,9 +208,17 @@ fn handle_message<M: Messenger + Clone, B: BlockState + Clone, PA: PatchworkStat
         }
         Operations::CrossBorder(msg) => {
             trace!("Crossing Border for conn_id {:?}", msg.local_conn_id);
-            let player = players
-                .get(&msg.local_conn_id)
-                .expect("Could not cross border: player not found");
+            // The player may have disconnected while the peer was accepting them
+            let player = match players.get(&msg.local_conn_id) {
+                Some(player) => player,
+                None => {
+                    warn!(
+                        "Could not cross border: player {:?} not found",
+                        msg.local_conn_id
+                    );
+                    return ControlFlow::Continue(());
+                }
+            };
             messenger.broadcast(
                 Packet::DestroyEntities(DestroyEntities {
                     entity_ids: vec![player.entity_id],
@@ -169,41 +233,63 @@ fn handle_message<M: Messenger + Clone, B: BlockState + Clone, PA: PatchworkStat
         }
         Operations::Reintroduce(msg) => {
             trace!("Reintroducing player for conn_id {:?}", msg.conn_id);
-            let player = players
-                .get(&msg.conn_id)
-                .expect("Could not reintroduce: player not found");
+            let player = match players.get(&msg.conn_id) {
+                Some(player) => player,
+                None => {
+                    warn!("Could not reintroduce: player {:?} not found", msg.conn_id);
+                    return ControlFlow::Continue(());
+                }
+            };
             messenger.broadcast(
                 Packet::SpawnPlayer(player.spawn_player_packet()),
                 None,
                 SubscriberType::Remote,
             );
         }
-        Operations::StatusResponse(msg) => {
-            trace!(
-                "Building and sending status ping response for conn_id {:?}",
-                msg.conn_id
+        Operations::GetPlayer(msg) => {
+            msg.reply.send(players.get(&msg.conn_id).cloned());
+        }
+        Operations::ReportStatus(msg) => {
+            trace!("Sending status to conn_id {:?}", msg.conn_id);
+            messenger.send_packet(
+                msg.conn_id,
+                client_ping::status_response(
+                    players.values().collect(),
+                    msg.protocol_version,
+                    config,
+                ),
             );
-            let status_response_object = minecraft_types::StatusResponse {
-                version: msg.version,
-                players: minecraft_types::PingPlayersInfo {
-                    max: SERVER_MAX_CAPACITY,
-                    online: players.len() as u16,
-                    sample: players
-                        .iter()
-                        .map(|(id, player)| minecraft_types::PingSamplePlayer {
-                            name: player.name.clone(),
-                            id: id.to_string(),
-                        })
-                        .collect(),
-                },
-                description: msg.description,
-            };
-            let status_response = StatusResponse {
-                json_response: serde_json::to_string(&status_response_object).unwrap(),
-            };
-            messenger.send_packet(msg.conn_id, Packet::StatusResponse(status_response));
         }
     }
+    ControlFlow::Continue(())
+}
+
+fn add_player<M: Messenger>(
+    conn_id: Uuid,
+    mut player: Player,
+    players: &mut HashMap<Uuid, Player>,
+    entity_conn_ids: &mut HashMap<i32, Uuid>,
+    entity_id: &mut i32,
+    messenger: &M,
+) -> Player {
+    if player.entity_id == 0 {
+        player.entity_id = *entity_id;
+        *entity_id += 1;
+    }
+    trace!("Creating new player {:?} for conn_id {:?}", player, conn_id);
+    messenger.broadcast(
+        Packet::PlayerInfo(player.player_info_packet()),
+        Some(conn_id),
+        SubscriberType::All,
+    );
+    messenger.broadcast(
+        Packet::SpawnPlayer(player.spawn_player_packet()),
+        Some(conn_id),
+        SubscriberType::All,
+    );
+    entity_conn_ids.insert(player.entity_id, conn_id);
+    players.insert(conn_id, player.clone());
+    player
 }
 
 impl Player {
@@ -217,6 +303,8 @@ impl Player {
             on_ground: false,
             username: self.name.clone(),
             entity_id: self.entity_id,
+            uuid: self.uuid.as_u128(),
+            properties: self.properties.clone(),
         }
     }
 
@@ -299,13 +387,22 @@ impl Player {
             number_of_players: 1, //send each player in an individual packet for now
             uuid: self.uuid.as_u128(),
             name: self.name.clone(),
-            number_of_properties: 0,
+            properties: self.properties.clone(),
             gamemode: 1,
-            ping: 100,
+            ping: self.ping,
             has_display_name: false,
         }
     }
 
+    fn player_info_update_latency_packet(&self) -> PlayerInfoUpdateLatency {
+        PlayerInfoUpdateLatency {
+            action: 2,
+            number_of_players: 1,
+            uuid: self.uuid.as_u128(),
+            ping: self.ping,
+        }
+    }
+
     fn spawn_player_packet(&self) -> SpawnPlayer {
         SpawnPlayer {
             entity_id: self.entity_id,
@@ -349,3 +446,88 @@ fn position_distance(old_position: Position, new_position: Option<Position>) ->
         None => 0.0,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::interfaces::{block, login, messenger, patchwork, player};
+    use super::super::test_support;
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    struct Service {
+        players: HashMap<Uuid, Player>,
+        entity_conn_ids: HashMap<i32, Uuid>,
+        entity_id: i32,
+        departed: HashMap<Uuid, Instant>,
+        login_service: login::Recorder,
+        config: Config,
+    }
+
+    impl Service {
+        fn new() -> Service {
+            Service {
+                players: HashMap::new(),
+                entity_conn_ids: HashMap::new(),
+                entity_id: 0,
+                departed: HashMap::new(),
+                login_service: login::Recorder::new(),
+                config: test_support::config(),
+            }
+        }
+
+        fn handle(&mut self, msg: Operations) {
+            let _ = handle_message(
+                msg,
+                &mut self.players,
+                &mut self.entity_conn_ids,
+                &mut self.entity_id,
+                &mut self.departed,
+                messenger::Recorder::new(),
+                channel().0,
+                block::Recorder::new(),
+                patchwork::Recorder::new(),
+                &self.login_service,
+                &self.config,
+            );
+        }
+    }
+
+    fn admit(conn_id: Uuid) -> Operations {
+        Operations::Admit(player::Admit {
+            conn_id,
+            player: test_support::player("Alex"),
+        })
+    }
+
+    fn delete(conn_id: Uuid) -> Operations {
+        Operations::Delete(player::Delete { conn_id })
+    }
+
+    #[test]
+    fn players_are_admitted() {
+        let mut service = Service::new();
+        let conn_id = Uuid::new_v4();
+        service.handle(admit(conn_id));
+        assert!(service.players.contains_key(&conn_id));
+        assert_eq!(1, service.login_service.take().len());
+    }
+
+    #[test]
+    fn players_who_left_before_being_admitted_are_not_admitted() {
+        let mut service = Service::new();
+        let conn_id = Uuid::new_v4();
+        service.handle(delete(conn_id));
+        service.handle(admit(conn_id));
+        assert!(service.players.is_empty());
+        assert!(service.login_service.take().is_empty());
+    }
+
+    #[test]
+    fn players_who_left_after_being_admitted_are_deleted() {
+        let mut service = Service::new();
+        let conn_id = Uuid::new_v4();
+        service.handle(admit(conn_id));
+        service.handle(delete(conn_id));
+        assert!(service.players.is_empty());
+    }
+}
@@ -0,0 +1,13 @@
+// A node is stopped with SIGINT or SIGTERM, by typing `stop` into its console, or when a service it
+// can't do without panics
+
+use std::sync::mpsc::Sender;
+
+// Signals can only be handled once per process, so this is up to whoever runs the node
+pub fn on_signal(shutdown: Sender<()>) {
+    if let Err(e) = ctrlc::set_handler(move || {
+        let _ = shutdown.send(());
+    }) {
+        warn!("Could not listen for shutdown signals: {}", e);
+    }
+}
@@ -0,0 +1,31 @@
+// Helpers shared by the unit tests of packet handlers and services. Services themselves are stood
+// in for by the Recorder that define_interface! generates alongside every interface
+
+use super::config::{self, Config};
+use super::interfaces::player::{Angle, Player, Position};
+use uuid::Uuid;
+
+// A node configured with nothing but the defaults
+pub fn config() -> Config {
+    config::load(Vec::new(), |_: &str| None).unwrap()
+}
+
+pub fn player(name: &str) -> Player {
+    Player {
+        conn_id: Uuid::new_v4(),
+        uuid: Uuid::new_v4(),
+        name: String::from(name),
+        properties: Vec::new(),
+        position: Position {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        },
+        angle: Angle {
+            pitch: 0.0,
+            yaw: 0.0,
+        },
+        entity_id: 0,
+        ping: 0,
+    }
+}
@@ -0,0 +1,116 @@
+// Runs two nodes side by side in this process, with the second one peered to the first, and walks
+// bots across the border between them
+
+use patchwork::bot::Bot;
+use patchwork::config;
+use patchwork::interfaces::patchwork::PatchworkState;
+use patchwork::models::minecraft_types::BlockPosition;
+use patchwork::models::packet::Packet;
+use patchwork::node::Node;
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn node(peer_port: Option<u16>) -> Node {
+    let mut args = vec![String::from("--port"), String::from("0")];
+    if let Some(peer_port) = peer_port {
+        args.push(String::from("--peer"));
+        args.push(format!("127.0.0.1:{}", peer_port));
+    }
+    Node::start(config::load(args, |_| None).unwrap()).unwrap()
+}
+
+// Peers are connected in the background, so walking over before then would go nowhere
+fn wait_for_peer(node: &Node) {
+    let deadline = Instant::now() + TIMEOUT;
+    while Instant::now() < deadline {
+        let maps = node.patchwork_state().maps().wait().unwrap();
+        if maps.iter().any(|map| map.peer_connection.is_some()) {
+            return;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("peer never connected");
+}
+
+#[test]
+fn players_crossing_the_border_show_up_on_the_other_node() {
+    let a = node(None);
+    let b = node(Some(a.port()));
+    wait_for_peer(&b);
+
+    let watcher = Bot::connect("127.0.0.1", a.port(), "watcher").unwrap();
+    let mut walker = Bot::connect("127.0.0.1", b.port(), "walker").unwrap();
+    walker.walk_to(24.0, 8.0);
+    watcher
+        .expect(TIMEOUT, |p| matches!(p, Packet::SpawnPlayer(_)))
+        .unwrap();
+
+    walker.walk_to(8.0, 8.0);
+    watcher
+        .expect(TIMEOUT, |p| matches!(p, Packet::DestroyEntities(_)))
+        .unwrap();
+
+    b.stop();
+    a.stop();
+}
+
+#[test]
+fn blocks_placed_across_the_border_land_on_the_other_node() {
+    let a = node(None);
+    let b = node(Some(a.port()));
+    wait_for_peer(&b);
+
+    let watcher = Bot::connect("127.0.0.1", a.port(), "watcher").unwrap();
+    let mut walker = Bot::connect("127.0.0.1", b.port(), "walker").unwrap();
+    walker.walk_to(24.0, 8.0);
+    watcher
+        .expect(TIMEOUT, |p| matches!(p, Packet::SpawnPlayer(_)))
+        .unwrap();
+
+    // The node across the border sees the block in its own coordinates
+    walker.place_block(BlockPosition { x: 20, y: 15, z: 4 });
+    let placed = watcher
+        .expect(TIMEOUT, |p| matches!(p, Packet::BlockChange(_)))
+        .unwrap();
+    match placed {
+        Packet::BlockChange(change) => {
+            assert_eq!(BlockPosition { x: 4, y: 16, z: 4 }, change.location)
+        }
+        _ => unreachable!(),
+    }
+
+    b.stop();
+    a.stop();
+}
+
+#[test]
+fn blocks_broken_across_the_border_are_broken_on_the_other_node() {
+    let a = node(None);
+    let b = node(Some(a.port()));
+    wait_for_peer(&b);
+
+    let watcher = Bot::connect("127.0.0.1", a.port(), "watcher").unwrap();
+    let mut walker = Bot::connect("127.0.0.1", b.port(), "walker").unwrap();
+    walker.walk_to(24.0, 8.0);
+    watcher
+        .expect(TIMEOUT, |p| matches!(p, Packet::SpawnPlayer(_)))
+        .unwrap();
+
+    walker.break_block(BlockPosition { x: 20, y: 15, z: 4 });
+    let broken = watcher
+        .expect(TIMEOUT, |p| matches!(p, Packet::BlockChange(_)))
+        .unwrap();
+    match broken {
+        Packet::BlockChange(change) => {
+            assert_eq!(BlockPosition { x: 4, y: 15, z: 4 }, change.location);
+            assert_eq!(0, change.block_id);
+        }
+        _ => unreachable!(),
+    }
+
+    b.stop();
+    a.stop();
+}
@@ -0,0 +1,104 @@
+// Exercises peer links under the conditions the cluster simulator can put them in
+
+use patchwork::bot::Bot;
+use patchwork::cluster::Cluster;
+use patchwork::config;
+use patchwork::config::Config;
+use patchwork::interfaces::patchwork::PatchworkState;
+use patchwork::models::packet::Packet;
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn config() -> Config {
+    config::load(Vec::new(), |_| None).unwrap()
+}
+
+fn eventually<F: Fn() -> bool>(condition: F) {
+    let deadline = Instant::now() + TIMEOUT;
+    while !condition() {
+        assert!(Instant::now() < deadline, "gave up waiting");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn nodes_are_linked_in_the_requested_topology() {
+    let cluster = Cluster::start(&config(), 3, &[(1, 0), (1, 2), (2, 0)]).unwrap();
+    cluster.wait_for_links(TIMEOUT).unwrap();
+
+    let map_counts: Vec<usize> = (0..3)
+        .map(|index| {
+            cluster
+                .node(index)
+                .patchwork_state()
+                .maps()
+                .wait()
+                .unwrap()
+                .len()
+        })
+        .collect();
+    assert_eq!(vec![1, 3, 2], map_counts);
+
+    cluster.stop();
+}
+
+#[test]
+fn killed_links_drop_the_map() {
+    let cluster = Cluster::start(&config(), 2, &[(1, 0)]).unwrap();
+    cluster.wait_for_links(TIMEOUT).unwrap();
+
+    cluster.link_between(1, 0).unwrap().kill();
+    eventually(|| {
+        let maps = cluster.node(1).patchwork_state().maps().wait().unwrap();
+        maps.iter().any(|map| map.dropped)
+    });
+
+    cluster.stop();
+}
+
+#[test]
+fn partitioned_links_hold_players_back_until_healed() {
+    let cluster = Cluster::start(&config(), 2, &[(1, 0)]).unwrap();
+    cluster.wait_for_links(TIMEOUT).unwrap();
+    let link = cluster.link_between(1, 0).unwrap();
+
+    let watcher = Bot::connect("127.0.0.1", cluster.port(0), "watcher").unwrap();
+    let mut walker = Bot::connect("127.0.0.1", cluster.port(1), "walker").unwrap();
+    link.partition();
+    walker.walk_to(24.0, 8.0);
+    assert!(watcher
+        .expect(Duration::from_millis(500), |p| matches!(
+            p,
+            Packet::SpawnPlayer(_)
+        ))
+        .is_err());
+
+    link.heal();
+    watcher
+        .expect(TIMEOUT, |p| matches!(p, Packet::SpawnPlayer(_)))
+        .unwrap();
+
+    cluster.stop();
+}
+
+#[test]
+fn delayed_links_slow_down_border_crossings() {
+    let delay = Duration::from_millis(500);
+    let cluster = Cluster::start(&config(), 2, &[(1, 0)]).unwrap();
+    cluster.wait_for_links(TIMEOUT).unwrap();
+    cluster.link_between(1, 0).unwrap().delay(delay);
+
+    let watcher = Bot::connect("127.0.0.1", cluster.port(0), "watcher").unwrap();
+    let mut walker = Bot::connect("127.0.0.1", cluster.port(1), "walker").unwrap();
+    let crossed_at = Instant::now();
+    walker.walk_to(24.0, 8.0);
+    watcher
+        .expect(TIMEOUT, |p| matches!(p, Packet::SpawnPlayer(_)))
+        .unwrap();
+    assert!(crossed_at.elapsed() >= delay);
+
+    cluster.stop();
+}
@@ -0,0 +1,79 @@
+// Starts nodes with the peers given on the command line and checks each gets a map and a
+// connection of its own
+
+use patchwork::config;
+use patchwork::interfaces::patchwork::PatchworkState;
+use patchwork::node::Node;
+
+use std::io::ErrorKind;
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn node(peers: &[TcpListener]) -> Node {
+    let mut args = vec![String::from("--port"), String::from("0")];
+    for peer in peers {
+        args.push(String::from("--peer"));
+        args.push(peer.local_addr().unwrap().to_string());
+    }
+    Node::start(config::load(args, |_| None).unwrap()).unwrap()
+}
+
+fn peer() -> TcpListener {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    listener
+}
+
+// Every connection the peer gets within the given time
+fn connections(peer: &TcpListener, within: Duration) -> Vec<TcpStream> {
+    let deadline = Instant::now() + within;
+    let mut connections = Vec::new();
+    while Instant::now() < deadline {
+        match peer.accept() {
+            Ok((stream, _)) => connections.push(stream),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(20)),
+            Err(e) => panic!("could not accept: {}", e),
+        }
+    }
+    connections
+}
+
+// Peers are connected in the background, so the first connection can take a moment
+fn first_connection(peer: &TcpListener) -> TcpStream {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        match peer.accept() {
+            Ok((stream, _)) => return stream,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                assert!(
+                    Instant::now() < deadline,
+                    "gave up waiting for a connection"
+                );
+                thread::sleep(Duration::from_millis(20));
+            }
+            Err(e) => panic!("could not accept: {}", e),
+        }
+    }
+}
+
+#[test]
+fn nodes_without_peers_only_have_their_own_map() {
+    let node = node(&[]);
+    assert_eq!(1, node.patchwork_state().maps().wait().unwrap().len());
+    node.stop();
+}
+
+#[test]
+fn every_peer_gets_a_map_and_a_connection() {
+    let peers = vec![peer(), peer(), peer()];
+    let node = node(&peers);
+    assert_eq!(4, node.patchwork_state().maps().wait().unwrap().len());
+    let _connections: Vec<TcpStream> = peers.iter().map(first_connection).collect();
+    for peer in peers.iter() {
+        assert!(connections(peer, Duration::from_millis(500)).is_empty());
+    }
+    node.stop();
+}
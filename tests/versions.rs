@@ -0,0 +1,78 @@
+// Logs bots in on every version we speak, and has them play together on one node
+
+use patchwork::bot::Bot;
+use patchwork::config;
+use patchwork::models::minecraft_types::BlockPosition;
+use patchwork::models::packet::Packet;
+use patchwork::models::protocol_version::ProtocolVersion;
+use patchwork::node::Node;
+
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn node() -> Node {
+    let args = vec![String::from("--port"), String::from("0")];
+    Node::start(config::load(args, |_| None).unwrap()).unwrap()
+}
+
+#[test]
+fn players_on_different_versions_see_each_other_build() {
+    let node = node();
+
+    let watcher = Bot::connect_as(
+        "127.0.0.1",
+        node.port(),
+        "watcher",
+        ProtocolVersion::V1_12_2,
+    )
+    .unwrap();
+    let mut builder = Bot::connect_as(
+        "127.0.0.1",
+        node.port(),
+        "builder",
+        ProtocolVersion::V1_14_4,
+    )
+    .unwrap();
+    let spawned = watcher
+        .expect(TIMEOUT, |p| matches!(p, Packet::SpawnPlayer(_)))
+        .unwrap();
+    match spawned {
+        Packet::SpawnPlayer(spawn) => assert_eq!(builder.entity_id, spawn.entity_id),
+        other => panic!("expected SpawnPlayer, got {:?}", other),
+    }
+
+    builder.walk_to(6.0, 6.0);
+    watcher
+        .expect(TIMEOUT, |p| {
+            matches!(p, Packet::EntityLookAndMove(_) | Packet::EntityTeleport(_))
+        })
+        .unwrap();
+
+    // Stone, which 1.12.2 knows by a block id of its own
+    builder.place_block(BlockPosition { x: 4, y: 15, z: 4 });
+    let placed = watcher
+        .expect(TIMEOUT, |p| matches!(p, Packet::BlockChange(_)))
+        .unwrap();
+    match placed {
+        Packet::BlockChange(change) => {
+            assert_eq!(BlockPosition { x: 4, y: 16, z: 4 }, change.location);
+            assert_eq!(1, change.block_id);
+        }
+        other => panic!("expected BlockChange, got {:?}", other),
+    }
+
+    node.stop();
+}
+
+#[test]
+fn every_supported_version_can_log_in_and_build() {
+    let node = node();
+    for version in ProtocolVersion::SUPPORTED.iter().copied() {
+        let bot = Bot::connect_as("127.0.0.1", node.port(), version.name(), version).unwrap();
+        bot.place_block(BlockPosition { x: 2, y: 15, z: 2 });
+        bot.expect(TIMEOUT, |p| matches!(p, Packet::BlockChange(_)))
+            .unwrap();
+    }
+    node.stop();
+}